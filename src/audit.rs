@@ -0,0 +1,386 @@
+//! Audit log of authentication events.
+//!
+//! `Realm` and `UserApi` report every challenge issued, push notification
+//! sent, session status result, `verify_login` result, and
+//! `check_valid_login` result to an `AuditSink`, if one is configured with
+//! `with_audit`.  `OfflineVerifier` also reports decisions made from its
+//! cache.
+//!
+//! Events identify users, sessions and realms, but never include realm
+//! secrets, presence values, challenges or signatures.
+//!
+//! Two sinks are provided: `JsonLinesSink` appends one JSON object per line to
+//! a file, and `SyslogSink` sends messages to a syslog daemon.  Sinks must
+//! not block authentication, so failures to write an event are ignored.
+
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{fmt, io};
+
+use chrono::Utc;
+use protocol::{DeviceId, KeyId, SessionId, Timestamp, UserId};
+
+/// Kinds of events that are audited.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum AuditEventKind {
+    LoginChallenge,
+    Push,
+    SessionStatus,
+    VerifyLogin,
+    CheckValidLogin,
+    OfflineCheck,
+    RevokeDevice,
+}
+
+impl AuditEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AuditEventKind::LoginChallenge  => "login_challenge",
+            AuditEventKind::Push            => "push",
+            AuditEventKind::SessionStatus   => "session_status",
+            AuditEventKind::VerifyLogin     => "verify_login",
+            AuditEventKind::CheckValidLogin => "check_valid_login",
+            AuditEventKind::OfflineCheck    => "offline_check",
+            AuditEventKind::RevokeDevice    => "revoke_device",
+        }
+    }
+}
+
+/// Result of an audited operation.  `Pending` is used for sessions that the
+/// user has not confirmed yet; `Denied` for logins that were checked
+/// successfully but are not valid.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum AuditOutcome {
+    Success,
+    Pending,
+    Denied,
+    Failure,
+}
+
+impl AuditOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Pending => "pending",
+            AuditOutcome::Denied  => "denied",
+            AuditOutcome::Failure => "failure",
+        }
+    }
+}
+
+/// A structured audit record.
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    pub kind:         AuditEventKind,
+    pub outcome:      AuditOutcome,
+    pub realm_key_id: KeyId,
+    pub user_id:      Option<UserId>,
+    pub session_id:   Option<SessionId>,
+    pub device_id:    Option<DeviceId>,
+    pub expires_at:   Option<Timestamp>,
+    pub reason:       Option<String>,
+    pub timestamp:    Timestamp,
+}
+
+impl AuditEvent {
+    /// Creates an event with the current time.
+    pub fn new(kind: AuditEventKind, outcome: AuditOutcome, realm_key_id: &KeyId) -> AuditEvent {
+        AuditEvent {
+            kind:         kind,
+            outcome:      outcome,
+            realm_key_id: realm_key_id.clone(),
+            user_id:      None,
+            session_id:   None,
+            device_id:    None,
+            expires_at:   None,
+            reason:       None,
+            timestamp:    Timestamp::new(Utc::now()),
+        }
+    }
+
+    pub fn user_id(mut self, user_id: &UserId) -> AuditEvent {
+        self.user_id = Some(user_id.clone());
+        self
+    }
+
+    pub fn session_id(mut self, session_id: &SessionId) -> AuditEvent {
+        self.session_id = Some(session_id.clone());
+        self
+    }
+
+    pub fn device_id(mut self, device_id: &DeviceId) -> AuditEvent {
+        self.device_id = Some(device_id.clone());
+        self
+    }
+
+    pub fn expires_at(mut self, expires_at: &Timestamp) -> AuditEvent {
+        self.expires_at = Some(expires_at.clone());
+        self
+    }
+
+    /// Explains a failure or a denial.  Reasons are fixed categories, such as
+    /// those from `trace::classify_error`, rather than error messages: the
+    /// `Display` text of an error can include the request URL, and with it
+    /// presence values and session ids.
+    pub fn reason(mut self, reason: &'static str) -> AuditEvent {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    /// Produces an object with `event`, `outcome`, `realm_key_id` and
+    /// `timestamp` fields, and any of `user_id`, `session_id`, `device_id`,
+    /// `expires_at` and `reason` that are set.  Times are in seconds since
+    /// January 1, 1970.
+    pub fn to_json(&self) -> Value {
+        let mut obj = Map::new();
+        obj.insert("event".to_string(), Value::from(self.kind.as_str()));
+        obj.insert("outcome".to_string(), Value::from(self.outcome.as_str()));
+        obj.insert("realm_key_id".to_string(), Value::from(&self.realm_key_id));
+        obj.insert("timestamp".to_string(), Value::from(&self.timestamp));
+        if let Some(ref uid) = self.user_id {
+            obj.insert("user_id".to_string(), Value::from(uid));
+        }
+        if let Some(ref sid) = self.session_id {
+            obj.insert("session_id".to_string(), Value::from(sid));
+        }
+        if let Some(ref did) = self.device_id {
+            obj.insert("device_id".to_string(), Value::from(did));
+        }
+        if let Some(ref t) = self.expires_at {
+            obj.insert("expires_at".to_string(), Value::from(t));
+        }
+        if let Some(ref reason) = self.reason {
+            obj.insert("reason".to_string(), Value::from(reason.as_str()));
+        }
+        Value::Object(obj)
+    }
+}
+
+/// Destination for audit events.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: &AuditEvent);
+}
+
+/// Optional, shared reference to an `AuditSink`.  This is the form in which
+/// `Realm` and `UserApi` hold their sink.  All `Auditor` values compare
+/// equal, so that holding one does not affect equality of the holder.
+#[derive(Clone, Default)]
+pub struct Auditor {
+    sink: Option<Arc<dyn AuditSink>>,
+}
+
+impl Auditor {
+    /// An auditor that discards events.
+    pub fn none() -> Auditor {
+        Auditor { sink: None }
+    }
+
+    pub fn new(sink: Arc<dyn AuditSink>) -> Auditor {
+        Auditor { sink: Some(sink) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        if let Some(ref sink) = self.sink {
+            sink.record(&event);
+        }
+    }
+}
+
+impl PartialEq for Auditor {
+    fn eq(&self, _: &Auditor) -> bool {
+        true
+    }
+}
+
+impl Eq for Auditor {}
+
+impl fmt::Debug for Auditor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(if self.is_enabled() { "Auditor(enabled)" } else { "Auditor(none)" })
+    }
+}
+
+/// Appends each event as a line of JSON to a file.
+pub struct JsonLinesSink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesSink {
+    /// Opens a file for appending, creating it if necessary.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<JsonLinesSink> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(JsonLinesSink { file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn record(&self, event: &AuditEvent) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = writeln!(file, "{}", event.to_json());
+        }
+    }
+}
+
+/// Sends each event to a syslog daemon over UDP, in the format of RFC 3164,
+/// with facility `authpriv`.  Successful events are logged with severity
+/// `info`, others with severity `notice`.  The message is the same JSON
+/// object that `JsonLinesSink` writes.
+pub struct SyslogSink {
+    socket: UdpSocket,
+    tag:    String,
+}
+
+const FACILITY_AUTHPRIV: u8 = 10;
+const SEVERITY_NOTICE:   u8 = 5;
+const SEVERITY_INFO:     u8 = 6;
+
+impl SyslogSink {
+    /// Connects to the syslog daemon at `addr`, for example
+    /// `"127.0.0.1:514"`.  Messages are tagged with `tag`, typically the name
+    /// of the program.
+    pub fn connect<A: ToSocketAddrs>(addr: A, tag: &str) -> io::Result<SyslogSink> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        Ok(SyslogSink { socket: socket, tag: tag.to_string() })
+    }
+
+    fn format(&self, event: &AuditEvent) -> String {
+        let severity = match event.outcome {
+            AuditOutcome::Success | AuditOutcome::Pending => SEVERITY_INFO,
+            AuditOutcome::Denied  | AuditOutcome::Failure => SEVERITY_NOTICE,
+        };
+        let priority = FACILITY_AUTHPRIV * 8 + severity;
+        format!("<{}>{}: {}", priority, self.tag, event.to_json())
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn record(&self, event: &AuditEvent) {
+        let _ = self.socket.send(self.format(event).as_bytes());
+    }
+}
+
+/// Keeps events in memory.  Useful in tests.
+#[derive(Default)]
+pub struct MemoryAuditSink {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+impl MemoryAuditSink {
+    pub fn new() -> MemoryAuditSink {
+        MemoryAuditSink { events: Mutex::new(Vec::new()) }
+    }
+
+    /// Returns the events recorded so far.
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl AuditSink for MemoryAuditSink {
+    fn record(&self, event: &AuditEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use serde_json::{Value};
+    use std::io::Read;
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::{env, fs};
+    use url::Url;
+
+    use super::*;
+    use protocol::{KeyId, Newtype, Presence, SessionId, UserId};
+    use testing::FakeTozny;
+    use user::UserApi;
+
+    fn event() -> AuditEvent {
+        AuditEvent::new(AuditEventKind::VerifyLogin, AuditOutcome::Success,
+                        &KeyId::from_slice("sid_realm"))
+            .user_id(&UserId::from_slice("sid_alice"))
+            .session_id(&SessionId::from_slice("sess_1"))
+    }
+
+    #[test]
+    fn it_encodes_only_the_fields_that_are_set() {
+        let js = event().to_json();
+        assert_eq!(js.get("event"), Some(&Value::from("verify_login")));
+        assert_eq!(js.get("user_id"), Some(&Value::from("sid_alice")));
+        assert!(js.get("reason").is_none());
+        assert!(js.get("expires_at").is_none());
+        assert!(js.get("device_id").is_none());
+    }
+
+    #[test]
+    fn json_lines_sink_appends_events() {
+        let mut path = env::temp_dir();
+        path.push("tozny-audit-test.jsonl");
+        let _ = fs::remove_file(&path);
+        {
+            let sink = JsonLinesSink::open(&path).unwrap();
+            sink.record(&event());
+            sink.record(&event());
+        }
+        let mut contents = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let js: Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(js.get("session_id"), Some(&Value::from("sess_1")));
+        assert_eq!(js.get("outcome"), Some(&Value::from("success")));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn syslog_sink_sends_datagrams() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink = SyslogSink::connect(server.local_addr().unwrap(), "tozny-test").unwrap();
+        sink.record(&event());
+        let mut buf = [0; 1024];
+        let (len, _) = server.recv_from(&mut buf).unwrap();
+        let msg = String::from_utf8(buf[..len].to_vec()).unwrap();
+        assert!(msg.starts_with("<86>tozny-test: {"));
+        assert!(msg.contains("\"outcome\":\"success\""));
+    }
+
+    #[test]
+    fn realm_records_verify_login_results_without_secrets() {
+        let tozny = FakeTozny::new();
+        let sink = Arc::new(MemoryAuditSink::new());
+        let realm = tozny.realm().with_audit(sink.clone());
+        assert!(realm.verify_login("e30", "not a signature").is_err());
+        let events = sink.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AuditEventKind::VerifyLogin);
+        assert_eq!(events[0].outcome, AuditOutcome::Failure);
+        let line = events[0].to_json().to_string();
+        assert!(!line.contains(tozny.secret().as_slice()));
+        assert!(!line.contains("not a signature"));
+    }
+
+    #[test]
+    fn failed_pushes_are_recorded_without_the_presence_value() {
+        let sink = Arc::new(MemoryAuditSink::new());
+        let api = UserApi::new(KeyId::from_slice("sid_realm"),
+                               Url::parse("http://127.0.0.1:1/index.php").unwrap())
+            .with_audit(sink.clone());
+        assert!(api.push(&SessionId::from_slice("sess_1"),
+                         &Presence::from_slice("presence_value")).is_err());
+        let events = sink.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].reason, Some("http".to_string()));
+        assert!(!events[0].to_json().to_string().contains("presence_value"));
+    }
+}
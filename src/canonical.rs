@@ -0,0 +1,134 @@
+//! The canonical JSON encoding that signed `Question` payloads use.
+//!
+//! A signature covers bytes, not values, so the bytes that are signed must
+//! not depend on the formatting choices of a particular JSON encoder.  The
+//! canonical form of a value is:
+//!
+//! - no whitespace between tokens;
+//! - object keys sorted by their UTF-8 bytes (equivalently, by code point),
+//!   each appearing once;
+//! - numbers written as integers in decimal, with a leading `-` for negative
+//!   values and no leading zeros, `+`, fraction or exponent.  Numbers that are
+//!   not integers have no canonical form, since implementations disagree on
+//!   how to print them;
+//! - strings escaped as `\"`, `\\`, `\b`, `\f`, `\n`, `\r`, `\t`, or
+//!   `\u00XX` with lowercase hex digits for the remaining characters below
+//!   U+0020.  All other characters, including `/` and non-ASCII characters,
+//!   are written as UTF-8 without escaping.
+//!
+//! This matches the output of Python's `json.dumps(value, sort_keys=True,
+//! separators=(",", ":"), ensure_ascii=False)` for values without floats; the
+//! vectors in `testdata/canonical.json` were produced that way.
+//!
+//! Verifiers should not re-encode what they receive: the signature is checked
+//! over the exact bytes that were signed, which may come from a sender that
+//! does not produce canonical JSON.  See `question::verify`.
+
+use serde::ser::{Error};
+use serde_json;
+use serde_json::{Map, Value};
+
+/// Encodes a value in canonical form.  Fails if the value contains a number
+/// that is not an integer.
+pub fn encode(value: &Value) -> Result<String, serde_json::Error> {
+    let mut out = String::new();
+    write_value(&mut out, value)?;
+    Ok(out)
+}
+
+fn write_value(out: &mut String, value: &Value) -> Result<(), serde_json::Error> {
+    match *value {
+        Value::Null          => out.push_str("null"),
+        Value::Bool(true)    => out.push_str("true"),
+        Value::Bool(false)   => out.push_str("false"),
+        Value::Number(ref n) => {
+            if let Some(i) = n.as_i64() {
+                out.push_str(&i.to_string())
+            }
+            else if let Some(u) = n.as_u64() {
+                out.push_str(&u.to_string())
+            }
+            else {
+                return Err(serde_json::Error::custom(format!(
+                    "{} has no canonical encoding; use an integer or a string", n)))
+            }
+        },
+        Value::String(ref s) => write_string(out, s),
+        Value::Array(ref vs) => {
+            out.push('[');
+            for (i, v) in vs.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, v)?;
+            }
+            out.push(']');
+        },
+        Value::Object(ref obj) => write_object(out, obj)?,
+    }
+    Ok(())
+}
+
+fn write_object(out: &mut String, obj: &Map<String, Value>) -> Result<(), serde_json::Error> {
+    let mut entries: Vec<(&String, &Value)> = obj.iter().collect();
+    entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    out.push('{');
+    for (i, (k, v)) in entries.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_string(out, k);
+        out.push(':');
+        write_value(out, v)?;
+    }
+    out.push('}');
+    Ok(())
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c    => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use serde_json::{Value};
+
+    use super::*;
+
+    #[test]
+    fn it_matches_the_reference_vectors() {
+        let vectors: Value = serde_json::from_str(include_str!("../testdata/canonical.json")).unwrap();
+        for case in vectors["valid"].as_array().unwrap() {
+            let input: Value = serde_json::from_str(case["input"].as_str().unwrap()).unwrap();
+            assert_eq!(encode(&input).unwrap(), case["canonical"].as_str().unwrap(),
+                       "{}", case["description"]);
+        }
+        for case in vectors["invalid"].as_array().unwrap() {
+            let input: Value = serde_json::from_str(case["input"].as_str().unwrap()).unwrap();
+            assert!(encode(&input).is_err(), "{}", case["description"]);
+        }
+    }
+
+    #[test]
+    fn canonical_output_is_a_fixed_point() {
+        let js = "{ \"b\": [1, -2, {\"y\": null, \"x\": true}], \"a\": \"caf\\u00e9\\n\" }";
+        let once = encode(&serde_json::from_str(js).unwrap()).unwrap();
+        let twice = encode(&serde_json::from_str(&once).unwrap()).unwrap();
+        assert_eq!(once, twice);
+    }
+}
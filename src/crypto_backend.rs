@@ -0,0 +1,362 @@
+//! The cryptographic primitives that `question` and `jwt` build on:
+//! HMAC-SHA256, constant-time comparison, secure random bytes, and RSA and
+//! Ed25519 signatures.
+//!
+//! Implementations are selected by cargo feature:
+//!
+//! - `backend-rustcrypto` (the default) uses the RustCrypto `hmac`, `sha2`,
+//!   `subtle`, `rsa` and `ed25519-dalek` crates, and the operating system's
+//!   random number generator.
+//! - `backend-openssl` uses OpenSSL.
+//!
+//! `DefaultBackend` names the implementation used by the rest of the crate.
+//! If both features are enabled it is the OpenSSL backend.
+
+/// A provider of the primitives used to sign and verify messages.
+pub trait CryptoBackend {
+    /// Computes the HMAC-SHA256 of `message` under `key`.
+    fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32];
+
+    /// Compares two byte strings in time that depends only on their lengths.
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool;
+
+    /// Fills `bytes` from a cryptographically secure random number generator.
+    fn fill_random(bytes: &mut [u8]);
+
+    /// Verifies an RSASSA-PKCS1-v1_5 signature with SHA-256.  The public key
+    /// is given as its big-endian modulus and exponent.
+    fn verify_rsa_sha256(modulus: &[u8], exponent: &[u8], message: &[u8], signature: &[u8]
+                         ) -> bool;
+
+    /// Verifies an Ed25519 signature with a 32-byte public key.
+    fn verify_ed25519(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool;
+
+    /// Signs `message` with RSASSA-PKCS1-v1_5 and SHA-256.  The private key
+    /// is given as PKCS#8 DER.  Returns `None` if the key cannot be read.
+    fn sign_rsa_sha256(private_key: &[u8], message: &[u8]) -> Option<Vec<u8>>;
+
+    /// The big-endian modulus and exponent of an RSA private key given as
+    /// PKCS#8 DER.
+    fn rsa_public_key(private_key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Signs `message` with the Ed25519 key derived from a 32-byte seed.
+    fn sign_ed25519(seed: &[u8], message: &[u8]) -> Option<Vec<u8>>;
+
+    /// The public key of the Ed25519 key derived from a 32-byte seed.
+    fn ed25519_public_key(seed: &[u8]) -> Option<[u8; 32]>;
+}
+
+#[cfg(feature = "backend-openssl")]
+pub type DefaultBackend = OpensslBackend;
+
+#[cfg(all(feature = "backend-rustcrypto", not(feature = "backend-openssl")))]
+pub type DefaultBackend = RustCryptoBackend;
+
+#[cfg(not(any(feature = "backend-rustcrypto", feature = "backend-openssl")))]
+compile_error!("tozny_auth needs a crypto backend: enable `backend-rustcrypto` or `backend-openssl`");
+
+/// Backend built on the RustCrypto crates.
+#[cfg(feature = "backend-rustcrypto")]
+pub struct RustCryptoBackend;
+
+#[cfg(feature = "backend-rustcrypto")]
+impl CryptoBackend for RustCryptoBackend {
+    fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+        use hmac::{Hmac, Mac};
+        use sha2::{Sha256};
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(message);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        use subtle::{ConstantTimeEq};
+        a.ct_eq(b).into()
+    }
+
+    fn fill_random(bytes: &mut [u8]) {
+        use rand::rngs::{OsRng};
+        use rand::RngCore;
+        OsRng.fill_bytes(bytes)
+    }
+
+    fn verify_rsa_sha256(modulus: &[u8], exponent: &[u8], message: &[u8], signature: &[u8]
+                         ) -> bool {
+        use rsa::{BigUint, RsaPublicKey};
+        use rsa::pkcs1v15::{Signature, VerifyingKey};
+        use rsa::signature::{Verifier};
+        use sha2::{Sha256};
+        use std::convert::{TryFrom};
+        let key = match RsaPublicKey::new(BigUint::from_bytes_be(modulus),
+                                          BigUint::from_bytes_be(exponent)) {
+            Ok(key) => VerifyingKey::<Sha256>::new(key),
+            Err(_)  => return false,
+        };
+        Signature::try_from(signature)
+            .map(|sig| key.verify(message, &sig).is_ok())
+            .unwrap_or(false)
+    }
+
+    fn verify_ed25519(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+        use std::convert::{TryFrom};
+        let key = match <&[u8; 32]>::try_from(public_key).ok()
+            .and_then(|bytes| VerifyingKey::from_bytes(bytes).ok()) {
+            Some(key) => key,
+            None      => return false,
+        };
+        Signature::from_slice(signature)
+            .map(|sig| key.verify(message, &sig).is_ok())
+            .unwrap_or(false)
+    }
+
+    fn sign_rsa_sha256(private_key: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+        use rand::rngs::{OsRng};
+        use rsa::{RsaPrivateKey};
+        use rsa::pkcs1v15::{SigningKey};
+        use rsa::pkcs8::{DecodePrivateKey};
+        use rsa::signature::{RandomizedSigner, SignatureEncoding};
+        use sha2::{Sha256};
+        // The random number generator blinds the private key operation.
+        RsaPrivateKey::from_pkcs8_der(private_key).ok()
+            .map(|key| SigningKey::<Sha256>::new(key).sign_with_rng(&mut OsRng, message).to_vec())
+    }
+
+    fn rsa_public_key(private_key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        use rsa::{RsaPrivateKey};
+        use rsa::pkcs8::{DecodePrivateKey};
+        use rsa::traits::{PublicKeyParts};
+        RsaPrivateKey::from_pkcs8_der(private_key).ok()
+            .map(|key| (key.n().to_bytes_be(), key.e().to_bytes_be()))
+    }
+
+    fn sign_ed25519(seed: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+        use ed25519_dalek::{Signer, SigningKey};
+        use std::convert::{TryFrom};
+        <&[u8; 32]>::try_from(seed).ok()
+            .map(|seed| SigningKey::from_bytes(seed).sign(message).to_bytes().to_vec())
+    }
+
+    fn ed25519_public_key(seed: &[u8]) -> Option<[u8; 32]> {
+        use ed25519_dalek::{SigningKey};
+        use std::convert::{TryFrom};
+        <&[u8; 32]>::try_from(seed).ok()
+            .map(|seed| SigningKey::from_bytes(seed).verifying_key().to_bytes())
+    }
+}
+
+/// Backend built on OpenSSL.
+#[cfg(feature = "backend-openssl")]
+pub struct OpensslBackend;
+
+#[cfg(feature = "backend-openssl")]
+impl CryptoBackend for OpensslBackend {
+    fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+        use openssl::hash::{MessageDigest};
+        use openssl::pkey::{PKey};
+        use openssl::sign::{Signer};
+        let code = PKey::hmac(key)
+            .and_then(|pkey| {
+                let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+                signer.update(message)?;
+                signer.sign_to_vec()
+            })
+            .expect("OpenSSL failed to compute HMAC-SHA256");
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&code);
+        out
+    }
+
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        // `memcmp::eq` requires equal lengths.
+        a.len() == b.len() && ::openssl::memcmp::eq(a, b)
+    }
+
+    fn fill_random(bytes: &mut [u8]) {
+        ::openssl::rand::rand_bytes(bytes).expect("OpenSSL random number generator failed")
+    }
+
+    fn verify_rsa_sha256(modulus: &[u8], exponent: &[u8], message: &[u8], signature: &[u8]
+                         ) -> bool {
+        use openssl::bn::{BigNum};
+        use openssl::hash::{MessageDigest};
+        use openssl::pkey::{PKey};
+        use openssl::rsa::{Rsa};
+        use openssl::sign::{Verifier};
+        BigNum::from_slice(modulus)
+            .and_then(|n| BigNum::from_slice(exponent).and_then(|e| Rsa::from_public_components(n, e)))
+            .and_then(PKey::from_rsa)
+            .and_then(|pkey| {
+                let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey)?;
+                verifier.update(message)?;
+                verifier.verify(signature)
+            })
+            .unwrap_or(false)
+    }
+
+    fn verify_ed25519(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        use openssl::pkey::{Id, PKey};
+        use openssl::sign::{Verifier};
+        public_key.len() == 32 && signature.len() == 64 &&
+            PKey::public_key_from_raw_bytes(public_key, Id::ED25519)
+                .and_then(|pkey| {
+                    Verifier::new_without_digest(&pkey)?.verify_oneshot(signature, message)
+                })
+                .unwrap_or(false)
+    }
+
+    fn sign_rsa_sha256(private_key: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+        use openssl::hash::{MessageDigest};
+        use openssl::pkey::{PKey};
+        use openssl::sign::{Signer};
+        PKey::private_key_from_pkcs8(private_key).ok()
+            .filter(|pkey| pkey.rsa().is_ok())
+            .and_then(|pkey| {
+                Signer::new(MessageDigest::sha256(), &pkey)
+                    .and_then(|mut signer| {
+                        signer.update(message)?;
+                        signer.sign_to_vec()
+                    })
+                    .ok()
+            })
+    }
+
+    fn rsa_public_key(private_key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        use openssl::pkey::{PKey};
+        PKey::private_key_from_pkcs8(private_key)
+            .and_then(|pkey| pkey.rsa())
+            .map(|rsa| (rsa.n().to_vec(), rsa.e().to_vec()))
+            .ok()
+    }
+
+    fn sign_ed25519(seed: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+        use openssl::pkey::{Id, PKey};
+        use openssl::sign::{Signer};
+        if seed.len() != 32 {
+            return None
+        }
+        PKey::private_key_from_raw_bytes(seed, Id::ED25519)
+            .and_then(|pkey| Signer::new_without_digest(&pkey)?.sign_oneshot_to_vec(message))
+            .ok()
+    }
+
+    fn ed25519_public_key(seed: &[u8]) -> Option<[u8; 32]> {
+        use openssl::pkey::{Id, PKey};
+        if seed.len() != 32 {
+            return None
+        }
+        let public = PKey::private_key_from_raw_bytes(seed, Id::ED25519)
+            .and_then(|pkey| pkey.raw_public_key())
+            .ok()?;
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&public);
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use serde_json::{Value};
+
+    use super::*;
+    use question;
+
+    // The same vectors as in `question`, checked against every backend that
+    // is compiled in.
+    const SECRET: &'static str = "8f8c9b8df39f8c8be4a39378bece4ac01cba948f9b4ef7b90acad3f49d5358f2";
+    const ENCODED: &'static str = "eyJub25jZSI6IjZiNDllYWM1OGRkNWU4ZDlhYWI2YTVlYWI5MTlmYzQ3ODYzY2IyMzNjYzU2MGM5YjYwNzcyNjg1ZTMyMWZmNTAiLCAiZXhwaXJlc19hdCI6IjE0MTQ1NDE5NzIiLCAicmVhbG1fa2V5X2lkIjoic2lkX2Q5MTVlNzIyNjk0N2IiLCAidXNlcl9pZCI6InNpZF8xMjM0IiwgIm1ldGhvZCI6InJlYWxtLnVzZXJfZ2V0In0";
+    const SIGNATURE: &'static str = "HB8PQnwlqsB6JlU9NFoDAS_NwUzEtY7EYcgVyZfjsH4";
+
+    fn conformance<B: CryptoBackend>() {
+        let code = B::hmac_sha256(SECRET.as_bytes(), ENCODED.as_bytes());
+        assert_eq!(question::encode_base64(&code), SIGNATURE);
+
+        let expected = question::decode_base64(SIGNATURE).unwrap();
+        assert!(B::constant_time_eq(&code, &expected));
+        assert!(!B::constant_time_eq(&code, &expected[..31]));
+        let mut tampered = expected.clone();
+        tampered[0] ^= 1;
+        assert!(!B::constant_time_eq(&code, &tampered));
+
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        B::fill_random(&mut a);
+        B::fill_random(&mut b);
+        assert!(a != b);
+
+        let rsa = vector(include_str!("../testdata/login_rsa.json"));
+        let n = b64(&rsa["public_key"]["n"]);
+        let e = b64(&rsa["public_key"]["e"]);
+        let (message, mut sig) = signed(&rsa);
+        assert!(B::verify_rsa_sha256(&n, &e, &message, &sig));
+        sig[0] ^= 1;
+        assert!(!B::verify_rsa_sha256(&n, &e, &message, &sig));
+        assert!(!B::verify_rsa_sha256(&[], &e, &message, &sig));
+
+        let ed = vector(include_str!("../testdata/login_ed25519.json"));
+        let x = b64(&ed["public_key"]["x"]);
+        let (message, mut sig) = signed(&ed);
+        assert!(B::verify_ed25519(&x, &message, &sig));
+        assert!(!B::verify_ed25519(&x, &message[1..], &sig));
+        assert!(!B::verify_ed25519(&x[..31], &message, &sig));
+        sig.pop();
+        assert!(!B::verify_ed25519(&x, &message, &sig));
+
+        // PKCS#1 v1.5 and Ed25519 signatures are deterministic, so every
+        // backend must produce the same bytes.
+        let der = include_bytes!("../testdata/rsa_private_key.der");
+        let (n, e) = B::rsa_public_key(der).unwrap();
+        let sig = B::sign_rsa_sha256(der, b"message").unwrap();
+        assert!(B::verify_rsa_sha256(&n, &e, b"message", &sig));
+        assert_eq!(sig, Reference::sign_rsa_sha256(der, b"message").unwrap());
+        assert!(B::sign_rsa_sha256(&der[1..], b"message").is_none());
+
+        let seed = [7u8; 32];
+        let public = B::ed25519_public_key(&seed).unwrap();
+        let sig = B::sign_ed25519(&seed, b"message").unwrap();
+        assert!(B::verify_ed25519(&public, b"message", &sig));
+        assert_eq!(sig, Reference::sign_ed25519(&seed, b"message").unwrap());
+        assert!(B::ed25519_public_key(&seed[1..]).is_none());
+        assert!(B::sign_ed25519(&seed[1..], b"message").is_none());
+    }
+
+    /// The backend that others are compared with.
+    #[cfg(feature = "backend-rustcrypto")]
+    type Reference = RustCryptoBackend;
+
+    #[cfg(not(feature = "backend-rustcrypto"))]
+    type Reference = OpensslBackend;
+
+    fn vector(contents: &str) -> Value {
+        serde_json::from_str(contents).unwrap()
+    }
+
+    fn b64(v: &Value) -> Vec<u8> {
+        question::decode_base64(v.as_str().unwrap()).unwrap()
+    }
+
+    /// The signed bytes and the signature from a signed login vector.
+    fn signed(v: &Value) -> (Vec<u8>, Vec<u8>) {
+        let q = &v["question"];
+        (q["signed_data"].as_str().unwrap().as_bytes().to_vec(), b64(&q["signature"]))
+    }
+
+    #[cfg(feature = "backend-rustcrypto")]
+    #[test]
+    fn rustcrypto_backend_conforms() {
+        conformance::<RustCryptoBackend>();
+    }
+
+    #[cfg(feature = "backend-openssl")]
+    #[test]
+    fn openssl_backend_conforms() {
+        conformance::<OpensslBackend>();
+    }
+
+    #[test]
+    fn default_backend_conforms() {
+        conformance::<DefaultBackend>();
+    }
+}
@@ -0,0 +1,258 @@
+//! Abstraction over the API calls that make up an interactive login.
+//!
+//! Integrations that drive a login from start to finish (such as the `oidc`
+//! module) are written against the `LoginFlow` trait rather than directly
+//! against `UserApi` and `Realm`.  That makes it possible to run them against
+//! `testing::FakeTozny` instead of the real Tozny API.
+//!
+//! `wait_for_login` and `login_with_push` implement the common steps of a
+//! login on top of any `LoginFlow`.
+
+use std::thread;
+use std::time::Duration;
+
+use login::Login;
+use presence::{PresenceError, PresenceStore};
+use protocol::{Presence, SessionId};
+use question::{Question, QuestionError};
+use realm::Realm;
+use user::{LoginChallenge, UserApi};
+use verifier::LoginVerifier;
+
+/// The steps of a Tozny login: issue a challenge, optionally push it to
+/// a device, wait for the user to confirm, and verify the signed result.
+pub trait LoginFlow {
+    /// See `UserApi::login_challenge`.
+    fn login_challenge(&self) -> Result<LoginChallenge, QuestionError>;
+
+    /// See `UserApi::push`.
+    fn push(&self, session_id: &SessionId, presence: &Presence) -> Result<(), QuestionError>;
+
+    /// See `UserApi::check_session_status`.
+    fn check_session_status(&self, session_id: &SessionId
+                            ) -> Result<Option<Question>, QuestionError>;
+
+    /// See `Realm::verify_login`.
+    fn verify_login(&self, signed_data: &str, signature: &str) -> Result<Login, QuestionError>;
+}
+
+/// `LoginFlow` implementation that talks to the Tozny API.
+pub struct ToznyLoginFlow {
+    pub user_api: UserApi,
+    pub realm:    Realm,
+}
+
+impl ToznyLoginFlow {
+    pub fn new(user_api: UserApi, realm: Realm) -> ToznyLoginFlow {
+        ToznyLoginFlow {
+            user_api: user_api,
+            realm:    realm,
+        }
+    }
+}
+
+impl LoginFlow for ToznyLoginFlow {
+    fn login_challenge(&self) -> Result<LoginChallenge, QuestionError> {
+        self.user_api.login_challenge()
+    }
+
+    fn push(&self, session_id: &SessionId, presence: &Presence) -> Result<(), QuestionError> {
+        self.user_api.push(session_id, presence)
+    }
+
+    fn check_session_status(&self, session_id: &SessionId
+                            ) -> Result<Option<Question>, QuestionError> {
+        self.user_api.check_session_status(session_id)
+    }
+
+    fn verify_login(&self, signed_data: &str, signature: &str) -> Result<Login, QuestionError> {
+        self.realm.verify_login(signed_data, signature)
+    }
+}
+
+/// `LoginFlow` implementation that talks to the Tozny API, but checks the
+/// signed result with public keys only.  Unlike `ToznyLoginFlow` it does not
+/// need the realm secret, so it can run in a process that the user controls.
+pub struct VerifierLoginFlow {
+    pub user_api: UserApi,
+    pub verifier: LoginVerifier,
+}
+
+impl VerifierLoginFlow {
+    pub fn new(user_api: UserApi, verifier: LoginVerifier) -> VerifierLoginFlow {
+        VerifierLoginFlow {
+            user_api: user_api,
+            verifier: verifier,
+        }
+    }
+}
+
+impl LoginFlow for VerifierLoginFlow {
+    fn login_challenge(&self) -> Result<LoginChallenge, QuestionError> {
+        self.user_api.login_challenge()
+    }
+
+    fn push(&self, session_id: &SessionId, presence: &Presence) -> Result<(), QuestionError> {
+        self.user_api.push(session_id, presence)
+    }
+
+    fn check_session_status(&self, session_id: &SessionId
+                            ) -> Result<Option<Question>, QuestionError> {
+        self.user_api.check_session_status(session_id)
+    }
+
+    fn verify_login(&self, signed_data: &str, signature: &str) -> Result<Login, QuestionError> {
+        self.verifier.verify_login(signed_data, signature)
+    }
+}
+
+/// Polls `check_session_status` about once a second, for up to
+/// `timeout_secs` seconds, until the user confirms `challenge`.  The result is
+/// checked with `verify_login`, and must be a login for the challenge's
+/// session; a validly signed login for any other session is rejected with
+/// `QuestionError::SessionMismatch`.
+///
+/// Returns `Ok(None)` if the user does not confirm the login in time.
+pub fn wait_for_login<F: LoginFlow>(flow:         &F,
+                                    challenge:    &LoginChallenge,
+                                    timeout_secs: u32,
+                                    ) -> Result<Option<Login>, QuestionError> {
+    for attempt in 0..timeout_secs {
+        if attempt > 0 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        if let Some(question) = flow.check_session_status(&challenge.session_id)? {
+            let login = flow.verify_login(&question.signed_data, &question.signature)?;
+            if login.session_id != challenge.session_id {
+                return Err(QuestionError::SessionMismatch)
+            }
+            return Ok(Some(login))
+        }
+    }
+    Ok(None)
+}
+
+/// Logs a user in by sending a push notification to the device that they
+/// used last time, instead of displaying a QR code.
+///
+/// Looks up a `Presence` stored under `key`, and if one is found calls
+/// `login_challenge`, pushes the challenge to that device, and waits for the
+/// login with `wait_for_login`.  Once the login is verified the store is
+/// updated with the presence value from the challenge.
+///
+/// Returns `Ok(None)` if there is no stored presence for `key`, or if the
+/// user does not confirm the login in time.  In either case the caller
+/// should fall back to displaying a QR code.
+pub fn login_with_push<F, S>(flow:         &F,
+                             store:        &mut S,
+                             key:          &str,
+                             timeout_secs: u32,
+                             ) -> Result<Option<Login>, PresenceError>
+    where F: LoginFlow, S: PresenceStore {
+    let presence = match store.get(key)? {
+        Some(p) => p,
+        None    => return Ok(None),
+    };
+    let challenge = flow.login_challenge().map_err(PresenceError::QuestionError)?;
+    flow.push(&challenge.session_id, &presence).map_err(PresenceError::QuestionError)?;
+    match wait_for_login(flow, &challenge, timeout_secs).map_err(PresenceError::QuestionError)? {
+        Some(login) => {
+            store.put(key, challenge.presence)?;
+            Ok(Some(login))
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use presence::MemoryPresenceStore;
+    use protocol::UserId;
+    use testing::FakeTozny;
+
+    /// Confirms the first login that `tozny` pushes, as the given user.
+    fn confirm_push(tozny: Arc<FakeTozny>, user_id: &'static str) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            loop {
+                if let Some((session_id, _)) = tozny.pushes().into_iter().next() {
+                    tozny.confirm(&session_id, &UserId::from_slice(user_id), "Alice");
+                    return
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        })
+    }
+
+    #[test]
+    fn it_logs_in_with_a_push_to_the_stored_device() {
+        let tozny = Arc::new(FakeTozny::new());
+        let mut store = MemoryPresenceStore::new();
+        assert!(login_with_push(&*tozny, &mut store, "alice", 5).unwrap().is_none());
+        assert!(tozny.pushes().is_empty());
+
+        store.put("alice", Presence::from_slice("pres_old")).unwrap();
+        let confirmer = confirm_push(tozny.clone(), "sid_alice");
+        let login = login_with_push(&*tozny, &mut store, "alice", 5).unwrap().unwrap();
+        confirmer.join().unwrap();
+        assert_eq!(login.user_id, UserId::from_slice("sid_alice"));
+
+        let (session_id, pushed) = tozny.pushes().remove(0);
+        assert_eq!(login.session_id, session_id);
+        assert_eq!(pushed, Presence::from_slice("pres_old"));
+        assert_eq!(store.get("alice").unwrap(), tozny.presence_for(&session_id));
+    }
+
+    #[test]
+    fn it_gives_up_after_the_timeout() {
+        let tozny = FakeTozny::new();
+        let mut store = MemoryPresenceStore::new();
+        store.put("alice", Presence::from_slice("pres_old")).unwrap();
+        assert!(login_with_push(&tozny, &mut store, "alice", 1).unwrap().is_none());
+        assert_eq!(tozny.pushes().len(), 1);
+        assert_eq!(store.get("alice").unwrap(), Some(Presence::from_slice("pres_old")));
+    }
+
+    /// Answers every status check with the login for one other session.
+    struct OtherSession {
+        tozny:   FakeTozny,
+        session: SessionId,
+    }
+
+    impl LoginFlow for OtherSession {
+        fn login_challenge(&self) -> Result<LoginChallenge, QuestionError> {
+            self.tozny.login_challenge()
+        }
+
+        fn push(&self, session_id: &SessionId, presence: &Presence) -> Result<(), QuestionError> {
+            self.tozny.push(session_id, presence)
+        }
+
+        fn check_session_status(&self, _: &SessionId) -> Result<Option<Question>, QuestionError> {
+            self.tozny.check_session_status(&self.session)
+        }
+
+        fn verify_login(&self, signed_data: &str, signature: &str) -> Result<Login, QuestionError> {
+            self.tozny.verify_login(signed_data, signature)
+        }
+    }
+
+    #[test]
+    fn it_rejects_logins_for_another_session() {
+        let tozny = FakeTozny::new();
+        let other = tozny.login_challenge().unwrap().session_id;
+        tozny.confirm(&other, &UserId::from_slice("sid_mallory"), "Mallory");
+        let flow = OtherSession { tozny: tozny, session: other };
+        let mut store = MemoryPresenceStore::new();
+        store.put("alice", Presence::from_slice("pres_old")).unwrap();
+        match login_with_push(&flow, &mut store, "alice", 5) {
+            Err(PresenceError::QuestionError(QuestionError::SessionMismatch)) => (),
+            r => panic!("expected a session mismatch, got {:?}", r),
+        }
+        assert_eq!(store.get("alice").unwrap(), Some(Presence::from_slice("pres_old")));
+    }
+}
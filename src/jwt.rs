@@ -0,0 +1,482 @@
+//! Converts verified Tozny logins into JSON Web Tokens, and validates those
+//! tokens.
+//!
+//! Tokens carry the registered claims `sub` (the Tozny `UserId`), `iss` (the
+//! realm key id), `iat` and `exp` (taken from `Login.expires_at`), plus `sid`
+//! for the Tozny `SessionId`.  Fields from a `User` record may be added as
+//! custom claims.
+//!
+//! Signatures are made and checked by the crate's `crypto_backend`.  This
+//! module is only available when the `jwt` cargo feature is enabled.
+
+use base64::Engine;
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use chrono::{Utc};
+use serde_json;
+use serde_json::{Map, Value};
+use std::{fmt, str};
+
+use crypto_backend::{CryptoBackend, DefaultBackend};
+use login::Login;
+use protocol::{KeyId, Secret, SessionId, Timestamp, UserId};
+use question;
+use user::User;
+
+/// Claims that are set by this module, and that may not be overridden by
+/// custom claims.
+const REGISTERED_CLAIMS: [&'static str; 5] = ["sub", "sid", "iss", "iat", "exp"];
+
+/// Base64 engine for token segments.  RFC 7515 requires unpadded base64url,
+/// so unlike `question::decode_base64` this rejects padding, the standard
+/// alphabet, line breaks and non-zero trailing bits: each token has exactly
+/// one encoding.
+const SEGMENT_ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::RequireNone)
+        .with_decode_allow_trailing_bits(false));
+
+/// Signature algorithms supported for tokens.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Algorithm {
+    HS256,
+    RS256,
+    EdDSA,
+}
+
+impl Algorithm {
+    fn name(&self) -> &'static str {
+        match *self {
+            Algorithm::HS256 => "HS256",
+            Algorithm::RS256 => "RS256",
+            Algorithm::EdDSA => "EdDSA",
+        }
+    }
+}
+
+/// Key used to sign tokens.
+pub enum SigningKey {
+    /// Shared secret for HMAC-SHA256.
+    Hs256(Secret),
+    /// RSA private key in PKCS#8 DER form, used with PKCS#1 v1.5 padding and
+    /// SHA-256.  Use `SigningKey::rsa` to check the key when it is loaded.
+    Rs256(Vec<u8>),
+    /// 64-byte Ed25519 keypair - the seed followed by the public key - as
+    /// produced by `SigningKey::ed25519`.
+    EdDsa(Vec<u8>),
+}
+
+impl SigningKey {
+    /// Reads an RSA private key from PKCS#8 DER.  Fails if the key is not a
+    /// valid RSA key.
+    pub fn rsa(pkcs8_der: &[u8]) -> Result<SigningKey, JwtError> {
+        match DefaultBackend::rsa_public_key(pkcs8_der) {
+            Some(_) => Ok(SigningKey::Rs256(pkcs8_der.to_vec())),
+            None    => Err(JwtError::InvalidKey),
+        }
+    }
+
+    /// Derives an Ed25519 signing key from a 32-byte seed.  Fails if `seed`
+    /// has any other length.
+    pub fn ed25519(seed: &[u8]) -> Result<SigningKey, JwtError> {
+        let public = DefaultBackend::ed25519_public_key(seed).ok_or(JwtError::InvalidKey)?;
+        let mut keypair = seed.to_vec();
+        keypair.extend_from_slice(&public);
+        Ok(SigningKey::EdDsa(keypair))
+    }
+
+    /// The key that validates tokens signed with this key.
+    pub fn verifying_key(&self) -> Result<VerifyingKey, JwtError> {
+        match *self {
+            SigningKey::Hs256(ref secret) => Ok(VerifyingKey::Hs256(secret.clone())),
+            SigningKey::Rs256(ref der)    => {
+                DefaultBackend::rsa_public_key(der)
+                    .map(|(n, e)| VerifyingKey::Rs256 { modulus: n, exponent: e })
+                    .ok_or(JwtError::InvalidKey)
+            },
+            SigningKey::EdDsa(_) => {
+                self.ed25519_public_key()
+                    .map(|public| VerifyingKey::EdDsa(public.to_vec()))
+                    .ok_or(JwtError::InvalidKey)
+            },
+        }
+    }
+
+    /// The public half of an Ed25519 key, for publishing to validators.
+    pub fn ed25519_public_key(&self) -> Option<&[u8]> {
+        match *self {
+            SigningKey::EdDsa(ref keypair) if keypair.len() == 64 => Some(&keypair[32..]),
+            _ => None,
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match *self {
+            SigningKey::Hs256(_) => Algorithm::HS256,
+            SigningKey::Rs256(_) => Algorithm::RS256,
+            SigningKey::EdDsa(_) => Algorithm::EdDSA,
+        }
+    }
+
+    fn sign(&self, message: &str) -> Result<Vec<u8>, JwtError> {
+        match *self {
+            SigningKey::Hs256(ref secret) => {
+                Ok(question::sign(secret, message).to_vec())
+            },
+            SigningKey::Rs256(ref der) => {
+                DefaultBackend::sign_rsa_sha256(der, message.as_bytes())
+                    .ok_or(JwtError::InvalidKey)
+            },
+            SigningKey::EdDsa(ref keypair) if keypair.len() == 64 => {
+                DefaultBackend::sign_ed25519(&keypair[..32], message.as_bytes())
+                    .ok_or(JwtError::InvalidKey)
+            },
+            SigningKey::EdDsa(_) => Err(JwtError::InvalidKey),
+        }
+    }
+}
+
+/// Key used to validate tokens.  Holding a `VerifyingKey` for `RS256` or
+/// `EdDSA` does not allow minting new tokens.
+pub enum VerifyingKey {
+    /// Shared secret for HMAC-SHA256.
+    Hs256(Secret),
+    /// RSA public key, given as its big-endian modulus and exponent.
+    Rs256 { modulus: Vec<u8>, exponent: Vec<u8> },
+    /// 32-byte Ed25519 public key.
+    EdDsa(Vec<u8>),
+}
+
+impl VerifyingKey {
+    pub fn algorithm(&self) -> Algorithm {
+        match *self {
+            VerifyingKey::Hs256(_) => Algorithm::HS256,
+            VerifyingKey::Rs256 { .. } => Algorithm::RS256,
+            VerifyingKey::EdDsa(_) => Algorithm::EdDSA,
+        }
+    }
+
+    fn verify(&self, message: &str, signature: &[u8]) -> bool {
+        match *self {
+            VerifyingKey::Hs256(ref secret) => {
+                let mac = question::sign(secret, message);
+                DefaultBackend::constant_time_eq(signature, &mac)
+            },
+            VerifyingKey::Rs256 { ref modulus, ref exponent } => {
+                DefaultBackend::verify_rsa_sha256(modulus, exponent, message.as_bytes(), signature)
+            },
+            VerifyingKey::EdDsa(ref public) => {
+                DefaultBackend::verify_ed25519(public, message.as_bytes(), signature)
+            },
+        }
+    }
+}
+
+/// Decoded contents of a validated token.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Claims {
+    pub sub:   UserId,
+    pub sid:   SessionId,
+    pub iss:   KeyId,
+    pub iat:   Timestamp,
+    pub exp:   Timestamp,
+    /// Any claims other than the registered claims above.
+    pub extra: Map<String, Value>,
+}
+
+/// Mints tokens for verified logins.
+pub struct JwtIssuer {
+    key: SigningKey,
+}
+
+impl JwtIssuer {
+    pub fn new(key: SigningKey) -> JwtIssuer {
+        JwtIssuer { key: key }
+    }
+
+    /// Creates a token for a login produced by `Realm::verify_login`.  If
+    /// a `User` record is given, its fields are added as custom claims.
+    pub fn issue(&self, login: &Login, user: Option<&User>) -> Result<String, JwtError> {
+        let mut claims = Map::new();
+        if let Some(user) = user {
+            let encoded = serde_json::to_value(user).map_err(JwtError::EncoderError)?;
+            if let Value::Object(fields) = encoded {
+                for (k, v) in fields.into_iter() {
+                    if k != "id" && !REGISTERED_CLAIMS.contains(&&k[..]) {
+                        claims.insert(k, v);
+                    }
+                }
+            }
+        }
+        claims.insert("sub".to_string(), Value::from(&login.user_id));
+        claims.insert("sid".to_string(), Value::from(&login.session_id));
+        claims.insert("iss".to_string(), Value::from(&login.realm_key_id));
+        claims.insert("iat".to_string(), Value::from(&Timestamp::new(Utc::now())));
+        claims.insert("exp".to_string(), Value::from(&login.expires_at));
+        self.encode(&claims)
+    }
+
+    /// Signs an arbitrary set of claims.
+    pub fn encode(&self, claims: &Map<String, Value>) -> Result<String, JwtError> {
+        let mut header = Map::new();
+        header.insert("alg".to_string(), Value::from(self.key.algorithm().name()));
+        header.insert("typ".to_string(), Value::from("JWT"));
+        let header = serde_json::to_string(&header).map_err(JwtError::EncoderError)?;
+        let payload = serde_json::to_string(claims).map_err(JwtError::EncoderError)?;
+        let signing_input = format!("{}.{}",
+                                    SEGMENT_ENGINE.encode(header.as_bytes()),
+                                    SEGMENT_ENGINE.encode(payload.as_bytes()));
+        let signature = self.key.sign(&signing_input)?;
+        Ok(format!("{}.{}", signing_input, SEGMENT_ENGINE.encode(&signature)))
+    }
+}
+
+/// Validates tokens minted by a `JwtIssuer`.
+pub struct JwtValidator {
+    key: VerifyingKey,
+}
+
+impl JwtValidator {
+    pub fn new(key: VerifyingKey) -> JwtValidator {
+        JwtValidator { key: key }
+    }
+
+    /// Checks the signature and expiration time of a token and decodes its
+    /// claims.  Tokens whose `alg` header does not match the algorithm of the
+    /// validator's key are rejected.
+    pub fn validate(&self, token: &str) -> Result<Claims, JwtError> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(JwtError::MalformedToken)
+        }
+        let header = decode_segment(parts[0])?;
+        match header.get("alg").and_then(|a| a.as_str()) {
+            Some(alg) if alg == self.key.algorithm().name() => (),
+            _ => return Err(JwtError::WrongAlgorithm),
+        }
+        let signature = SEGMENT_ENGINE.decode(parts[2]).map_err(|_| JwtError::MalformedToken)?;
+        let signing_input = &token[..parts[0].len() + 1 + parts[1].len()];
+        if !self.key.verify(signing_input, &signature) {
+            return Err(JwtError::InvalidSignature)
+        }
+        let claims = match decode_segment(parts[1])? {
+            Value::Object(obj) => obj,
+            _                  => return Err(JwtError::MalformedToken),
+        };
+        let claims = claims_from_object(claims)?;
+        if *claims.exp.as_slice() <= Utc::now() {
+            return Err(JwtError::Expired)
+        }
+        Ok(claims)
+    }
+}
+
+fn decode_segment(segment: &str) -> Result<Value, JwtError> {
+    let bytes = SEGMENT_ENGINE.decode(segment).map_err(|_| JwtError::MalformedToken)?;
+    let s = str::from_utf8(&bytes).map_err(|_| JwtError::MalformedToken)?;
+    serde_json::from_str(s).map_err(|_| JwtError::MalformedToken)
+}
+
+fn claims_from_object(mut obj: Map<String, Value>) -> Result<Claims, JwtError> {
+    fn string(obj: &mut Map<String, Value>, k: &str) -> Result<String, JwtError> {
+        match obj.remove(k) {
+            Some(Value::String(s)) => Ok(s),
+            _                      => Err(JwtError::MalformedToken),
+        }
+    }
+    fn time(obj: &mut Map<String, Value>, k: &str) -> Result<Timestamp, JwtError> {
+        obj.remove(k)
+            .and_then(|v| question::from_json(&v).ok())
+            .ok_or(JwtError::MalformedToken)
+    }
+    Ok(Claims {
+        sub:   UserId::new(string(&mut obj, "sub")?),
+        sid:   SessionId::new(string(&mut obj, "sid")?),
+        iss:   KeyId::new(string(&mut obj, "iss")?),
+        iat:   time(&mut obj, "iat")?,
+        exp:   time(&mut obj, "exp")?,
+        extra: obj,
+    })
+}
+
+/// Errors that may occur while minting or validating a token.
+#[derive(Debug)]
+pub enum JwtError {
+    EncoderError(serde_json::Error),
+    InvalidKey,
+    MalformedToken,
+    WrongAlgorithm,
+    InvalidSignature,
+    Expired,
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &JwtError::EncoderError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error encoding token: {}", err))
+            },
+            &JwtError::InvalidKey => {
+                f.write_str("Key cannot be used to sign tokens.")
+            },
+            &JwtError::MalformedToken => {
+                f.write_str("Token is malformed.")
+            },
+            &JwtError::WrongAlgorithm => {
+                f.write_str("Token is not signed with the expected algorithm.")
+            },
+            &JwtError::InvalidSignature => {
+                f.write_str("Token has an invalid signature.")
+            },
+            &JwtError::Expired => {
+                f.write_str("Token has expired.")
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use serde_json::{Value};
+
+    use super::*;
+    use login::Login;
+    use protocol::{KeyId, Secret, SessionId, SignatureType, Timestamp, UserId};
+    use user::User;
+
+    fn login(expires_in: Duration) -> Login {
+        Login {
+            user_id:        UserId::from_slice("sid_1234"),
+            session_id:     SessionId::from_slice("session_5678"),
+            realm_key_id:   KeyId::from_slice("sid_d915e7226947b"),
+            user_display:   "Alice".to_string(),
+            expires_at:     Timestamp::new(Utc::now() + expires_in),
+            signature_type: SignatureType::from_slice("HMAC"),
+        }
+    }
+
+    #[test]
+    fn it_round_trips_hs256_tokens() {
+        let secret = Secret::from_slice("jwt key");
+        let user = User { id: UserId::from_slice("sid_1234"), logins: 3, meta: None };
+        let token = JwtIssuer::new(SigningKey::Hs256(secret.clone()))
+            .issue(&login(Duration::minutes(5)), Some(&user)).unwrap();
+        let claims = JwtValidator::new(VerifyingKey::Hs256(secret))
+            .validate(&token).unwrap();
+        assert_eq!(claims.sub, UserId::from_slice("sid_1234"));
+        assert_eq!(claims.sid, SessionId::from_slice("session_5678"));
+        assert_eq!(claims.iss, KeyId::from_slice("sid_d915e7226947b"));
+        assert_eq!(claims.extra.get("logins"), Some(&Value::from(3)));
+    }
+
+    #[test]
+    fn it_round_trips_eddsa_tokens() {
+        let seed = [7u8; 32];
+        let key = SigningKey::ed25519(&seed).unwrap();
+        let public = key.ed25519_public_key().unwrap().to_vec();
+        let token = JwtIssuer::new(key).issue(&login(Duration::minutes(5)), None).unwrap();
+        let claims = JwtValidator::new(VerifyingKey::EdDsa(public)).validate(&token).unwrap();
+        assert_eq!(claims.sub, UserId::from_slice("sid_1234"));
+    }
+
+    #[test]
+    fn it_round_trips_rs256_tokens() {
+        let key = SigningKey::rsa(include_bytes!("../testdata/rsa_private_key.der")).unwrap();
+        let validator = JwtValidator::new(key.verifying_key().unwrap());
+        let token = JwtIssuer::new(key).issue(&login(Duration::minutes(5)), None).unwrap();
+        let claims = validator.validate(&token).unwrap();
+        assert_eq!(claims.sub, UserId::from_slice("sid_1234"));
+
+        let parts: Vec<&str> = token.split('.').collect();
+        assert_eq!(decode_segment(parts[0]).unwrap()["alg"], Value::from("RS256"));
+        let forged = format!("{}.{}.{}", parts[0], parts[1], &parts[2][1..]);
+        assert!(validator.validate(&forged).is_err());
+    }
+
+    #[test]
+    fn it_rejects_malformed_keys() {
+        assert!(SigningKey::ed25519(&[7u8; 31]).is_err());
+        assert!(SigningKey::rsa(b"not a key").is_err());
+    }
+
+    #[test]
+    fn it_rejects_expired_tokens() {
+        let secret = Secret::from_slice("jwt key");
+        let token = JwtIssuer::new(SigningKey::Hs256(secret.clone()))
+            .issue(&login(Duration::minutes(-5)), None).unwrap();
+        match JwtValidator::new(VerifyingKey::Hs256(secret)).validate(&token) {
+            Err(JwtError::Expired) => (),
+            r => panic!("expected expired token, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_rejects_tokens_with_a_different_algorithm() {
+        let token = JwtIssuer::new(SigningKey::ed25519(&[7u8; 32]).unwrap())
+            .issue(&login(Duration::minutes(5)), None).unwrap();
+        let validator = JwtValidator::new(VerifyingKey::Hs256(Secret::from_slice("jwt key")));
+        match validator.validate(&token) {
+            Err(JwtError::WrongAlgorithm) => (),
+            r => panic!("expected wrong algorithm, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_rejects_tampered_tokens() {
+        let secret = Secret::from_slice("jwt key");
+        let token = JwtIssuer::new(SigningKey::Hs256(secret.clone()))
+            .issue(&login(Duration::minutes(5)), None).unwrap();
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = format!("{}.{}x.{}", parts[0], parts[1], parts[2]);
+        assert!(JwtValidator::new(VerifyingKey::Hs256(secret)).validate(&forged).is_err());
+    }
+
+    #[test]
+    fn it_accepts_only_one_encoding_of_a_token() {
+        let secret = Secret::from_slice("jwt key");
+        let validator = JwtValidator::new(VerifyingKey::Hs256(secret.clone()));
+        let token = JwtIssuer::new(SigningKey::Hs256(secret))
+            .issue(&login(Duration::minutes(5)), None).unwrap();
+        assert!(validator.validate(&token).is_ok());
+
+        let parts: Vec<&str> = token.split('.').collect();
+        // A 32-byte HS256 signature is 43 characters, one short of a padded
+        // block.
+        for forged in &[format!("{}.{}.{}=", parts[0], parts[1], parts[2]),
+                        format!("{}.{}.{}\n", parts[0], parts[1], parts[2])] {
+            match validator.validate(forged) {
+                Err(JwtError::MalformedToken) => (),
+                r => panic!("expected malformed token, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn it_rejects_the_standard_base64_alphabet() {
+        let secret = Secret::from_slice("jwt key");
+        let validator = JwtValidator::new(VerifyingKey::Hs256(secret.clone()));
+        // Find a token whose signature uses a character that differs between
+        // the two alphabets.
+        let token = (0..1000)
+            .map(|n| {
+                let mut claims = Map::new();
+                claims.insert("n".to_string(), Value::from(n));
+                claims.insert("exp".to_string(),
+                              Value::from(&Timestamp::new(Utc::now() + Duration::minutes(5))));
+                JwtIssuer::new(SigningKey::Hs256(secret.clone())).encode(&claims).unwrap()
+            })
+            .find(|t| t.rsplit('.').next().unwrap().contains(['-', '_']))
+            .unwrap();
+        let standard: String = token.chars()
+            .map(|c| match c { '-' => '+', '_' => '/', c => c })
+            .collect();
+        match validator.validate(&standard) {
+            Err(JwtError::MalformedToken) => (),
+            r => panic!("expected malformed token, got {:?}", r),
+        }
+    }
+}
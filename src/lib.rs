@@ -1,8 +1,3 @@
-#![feature(collections)]
-#![feature(core)]
-#![feature(old_io)]
-#![feature(std_misc)]
-
 //! Library interface to the [Tozny authentication service][tozny].  The purpose
 //! of this SDK is to make it easy to add Tozny support to Rust apps.
 //!
@@ -11,21 +6,85 @@
 //! [tozny]: http://tozny.com/
 //! [tozny-pam]: https://github.com/tozny/tozny-pam
 
+extern crate base64;
+#[cfg(feature = "tower")]
+extern crate bytes;
 extern crate chrono;
-extern crate collections;
-extern crate core;
-extern crate crypto;
-extern crate hyper;
+#[cfg(feature = "backend-rustcrypto")]
+extern crate ed25519_dalek;
+#[cfg(feature = "backend-rustcrypto")]
+extern crate hmac;
+#[cfg(feature = "tower")]
+extern crate http;
+#[cfg(feature = "tower")]
+extern crate http_body;
+#[cfg(feature = "tower")]
+extern crate http_body_util;
+#[cfg(feature = "qr")]
+extern crate image;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(feature = "openssl")]
+extern crate openssl;
+#[cfg(feature = "qr")]
+extern crate qrcode;
+#[cfg(feature = "backend-rustcrypto")]
 extern crate rand;
-extern crate "rustc-serialize" as rustc_serialize;
+#[cfg(feature = "backend-rustcrypto")]
+extern crate rsa;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "backend-rustcrypto")]
+extern crate sha2;
+#[cfg(feature = "backend-rustcrypto")]
+extern crate subtle;
+#[cfg(feature = "tower")]
+extern crate tokio;
+#[cfg(feature = "tower")]
+extern crate tower_layer;
+#[cfg(feature = "tower")]
+extern crate tower_service;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
+extern crate ureq;
 extern crate url;
 
 pub use self::login::{Login};
+pub use self::presence::{PresenceStore};
 pub use self::realm::{Realm};
+pub use self::session::{Session, SessionManager};
 pub use self::user::{User, UserApi};
+pub use self::verifier::{LoginVerifier};
 
+pub mod audit;
+pub mod canonical;
+pub mod crypto_backend;
+pub mod flow;
+#[cfg(feature = "jwt")]
+pub mod jwt;
 pub mod login;
+pub mod mapping;
+pub mod metrics;
+pub mod offline;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+pub mod presence;
 pub mod protocol;
+#[cfg(feature = "qr")]
+pub mod qr;
 pub mod question;
 pub mod realm;
+pub mod session;
+pub mod status;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "tower")]
+pub mod tower;
+pub mod trace;
 pub mod user;
+pub mod verifier;
+pub mod web;
@@ -1,8 +1,10 @@
-use protocol::{KeyId, SessionId, SignatureType, Timestamp, UserId};
+use protocol::{KeyId, Newtype, Secret, SessionId, SignatureType, Timestamp, UserId};
+use question;
+use question::{PublicKey, QuestionError};
 
 /// Upon success authentication, the `user.check_session_status` API call will
 /// return a `Login` value.
-#[derive(Debug, RustcDecodable, RustcEncodable)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Login {
     pub user_id:        UserId,
     pub session_id:     SessionId,
@@ -11,3 +13,131 @@ pub struct Login {
     pub expires_at:     Timestamp,
     pub signature_type: SignatureType,
 }
+
+/// The one field that must be read before a login's signature can be checked.
+#[derive(Deserialize)]
+struct SignedWith {
+    signature_type: SignatureType,
+}
+
+/// Checks the signature on a login using the algorithm named by its
+/// `signature_type`, then decodes it.
+///
+/// `HMAC` signatures are checked with the realm `secret`.  `RSA` and
+/// `ED25519` signatures are checked with those of `public_keys` that have the
+/// matching type; any one of them may have made the signature.  A service that
+/// holds only public keys passes `None` as the secret, and then rejects
+/// HMAC-signed logins.
+///
+/// This function runs locally - it does not make any network requests.
+pub fn verify(secret: Option<&Secret>, public_keys: &[PublicKey], signed_data: &str,
+              signature: &str) -> Result<Login, QuestionError> {
+    // The signature covers `signature_type`, so a forged type fails below.
+    let signature_type = match question::unpack::<SignedWith>(signed_data) {
+        Ok(s)  => s.signature_type,
+        Err(_) => return Err(QuestionError::InvalidSignature),
+    };
+    let valid = match signature_type.as_slice() {
+        "HMAC" => {
+            secret.map(|s| question::check_signature(s, signature, signed_data))
+                .unwrap_or(false)
+        },
+        "RSA" | "ED25519" => {
+            public_keys.iter()
+                .filter(|k| k.signature_type() == signature_type)
+                .any(|k| question::check_public_signature(k, signature, signed_data))
+        },
+        _ => return Err(QuestionError::UnsupportedSignatureType(signature_type)),
+    };
+    if valid {
+        question::unpack(signed_data)
+    }
+    else {
+        Err(QuestionError::InvalidSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use serde_json::{Value};
+
+    use super::*;
+    use protocol::{Secret, UserId};
+    use question;
+    use question::{PublicKey, QuestionError};
+
+    struct Vector {
+        key:         PublicKey,
+        signed_data: String,
+        signature:   String,
+    }
+
+    fn b64(v: &Value) -> Vec<u8> {
+        question::decode_base64(v.as_str().unwrap()).unwrap()
+    }
+
+    fn vector(contents: &str) -> Vector {
+        let js: Value = serde_json::from_str(contents).unwrap();
+        let k = &js["public_key"];
+        let key = match k["kty"].as_str().unwrap() {
+            "RSA" => PublicKey::Rsa { modulus: b64(&k["n"]), exponent: b64(&k["e"]) },
+            _     => PublicKey::Ed25519(b64(&k["x"])),
+        };
+        Vector {
+            key:         key,
+            signed_data: js["question"]["signed_data"].as_str().unwrap().to_string(),
+            signature:   js["question"]["signature"].as_str().unwrap().to_string(),
+        }
+    }
+
+    fn rsa() -> Vector {
+        vector(include_str!("../testdata/login_rsa.json"))
+    }
+
+    fn ed25519() -> Vector {
+        vector(include_str!("../testdata/login_ed25519.json"))
+    }
+
+    #[test]
+    fn it_verifies_logins_signed_with_public_key_algorithms() {
+        for v in [rsa(), ed25519()] {
+            let keys = [rsa().key, ed25519().key];
+            let login = verify(None, &keys, &v.signed_data, &v.signature).unwrap();
+            assert_eq!(login.user_id, UserId::from_slice("sid_52fa6d2d0f9d3"));
+            assert_eq!(login.signature_type, v.key.signature_type());
+        }
+    }
+
+    #[test]
+    fn it_rejects_signatures_from_unknown_keys() {
+        let v = ed25519();
+        let other = PublicKey::Ed25519(vec![0x3b; 32]);
+        match verify(None, &[other, rsa().key], &v.signed_data, &v.signature) {
+            Err(QuestionError::InvalidSignature) => (),
+            r => panic!("expected invalid signature, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_rejects_hmac_logins_without_a_secret() {
+        let q: Value = serde_json::from_str(include_str!("../testdata/login.json")).unwrap();
+        let signed_data = q["question"]["signed_data"].as_str().unwrap();
+        let signature = q["question"]["signature"].as_str().unwrap();
+        match verify(None, &[rsa().key], signed_data, signature) {
+            Err(QuestionError::InvalidSignature) => (),
+            r => panic!("expected invalid signature, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_rejects_unsupported_signature_types() {
+        let secret = Secret::from_slice("realm secret");
+        let signed_data = question::encode_base64("{\"signature_type\":\"DSA\"}".as_bytes());
+        let signature = question::encode_base64(&question::sign(&secret, &signed_data));
+        match verify(Some(&secret), &[], &signed_data, &signature) {
+            Err(QuestionError::UnsupportedSignatureType(ref t)) => assert_eq!(t.as_slice(), "DSA"),
+            r => panic!("expected unsupported signature type, got {:?}", r),
+        }
+    }
+}
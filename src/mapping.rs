@@ -0,0 +1,386 @@
+//! Maps Tozny users to local accounts.
+//!
+//! `Login.user_id` is an opaque Tozny id.  Integrations such as PAM and SSH
+//! need to know which Unix account it corresponds to, and - to send a push
+//! notification - which Tozny user and device belong to a given account.
+//!
+//! A `UserMapping` answers both questions.  Three implementations are
+//! provided:
+//!
+//! - `StaticMapping`: a fixed table, typically read from a JSON file
+//! - `MetaFieldMapping`: reads the account name from a field in the Tozny
+//!   user's `meta` record, such as `username`
+//! - any `Directory`, an LDAP-like lookup interface, through
+//!   `MetaFieldMapping`
+//!
+//! `Realm` is a `Directory`, but the Tozny API cannot search users by a
+//! `meta` field, so `MetaFieldMapping<Realm>` only maps Tozny users to local
+//! accounts.  Sending a push notification to an account by name
+//! (`map_to_user_id`, `find_presence`) needs a `StaticMapping`, or a
+//! `Directory` that supports `search`.
+//!
+//! Lookups are strict: `map_to_local` and `map_to_user_id` fail unless there
+//! is exactly one candidate, and names that are not valid account names are
+//! rejected.
+
+use std::collections::BTreeMap;
+use serde_json;
+use serde_json::{Value};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::{fmt, io};
+
+use presence::{PresenceError, PresenceStore};
+use protocol::{Newtype, Presence, UserId};
+use question::{QuestionError};
+use realm::Realm;
+use user::User;
+
+/// Two-way association between Tozny users and local account names.
+pub trait UserMapping {
+    /// Lists the local account names associated with a Tozny user.
+    fn local_names(&self, user_id: &UserId) -> Result<Vec<String>, MappingError>;
+
+    /// Lists the Tozny users associated with a local account name.
+    fn user_ids(&self, local_name: &str) -> Result<Vec<UserId>, MappingError>;
+}
+
+/// LDAP-like source of Tozny user records.
+pub trait Directory {
+    /// Looks up a user by id.  Returns `Ok(None)` if there is no such user.
+    fn get_user(&self, user_id: &UserId) -> Result<Option<User>, MappingError>;
+
+    /// Finds users whose `meta` field `field` is equal to `value`.
+    fn search(&self, field: &str, value: &str) -> Result<Vec<User>, MappingError>;
+}
+
+/// The `error_code` that `realm.user_get` responds with for an unknown user.
+const USER_NOT_FOUND: &'static str = "e_user_not_found";
+
+/// Uses `Realm::user_get` to look users up.  The Tozny API does not support
+/// searching, so `search` returns `MappingError::Unsupported`.
+impl Directory for Realm {
+    fn get_user(&self, user_id: &UserId) -> Result<Option<User>, MappingError> {
+        match self.user_get(user_id) {
+            Ok(user) => Ok(Some(user)),
+            Err(QuestionError::ErrorResponse(ref errs)) if is_user_not_found(errs) => Ok(None),
+            Err(err) => Err(MappingError::QuestionError(err)),
+        }
+    }
+
+    fn search(&self, _field: &str, _value: &str) -> Result<Vec<User>, MappingError> {
+        Err(MappingError::Unsupported)
+    }
+}
+
+/// Other error responses, such as a rejected signature, are failures rather
+/// than a missing user.
+fn is_user_not_found(errs: &Value) -> bool {
+    let not_found = |err: &Value| {
+        err.get("error_code").and_then(|v| v.as_str()) == Some(USER_NOT_FOUND)
+    };
+    match *errs {
+        Value::Array(ref errs) => errs.iter().any(not_found),
+        ref err                => not_found(err),
+    }
+}
+
+/// Finds the single local account for a Tozny user.
+pub fn map_to_local<M: UserMapping>(mapping: &M, user_id: &UserId) -> Result<String, MappingError> {
+    let names = mapping.local_names(user_id)?;
+    let name = exactly_one(names)?;
+    if is_valid_account_name(&name) {
+        Ok(name)
+    }
+    else {
+        Err(MappingError::InvalidName(name))
+    }
+}
+
+/// Finds the single Tozny user for a local account.
+pub fn map_to_user_id<M: UserMapping>(mapping: &M, local_name: &str) -> Result<UserId, MappingError> {
+    if !is_valid_account_name(local_name) {
+        return Err(MappingError::InvalidName(local_name.to_string()))
+    }
+    let ids = mapping.user_ids(local_name)?;
+    exactly_one(ids.into_iter().map(|id| id.unwrap()).collect()).map(UserId::new)
+}
+
+/// Finds the Tozny user for a local account, along with the presence value
+/// remembered for that account, if any.  Presence values are looked up under
+/// the local name first, then under the Tozny user id.
+pub fn find_presence<M, S>(mapping: &M, store: &S, local_name: &str
+                           ) -> Result<(UserId, Option<Presence>), MappingError>
+    where M: UserMapping, S: PresenceStore {
+    let user_id = map_to_user_id(mapping, local_name)?;
+    let mut presence = store.get(local_name).map_err(MappingError::PresenceError)?;
+    if presence.is_none() {
+        presence = store.get(user_id.as_slice()).map_err(MappingError::PresenceError)?;
+    }
+    Ok((user_id, presence))
+}
+
+/// Accepts names that are safe to use as Unix account names: non-empty, at
+/// most 32 characters, made of ASCII letters, digits, `.`, `_` and `-`, and
+/// not starting with `-`.
+pub fn is_valid_account_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 32 && !name.starts_with('-') &&
+        name.chars().all(|c| matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-'))
+}
+
+fn exactly_one(mut candidates: Vec<String>) -> Result<String, MappingError> {
+    candidates.sort();
+    candidates.dedup();
+    match candidates.len() {
+        0 => Err(MappingError::NotFound),
+        1 => Ok(candidates.pop().unwrap()),
+        _ => Err(MappingError::Ambiguous(candidates)),
+    }
+}
+
+/// A fixed table from Tozny user ids to local account names.
+#[derive(Clone, Debug)]
+pub struct StaticMapping {
+    users: BTreeMap<String, String>,
+}
+
+impl StaticMapping {
+    /// Keys are Tozny user ids; values are local account names.
+    pub fn new(users: BTreeMap<String, String>) -> StaticMapping {
+        StaticMapping { users: users }
+    }
+
+    /// Reads a JSON object from a file, such as
+    /// `{"sid_abcdef": "alice", "sid_123456": "bob"}`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<StaticMapping, MappingError> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(MappingError::IoError)?;
+        serde_json::from_str(&contents)
+            .map(StaticMapping::new)
+            .map_err(MappingError::DecoderError)
+    }
+}
+
+impl UserMapping for StaticMapping {
+    fn local_names(&self, user_id: &UserId) -> Result<Vec<String>, MappingError> {
+        Ok(self.users.get(user_id.as_slice()).cloned().into_iter().collect())
+    }
+
+    fn user_ids(&self, local_name: &str) -> Result<Vec<UserId>, MappingError> {
+        Ok(self.users.iter()
+           .filter(|&(_, name)| name == local_name)
+           .map(|(id, _)| UserId::from_slice(id))
+           .collect())
+    }
+}
+
+/// Reads the local account name from a field of the Tozny user's `meta`
+/// record.  Looking up the Tozny user for an account name relies on
+/// `Directory::search`, which `Realm` does not support.
+pub struct MetaFieldMapping<D> {
+    directory: D,
+    field:     String,
+}
+
+impl<D: Directory> MetaFieldMapping<D> {
+    pub fn new(directory: D, field: &str) -> MetaFieldMapping<D> {
+        MetaFieldMapping {
+            directory: directory,
+            field:     field.to_string(),
+        }
+    }
+}
+
+impl<D: Directory> UserMapping for MetaFieldMapping<D> {
+    fn local_names(&self, user_id: &UserId) -> Result<Vec<String>, MappingError> {
+        let user = self.directory.get_user(user_id)?;
+        Ok(user.and_then(|u| u.meta_field(&self.field)).into_iter().collect())
+    }
+
+    fn user_ids(&self, local_name: &str) -> Result<Vec<UserId>, MappingError> {
+        let users = self.directory.search(&self.field, local_name)?;
+        // Directories may match loosely (for example, case-insensitively);
+        // only exact matches count.
+        Ok(users.into_iter()
+           .filter(|u| u.meta_field(&self.field).map(|v| v == local_name).unwrap_or(false))
+           .map(|u| u.id)
+           .collect())
+    }
+}
+
+/// Reasons that a mapping lookup can fail.
+#[derive(Debug)]
+pub enum MappingError {
+    NotFound,
+    Ambiguous(Vec<String>),
+    InvalidName(String),
+    Unsupported,
+    IoError(io::Error),
+    DecoderError(serde_json::Error),
+    PresenceError(PresenceError),
+    QuestionError(QuestionError),
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &MappingError::NotFound => {
+                f.write_str("No matching account.")
+            },
+            &MappingError::Ambiguous(ref names) => {
+                f.write_fmt(format_args!(
+                        "Mapping is ambiguous; candidates are: {}", names.join(", ")))
+            },
+            &MappingError::InvalidName(ref name) => {
+                f.write_fmt(format_args!(
+                        "Not a valid account name: {:?}", name))
+            },
+            &MappingError::Unsupported => {
+                f.write_str("This directory does not support the requested lookup.")
+            },
+            &MappingError::IoError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error reading user mapping: {}", err))
+            },
+            &MappingError::DecoderError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error in user mapping: {}", err))
+            },
+            &MappingError::PresenceError(ref err) => fmt::Display::fmt(err, f),
+            &MappingError::QuestionError(ref err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use serde_json::{Map, Value};
+
+    use super::*;
+    use presence::{MemoryPresenceStore, PresenceStore};
+    use protocol::{KeyId, Newtype, Presence, Secret, UserId};
+    use testing::{FakeResponse, serve_http};
+    use user::User;
+
+    fn static_mapping() -> StaticMapping {
+        let mut users = BTreeMap::new();
+        users.insert("sid_alice".to_string(), "alice".to_string());
+        users.insert("sid_bob".to_string(), "bob".to_string());
+        users.insert("sid_bob2".to_string(), "bob".to_string());
+        users.insert("sid_evil".to_string(), "../root".to_string());
+        StaticMapping::new(users)
+    }
+
+    struct FakeDirectory(Vec<User>);
+
+    impl Directory for FakeDirectory {
+        fn get_user(&self, user_id: &UserId) -> Result<Option<User>, MappingError> {
+            Ok(self.0.iter().find(|u| u.id == *user_id).map(|u| user(u.id.as_slice(),
+                                                                       u.meta_field("username"))))
+        }
+
+        fn search(&self, field: &str, value: &str) -> Result<Vec<User>, MappingError> {
+            Ok(self.0.iter()
+               .filter(|u| u.meta_field(field).map(|v| v.to_lowercase() == value.to_lowercase())
+                       .unwrap_or(false))
+               .map(|u| user(u.id.as_slice(), u.meta_field(field)))
+               .collect())
+        }
+    }
+
+    fn user(id: &str, username: Option<String>) -> User {
+        let meta = username.map(|name| {
+            let mut meta = Map::new();
+            meta.insert("username".to_string(), Value::from(name));
+            meta
+        });
+        User { id: UserId::from_slice(id), logins: 0, meta: meta }
+    }
+
+    #[test]
+    fn static_mapping_maps_both_ways() {
+        let m = static_mapping();
+        assert_eq!(map_to_local(&m, &UserId::from_slice("sid_alice")).unwrap(), "alice");
+        assert_eq!(map_to_user_id(&m, "alice").unwrap(), UserId::from_slice("sid_alice"));
+    }
+
+    #[test]
+    fn it_rejects_ambiguous_and_missing_mappings() {
+        let m = static_mapping();
+        match map_to_user_id(&m, "bob") {
+            Err(MappingError::Ambiguous(ids)) => assert_eq!(ids.len(), 2),
+            r => panic!("expected ambiguity, got {:?}", r),
+        }
+        match map_to_local(&m, &UserId::from_slice("sid_carol")) {
+            Err(MappingError::NotFound) => (),
+            r => panic!("expected no match, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_account_names() {
+        match map_to_local(&static_mapping(), &UserId::from_slice("sid_evil")) {
+            Err(MappingError::InvalidName(_)) => (),
+            r => panic!("expected invalid name, got {:?}", r),
+        }
+        assert!(is_valid_account_name("alice.smith-2"));
+        assert!(!is_valid_account_name("-alice"));
+        assert!(!is_valid_account_name(""));
+        assert!(!is_valid_account_name("al ice"));
+    }
+
+    #[test]
+    fn meta_field_mapping_uses_the_directory() {
+        let m = MetaFieldMapping::new(FakeDirectory(vec![
+            user("sid_alice", Some("alice".to_string())),
+            user("sid_alice_upper", Some("ALICE".to_string())),
+            user("sid_nameless", None),
+        ]), "username");
+        assert_eq!(map_to_local(&m, &UserId::from_slice("sid_alice")).unwrap(), "alice");
+        assert_eq!(map_to_user_id(&m, "alice").unwrap(), UserId::from_slice("sid_alice"));
+        assert!(map_to_local(&m, &UserId::from_slice("sid_nameless")).is_err());
+    }
+
+    #[test]
+    fn realm_directory_only_treats_unknown_users_as_missing() {
+        let realm = |error: Value| {
+            let url = serve_http(move |_| FakeResponse::json(&json!({
+                "return": "error",
+                "errors": [error.clone()],
+            })));
+            Realm::new(KeyId::from_slice("sid_123"), Secret::from_slice("secret"), url)
+        };
+        let unknown = realm(json!({
+            "error_code":    "e_user_not_found",
+            "error_message": "No such user.",
+        }));
+        assert!(unknown.get_user(&UserId::from_slice("sid_alice")).unwrap().is_none());
+
+        let rejected = realm(json!({
+            "error_code":    "e_invalid_signature",
+            "error_message": "Invalid signature.",
+        }));
+        match rejected.get_user(&UserId::from_slice("sid_alice")) {
+            Err(MappingError::QuestionError(QuestionError::ErrorResponse(_))) => (),
+            r => panic!("expected an API error, got {:?}", r),
+        }
+        match map_to_user_id(&MetaFieldMapping::new(unknown, "username"), "alice") {
+            Err(MappingError::Unsupported) => (),
+            r => panic!("expected unsupported, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_finds_the_presence_for_a_local_account() {
+        let m = static_mapping();
+        let mut store = MemoryPresenceStore::new();
+        store.put("sid_alice", Presence::from_slice("p_1")).unwrap();
+        let (uid, presence) = find_presence(&m, &store, "alice").unwrap();
+        assert_eq!(uid, UserId::from_slice("sid_alice"));
+        assert_eq!(presence, Some(Presence::from_slice("p_1")));
+    }
+}
@@ -0,0 +1,378 @@
+//! Metrics for logins and Tozny API calls.
+//!
+//! `Realm` and `UserApi` report to a `MetricsRecorder`, if one is configured
+//! with `with_metrics`:
+//!
+//! - challenges created, push notifications sent, logins verified, and
+//!   logins rejected because of an invalid signature
+//! - API errors, by method and error category (see `trace::classify_error`)
+//! - latency of every API call, by method
+//! - time from `login_challenge` to a verified `Login` for the same session
+//!
+//! Give clones of one recorder to the `UserApi` and the `Realm` that work
+//! together, so that the time from challenge to login can be measured.
+//!
+//! `PrometheusMetrics` keeps counts and histograms in memory and renders them
+//! in the Prometheus text exposition format.  It is also a `web::WebHandler`,
+//! so it can be mounted at `/metrics` and scraped directly.
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::fmt;
+
+use protocol::{Method, Newtype, SessionId};
+use question::{QuestionError};
+use trace;
+use web::{WebHandler, WebRequest, WebResponse};
+
+/// Receives measurements.  Implement this to forward metrics to another
+/// metrics system.
+pub trait Metrics: Send + Sync {
+    fn challenge_created(&self);
+    fn push_sent(&self);
+    fn login_verified(&self);
+    fn invalid_signature(&self);
+
+    /// Called for every API call.  `error` is `None` for successful calls.
+    fn api_call(&self, method: &str, latency_secs: f64, error: Option<&'static str>);
+
+    /// Time from `login_challenge` to a verified login for the same session.
+    fn login_duration(&self, seconds: f64);
+}
+
+/// Challenges that have not been verified within this many seconds are
+/// forgotten, and do not contribute to `login_duration`.
+const PENDING_LOGIN_SECS: i64 = 600;
+
+/// Optional, shared reference to a `Metrics` implementation, along with the
+/// start times of pending logins.  Clones share state.  All recorders compare
+/// equal, so that holding one does not affect equality of the holder.
+#[derive(Clone, Default)]
+pub struct MetricsRecorder {
+    inner: Option<Arc<RecorderState>>,
+}
+
+struct RecorderState {
+    metrics: Arc<dyn Metrics>,
+    pending: Mutex<BTreeMap<String, DateTime<Utc>>>,
+}
+
+impl MetricsRecorder {
+    /// A recorder that discards measurements.
+    pub fn none() -> MetricsRecorder {
+        MetricsRecorder { inner: None }
+    }
+
+    pub fn new<M: Metrics + 'static>(metrics: Arc<M>) -> MetricsRecorder {
+        MetricsRecorder {
+            inner: Some(Arc::new(RecorderState {
+                metrics: metrics,
+                pending: Mutex::new(BTreeMap::new()),
+            })),
+        }
+    }
+
+    pub fn challenge_created(&self, session_id: &SessionId) {
+        if let Some(ref state) = self.inner {
+            let now = Utc::now();
+            let cutoff = now - Duration::seconds(PENDING_LOGIN_SECS);
+            let mut pending = state.pending.lock().unwrap();
+            let stale: Vec<String> = pending.iter()
+                .filter(|&(_, started)| *started < cutoff)
+                .map(|(sid, _)| sid.clone())
+                .collect();
+            for sid in stale.iter() {
+                pending.remove(sid);
+            }
+            pending.insert(session_id.as_slice().to_string(), now);
+            state.metrics.challenge_created();
+        }
+    }
+
+    pub fn push_sent(&self) {
+        if let Some(ref state) = self.inner {
+            state.metrics.push_sent();
+        }
+    }
+
+    pub fn login_verified(&self, session_id: &SessionId) {
+        if let Some(ref state) = self.inner {
+            state.metrics.login_verified();
+            let started = state.pending.lock().unwrap().remove(session_id.as_slice());
+            if let Some(started) = started {
+                state.metrics.login_duration(seconds_since(started));
+            }
+        }
+    }
+
+    pub fn invalid_signature(&self) {
+        if let Some(ref state) = self.inner {
+            state.metrics.invalid_signature();
+        }
+    }
+
+    /// Records the latency and outcome of an API call that started at
+    /// `started`.
+    pub fn api_call<T>(&self, method: &Method, started: DateTime<Utc>,
+                       result: &Result<T, QuestionError>) {
+        if let Some(ref state) = self.inner {
+            let error = result.as_ref().err().map(trace::classify_error);
+            state.metrics.api_call(method.as_slice(), seconds_since(started), error);
+        }
+    }
+}
+
+fn seconds_since(started: DateTime<Utc>) -> f64 {
+    let elapsed = Utc::now() - started;
+    match elapsed.num_microseconds() {
+        Some(us) => us as f64 / 1_000_000.0,
+        None     => elapsed.num_seconds() as f64,
+    }
+}
+
+impl PartialEq for MetricsRecorder {
+    fn eq(&self, _: &MetricsRecorder) -> bool {
+        true
+    }
+}
+
+impl Eq for MetricsRecorder {}
+
+impl fmt::Debug for MetricsRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(if self.inner.is_some() { "MetricsRecorder(enabled)" }
+                    else { "MetricsRecorder(none)" })
+    }
+}
+
+/// Histogram bucket bounds for API latency, in seconds.
+pub const API_LATENCY_BUCKETS: &'static [f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Histogram bucket bounds for the time to complete a login, in seconds.
+pub const LOGIN_DURATION_BUCKETS: &'static [f64] = &[
+    1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+#[derive(Clone, Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum:    f64,
+    count:  u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram { bounds: bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (i, bound) in self.bounds.iter().enumerate() {
+            if value <= *bound {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Writes `_bucket`, `_sum` and `_count` series.  `labels` is either
+    /// empty or a list of `name="value"` pairs, without braces.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, self.count);
+        let braced = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+#[derive(Debug)]
+struct PrometheusState {
+    challenges_created: u64,
+    pushes_sent:        u64,
+    logins_verified:    u64,
+    invalid_signatures: u64,
+    api_errors:         BTreeMap<(String, &'static str), u64>,
+    api_latency:        BTreeMap<String, Histogram>,
+    login_duration:     Histogram,
+}
+
+/// `Metrics` implementation that renders the Prometheus text format.
+#[derive(Debug)]
+pub struct PrometheusMetrics {
+    state: Mutex<PrometheusState>,
+}
+
+impl Default for PrometheusMetrics {
+    fn default() -> PrometheusMetrics {
+        PrometheusMetrics::new()
+    }
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> PrometheusMetrics {
+        PrometheusMetrics {
+            state: Mutex::new(PrometheusState {
+                challenges_created: 0,
+                pushes_sent:        0,
+                logins_verified:    0,
+                invalid_signatures: 0,
+                api_errors:         BTreeMap::new(),
+                api_latency:        BTreeMap::new(),
+                login_duration:     Histogram::new(LOGIN_DURATION_BUCKETS),
+            }),
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format, version
+    /// 0.0.4.
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+        counter(&mut out, "tozny_challenges_created_total",
+                "Login challenges created.", state.challenges_created);
+        counter(&mut out, "tozny_pushes_sent_total",
+                "Push notifications sent.", state.pushes_sent);
+        counter(&mut out, "tozny_logins_verified_total",
+                "Logins with a valid signature.", state.logins_verified);
+        counter(&mut out, "tozny_invalid_signatures_total",
+                "Logins rejected because of an invalid signature.", state.invalid_signatures);
+
+        let _ = writeln!(out, "# HELP tozny_api_errors_total Failed Tozny API calls.");
+        let _ = writeln!(out, "# TYPE tozny_api_errors_total counter");
+        for (&(ref method, error), count) in state.api_errors.iter() {
+            let _ = writeln!(out, "tozny_api_errors_total{{method=\"{}\",error=\"{}\"}} {}",
+                             escape_label(method), error, count);
+        }
+
+        let _ = writeln!(out, "# HELP tozny_api_request_duration_seconds \
+                               Latency of Tozny API calls.");
+        let _ = writeln!(out, "# TYPE tozny_api_request_duration_seconds histogram");
+        for (method, histogram) in state.api_latency.iter() {
+            histogram.render(&mut out, "tozny_api_request_duration_seconds",
+                             &format!("method=\"{}\"", escape_label(method)));
+        }
+
+        let _ = writeln!(out, "# HELP tozny_login_duration_seconds \
+                               Time from login challenge to verified login.");
+        let _ = writeln!(out, "# TYPE tozny_login_duration_seconds histogram");
+        state.login_duration.render(&mut out, "tozny_login_duration_seconds", "");
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+impl Metrics for PrometheusMetrics {
+    fn challenge_created(&self) {
+        self.state.lock().unwrap().challenges_created += 1;
+    }
+
+    fn push_sent(&self) {
+        self.state.lock().unwrap().pushes_sent += 1;
+    }
+
+    fn login_verified(&self) {
+        self.state.lock().unwrap().logins_verified += 1;
+    }
+
+    fn invalid_signature(&self) {
+        self.state.lock().unwrap().invalid_signatures += 1;
+    }
+
+    fn api_call(&self, method: &str, latency_secs: f64, error: Option<&'static str>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = error {
+            *state.api_errors.entry((method.to_string(), error)).or_insert(0) += 1;
+        }
+        state.api_latency.entry(method.to_string())
+            .or_insert(Histogram::new(API_LATENCY_BUCKETS))
+            .observe(latency_secs);
+    }
+
+    fn login_duration(&self, seconds: f64) {
+        self.state.lock().unwrap().login_duration.observe(seconds);
+    }
+}
+
+impl WebHandler for PrometheusMetrics {
+    /// Responds to any request with the rendered metrics.
+    fn handle(&self, _: WebRequest) -> WebResponse {
+        WebResponse {
+            status:       200,
+            content_type: "text/plain; version=0.0.4",
+            location:     None,
+            set_cookie:   None,
+            body:         self.render(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use std::sync::Arc;
+
+    use super::*;
+    use protocol::{Method, SessionId};
+    use question::{QuestionError};
+    use testing::FakeTozny;
+
+    #[test]
+    fn it_counts_events_and_api_errors_by_method() {
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let recorder = MetricsRecorder::new(metrics.clone());
+        recorder.challenge_created(&SessionId::from_slice("sess_1"));
+        recorder.push_sent();
+        let method = Method::from_slice("realm.user_get");
+        recorder.api_call(&method, Utc::now(), &Ok::<(), QuestionError>(()));
+        recorder.api_call(&method, Utc::now(), &Err::<(), _>(QuestionError::BadlyFormedResponse));
+        let text = metrics.render();
+        assert!(text.contains("tozny_challenges_created_total 1\n"));
+        assert!(text.contains("tozny_pushes_sent_total 1\n"));
+        assert!(text.contains(
+            "tozny_api_errors_total{method=\"realm.user_get\",error=\"badly_formed_response\"} 1\n"));
+        assert!(text.contains(
+            "tozny_api_request_duration_seconds_count{method=\"realm.user_get\"} 2\n"));
+        assert!(text.contains(
+            "tozny_api_request_duration_seconds_bucket{method=\"realm.user_get\",le=\"+Inf\"} 2\n"));
+    }
+
+    #[test]
+    fn it_measures_time_from_challenge_to_verified_login() {
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let recorder = MetricsRecorder::new(metrics.clone());
+        recorder.challenge_created(&SessionId::from_slice("sess_1"));
+        recorder.login_verified(&SessionId::from_slice("sess_1"));
+        recorder.login_verified(&SessionId::from_slice("sess_unknown"));
+        let text = metrics.render();
+        assert!(text.contains("tozny_logins_verified_total 2\n"));
+        assert!(text.contains("tozny_login_duration_seconds_count 1\n"));
+        assert!(text.contains("tozny_login_duration_seconds_bucket{le=\"1\"} 1\n"));
+    }
+
+    #[test]
+    fn realm_counts_invalid_signatures() {
+        let tozny = FakeTozny::new();
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let realm = tozny.realm().with_metrics(MetricsRecorder::new(metrics.clone()));
+        assert!(realm.verify_login("e30", "bm90IGEgc2lnbmF0dXJl").is_err());
+        assert!(metrics.render().contains("tozny_invalid_signatures_total 1\n"));
+    }
+}
@@ -0,0 +1,685 @@
+//! Offline grace mode for login checks.
+//!
+//! `Realm::check_valid_login` needs the Tozny API.  If the API cannot be
+//! reached, every check fails, which locks users out of services that
+//! re-check their sessions.  An `OfflineVerifier` remembers each login that it
+//! verifies, and while the API is unreachable it answers checks for those
+//! logins from its cache - but only within a bounded grace period after the
+//! login was verified, and never after the login itself expires.
+//!
+//! Cache records are signed with HMAC-SHA256 using a local key, so a record
+//! that has been tampered with, or written by someone without the key, is
+//! ignored.  The key should be different from the realm secret.
+//!
+//! Every decision made from the cache is reported to the realm's audit sink
+//! (see the `audit` module) as an `offline_check` event, and to a log
+//! function if one is configured with `log_with`.
+//!
+//! Offline mode is opt-in: nothing is cached unless logins go through
+//! `OfflineVerifier::verify_login`.  It is meant for services that re-check
+//! logins they have already accepted, such as web sessions.  The PAM module
+//! does not use it: each PAM authentication is a new Tozny login, which
+//! cannot be completed while the API is unreachable.
+
+use chrono::{Duration, Utc};
+use std::collections::BTreeMap;
+use serde_json;
+use serde_json::{Value};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+
+use audit::{AuditEvent, AuditEventKind, AuditOutcome};
+use login::Login;
+use protocol::{KeyId, Newtype, Secret, SessionId, Timestamp, UserId};
+use presence;
+use question;
+use question::{QuestionError};
+use realm::Realm;
+
+/// Prepended to the payload of a record before it is signed, so that values
+/// signed with the same key for another purpose, such as
+/// `session::SessionManager` tokens, are not accepted as records.
+const SIGNATURE_CONTEXT: &'static str = "tozny_auth.offline.v1:";
+
+/// A verified login, as remembered by the offline cache.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct OfflineRecord {
+    pub user_id:      UserId,
+    pub session_id:   SessionId,
+    pub realm_key_id: KeyId,
+    pub expires_at:   Timestamp,
+    pub verified_at:  Timestamp,
+}
+
+/// Signed records, keyed by session id.  Records are kept in memory, or in a
+/// JSON file so that they survive restarts and can be shared between
+/// processes.  A file-backed cache reads the file on every lookup, and makes
+/// each change while holding an exclusive lock on a companion file,
+/// `<path>.lock`, after re-reading the file so that records written by other
+/// processes are kept.
+pub struct OfflineCache {
+    key:     Secret,
+    path:    Option<PathBuf>,
+    entries: BTreeMap<String, String>,
+}
+
+impl OfflineCache {
+    /// Creates a cache that is lost when it is dropped.
+    pub fn in_memory(key: Secret) -> OfflineCache {
+        OfflineCache { key: key, path: None, entries: BTreeMap::new() }
+    }
+
+    /// Creates a cache backed by a file.  The file does not need to exist yet.
+    ///
+    /// The file does not contain secrets.  Records are bound to their session
+    /// and realm, so they cannot be moved to another session, but anyone who
+    /// can write to the file can delete records, or put back records that
+    /// were removed when Tozny reported their login invalid.  Make sure that
+    /// only the service that uses it can write to it.
+    pub fn open<P: AsRef<Path>>(key: Secret, path: P) -> Result<OfflineCache, OfflineError> {
+        let path = path.as_ref().to_path_buf();
+        read_entries(&path)?;
+        Ok(OfflineCache { key: key, path: Some(path), entries: BTreeMap::new() })
+    }
+
+    /// Records a verified login.
+    pub fn put(&mut self, record: &OfflineRecord) -> Result<(), OfflineError> {
+        let encoded = serde_json::to_string(record).map_err(OfflineError::EncoderError)?;
+        let payload = question::encode_base64(encoded.as_bytes());
+        let message = signed_message(&payload);
+        let signature = question::encode_base64(&question::sign(&self.key, &message));
+        let sid = record.session_id.as_slice().to_string();
+        self.update(|entries| {
+            entries.insert(sid, format!("{}.{}", payload, signature));
+            true
+        })
+    }
+
+    /// Looks up the record for a session.  Records with invalid signatures
+    /// are reported as `OfflineError::InvalidSignature`.
+    pub fn get(&self, session_id: &SessionId) -> Result<Option<OfflineRecord>, OfflineError> {
+        let signed = match self.path {
+            Some(ref path) => read_entries(path)?.remove(session_id.as_slice()),
+            None           => self.entries.get(session_id.as_slice()).cloned(),
+        };
+        match signed {
+            Some(signed) => decode(&self.key, &signed).map(Some),
+            None         => Ok(None),
+        }
+    }
+
+    /// Forgets the record for a session, if any.
+    pub fn remove(&mut self, session_id: &SessionId) -> Result<(), OfflineError> {
+        self.update(|entries| entries.remove(session_id.as_slice()).is_some())
+    }
+
+    /// Forgets records for logins that have expired.
+    pub fn purge_expired(&mut self) -> Result<(), OfflineError> {
+        let now = Utc::now();
+        let key = self.key.clone();
+        self.update(|entries| {
+            let before = entries.len();
+            entries.retain(|_, signed| match decode(&key, signed) {
+                Ok(record) => *record.expires_at.as_slice() > now,
+                Err(_)     => false,
+            });
+            entries.len() != before
+        })
+    }
+
+    /// Applies a change to the records, and saves them if `change` returns
+    /// `true`.  For a file-backed cache the file is locked, and re-read, for
+    /// the duration of the change.
+    fn update<F>(&mut self, change: F) -> Result<(), OfflineError>
+        where F: FnOnce(&mut BTreeMap<String, String>) -> bool {
+        let path = match self.path {
+            Some(ref p) => p.clone(),
+            None        => {
+                change(&mut self.entries);
+                return Ok(())
+            },
+        };
+        let _lock = presence::lock_file(&sibling(&path, ".lock")).map_err(OfflineError::IoError)?;
+        let mut entries = read_entries(&path)?;
+        if change(&mut entries) {
+            write_entries(&path, &entries)
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+/// Checks the signature on a cache record, and decodes it.
+fn decode(key: &Secret, signed: &str) -> Result<OfflineRecord, OfflineError> {
+    let mut parts = signed.splitn(2, '.');
+    let (payload, signature) = match (parts.next(), parts.next()) {
+        (Some(p), Some(s)) => (p, s),
+        _                  => return Err(OfflineError::InvalidSignature),
+    };
+    if !question::check_signature(key, signature, &signed_message(payload)) {
+        return Err(OfflineError::InvalidSignature)
+    }
+    question::unpack(payload).map_err(|_| OfflineError::InvalidSignature)
+}
+
+/// The message that is signed for a record payload.
+fn signed_message(payload: &str) -> String {
+    format!("{}{}", SIGNATURE_CONTEXT, payload)
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Writes to a temporary file first, then moves it into place, so that
+/// readers never see a partially written cache.  The file is created readable
+/// only by its owner.
+fn write_entries(path: &Path, entries: &BTreeMap<String, String>) -> Result<(), OfflineError> {
+    let encoded = serde_json::to_string(entries).map_err(OfflineError::EncoderError)?;
+    let tmp_path = sibling(path, ".tmp");
+    let _ = fs::remove_file(&tmp_path);
+    presence::private_file(&tmp_path)
+        .and_then(|mut file| file.write_all(encoded.as_bytes()).and_then(|_| file.sync_all()))
+        .map_err(OfflineError::IoError)?;
+    fs::rename(&tmp_path, path).map_err(OfflineError::IoError)
+}
+
+fn read_entries(path: &Path) -> Result<BTreeMap<String, String>, OfflineError> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(BTreeMap::new())
+        },
+        Err(err) => return Err(OfflineError::IoError(err)),
+    };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).map_err(OfflineError::IoError)?;
+    let obj = match serde_json::from_str(&contents).map_err(OfflineError::ParserError)? {
+        Value::Object(obj) => obj,
+        _                  => return Err(OfflineError::BadlyFormedCache),
+    };
+    let mut entries = BTreeMap::new();
+    for (k, v) in obj.into_iter() {
+        match v {
+            Value::String(s) => { entries.insert(k, s); },
+            _                => return Err(OfflineError::BadlyFormedCache),
+        }
+    }
+    Ok(entries)
+}
+
+/// A check that was answered from the offline cache because the Tozny API
+/// could not be reached.
+#[derive(Debug)]
+pub enum OfflineDecision {
+    /// The login was verified within the grace period.
+    Allowed { user_id: UserId, session_id: SessionId, verified_at: Timestamp },
+    /// The check failed for the given reason.
+    Denied  { user_id: UserId, session_id: SessionId, reason: &'static str },
+}
+
+impl fmt::Display for OfflineDecision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &OfflineDecision::Allowed { ref user_id, ref session_id, ref verified_at } => {
+                f.write_fmt(format_args!(
+                        "offline: allowed user_id={} session_id={} verified_at={}",
+                        user_id.as_slice(), session_id.as_slice(),
+                        verified_at.as_slice().timestamp()))
+            },
+            &OfflineDecision::Denied { ref user_id, ref session_id, reason } => {
+                f.write_fmt(format_args!(
+                        "offline: denied user_id={} session_id={} reason={}",
+                        user_id.as_slice(), session_id.as_slice(), reason))
+            },
+        }
+    }
+}
+
+/// Verifies and checks logins with a `Realm`, falling back to an
+/// `OfflineCache` when the Tozny API is unreachable.
+pub struct OfflineVerifier {
+    realm:      Realm,
+    cache:      OfflineCache,
+    grace_secs: i64,
+    log:        Box<dyn Fn(&OfflineDecision) + Send + Sync>,
+}
+
+impl OfflineVerifier {
+    /// Cached logins are accepted for up to `grace_secs` seconds after they
+    /// were verified.
+    pub fn new(realm: Realm, cache: OfflineCache, grace_secs: i64) -> OfflineVerifier {
+        OfflineVerifier {
+            realm:      realm,
+            cache:      cache,
+            grace_secs: grace_secs,
+            log:        Box::new(|_| ()),
+        }
+    }
+
+    /// Reports offline decisions to the given function.  By default they are
+    /// only reported to the realm's audit sink.
+    pub fn log_with<F>(mut self, log: F) -> OfflineVerifier
+        where F: Fn(&OfflineDecision) + Send + Sync + 'static {
+        self.log = Box::new(log);
+        self
+    }
+
+    pub fn realm(&self) -> &Realm {
+        &self.realm
+    }
+
+    /// Like `Realm::verify_login`, and also records the login in the cache.
+    /// This runs locally - it does not make any network requests.
+    pub fn verify_login(&mut self, signed_data: &str, signature: &str
+                        ) -> Result<Login, OfflineError> {
+        let login = self.realm.verify_login(signed_data, signature)
+                    .map_err(OfflineError::QuestionError)?;
+        self.cache.put(&OfflineRecord {
+            user_id:      login.user_id.clone(),
+            session_id:   login.session_id.clone(),
+            realm_key_id: login.realm_key_id.clone(),
+            expires_at:   login.expires_at.clone(),
+            verified_at:  Timestamp::new(Utc::now()),
+        })?;
+        Ok(login)
+    }
+
+    /// Like `Realm::check_valid_login`.  If the Tozny API cannot be reached,
+    /// the check succeeds if a matching login was verified within the grace
+    /// period and has not expired; otherwise the network error is returned.
+    /// Logins that Tozny reports as invalid are removed from the cache.
+    pub fn check_valid_login(&mut self, uid: &UserId, sid: &SessionId, expires_at: &Timestamp
+                             ) -> Result<bool, OfflineError> {
+        match self.realm.check_valid_login(uid, sid, expires_at) {
+            Ok(valid) => {
+                if !valid {
+                    self.cache.remove(sid)?;
+                }
+                Ok(valid)
+            },
+            Err(err) => {
+                if !is_unreachable(&err) {
+                    return Err(OfflineError::QuestionError(err))
+                }
+                match self.check_cached(uid, sid, expires_at) {
+                    Ok(verified_at) => {
+                        self.report(OfflineDecision::Allowed {
+                            user_id:     uid.clone(),
+                            session_id:  sid.clone(),
+                            verified_at: verified_at,
+                        });
+                        Ok(true)
+                    },
+                    Err(reason) => {
+                        self.report(OfflineDecision::Denied {
+                            user_id:    uid.clone(),
+                            session_id: sid.clone(),
+                            reason:     reason,
+                        });
+                        Err(OfflineError::QuestionError(err))
+                    },
+                }
+            },
+        }
+    }
+
+    /// Passes a decision to the log function, and to the realm's audit sink.
+    fn report(&self, decision: OfflineDecision) {
+        (self.log)(&decision);
+        let key_id = self.realm.key_id();
+        let event = match decision {
+            OfflineDecision::Allowed { ref user_id, ref session_id, .. } => {
+                AuditEvent::new(AuditEventKind::OfflineCheck, AuditOutcome::Success, key_id)
+                    .user_id(user_id)
+                    .session_id(session_id)
+            },
+            OfflineDecision::Denied { ref user_id, ref session_id, reason } => {
+                AuditEvent::new(AuditEventKind::OfflineCheck, AuditOutcome::Denied, key_id)
+                    .user_id(user_id)
+                    .session_id(session_id)
+                    .reason(reason)
+            },
+        };
+        self.realm.audit().record(event);
+    }
+
+    /// Returns the verification time of a usable cached login, or the reason
+    /// that there is none.
+    fn check_cached(&self, uid: &UserId, sid: &SessionId, expires_at: &Timestamp
+                    ) -> Result<Timestamp, &'static str> {
+        let record = match self.cache.get(sid) {
+            Ok(Some(record))                    => record,
+            Ok(None)                            => return Err("not cached"),
+            Err(OfflineError::InvalidSignature) => return Err("invalid cache record"),
+            Err(_)                              => return Err("cache unreadable"),
+        };
+        let now = Utc::now();
+        // The record is only signed, not bound to where it is stored, so check
+        // that it is the record for this session and realm.
+        if record.session_id != *sid || record.realm_key_id != *self.realm.key_id() {
+            Err("invalid cache record")
+        }
+        else if record.user_id != *uid || record.expires_at != *expires_at {
+            Err("login does not match cache record")
+        }
+        else if *record.expires_at.as_slice() <= now {
+            Err("login expired")
+        }
+        else if *record.verified_at.as_slice() + Duration::seconds(self.grace_secs) <= now {
+            Err("grace period elapsed")
+        }
+        else {
+            Ok(record.verified_at)
+        }
+    }
+}
+
+/// Errors that indicate the API could not be reached, as opposed to an
+/// answer from the API.  A server error, or a response that is not JSON
+/// (such as an error page from a proxy or gateway), counts as unreachable.
+fn is_unreachable(err: &QuestionError) -> bool {
+    matches!(*err, QuestionError::HttpError(_) | QuestionError::IoError(_)
+                 | QuestionError::ServerError(_) | QuestionError::ParserError(_))
+}
+
+/// Errors that may occur while verifying logins or accessing the cache.
+#[derive(Debug)]
+pub enum OfflineError {
+    IoError(io::Error),
+    ParserError(serde_json::Error),
+    EncoderError(serde_json::Error),
+    BadlyFormedCache,
+    InvalidSignature,
+    QuestionError(QuestionError),
+}
+
+impl fmt::Display for OfflineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &OfflineError::IoError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error accessing offline cache: {}", err))
+            },
+            &OfflineError::ParserError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error reading offline cache: {}", err))
+            },
+            &OfflineError::EncoderError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error writing offline cache: {}", err))
+            },
+            &OfflineError::BadlyFormedCache => {
+                f.write_str("Offline cache does not contain a JSON object of strings.")
+            },
+            &OfflineError::InvalidSignature => {
+                f.write_str("Offline cache record has an invalid signature.")
+            },
+            &OfflineError::QuestionError(ref err) => {
+                fmt::Display::fmt(err, f)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Timelike, Utc};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::{env, fs, process};
+    use url::Url;
+
+    use super::*;
+    use protocol::{KeyId, Newtype, Secret, SessionId, SignatureType, Timestamp, UserId};
+    use question;
+    use realm::Realm;
+    use session::{SessionError, SessionManager};
+    use testing::{serve_http, FakeResponse, FakeTozny};
+    use trace;
+
+    /// A realm whose API cannot be reached: nothing listens on port 1.
+    fn unreachable_realm(tozny: &FakeTozny) -> Realm {
+        Realm::new(tozny.key_id().clone(), tozny.secret().clone(),
+                   Url::parse("http://127.0.0.1:1/index.php").unwrap())
+    }
+
+    fn signed_login(tozny: &FakeTozny, expires_in: Duration) -> (String, String) {
+        let payload = format!(
+            "{{\"user_id\":\"sid_alice\",\"session_id\":\"sess_1\",\"realm_key_id\":\"{}\",\
+              \"user_display\":\"Alice\",\"expires_at\":{},\"signature_type\":\"HMAC\"}}",
+            tozny.key_id().as_slice(), (Utc::now() + expires_in).timestamp());
+        let signed_data = question::encode_base64(payload.as_bytes());
+        let signature = question::encode_base64(&question::sign(tozny.secret(), &signed_data));
+        (signed_data, signature)
+    }
+
+    /// A realm behind a gateway that answers every request with the given
+    /// status line and an HTML page.
+    fn gateway_realm(tozny: &FakeTozny, status: &'static str) -> Realm {
+        let url = serve_http(move |_| {
+            FakeResponse::new(status, "text/html", "<html><body>Bad Gateway</body></html>")
+        });
+        Realm::new(tozny.key_id().clone(), tozny.secret().clone(), url)
+    }
+
+    /// A path in the temporary directory that no other test uses.
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("tozny-offline-{}-{}-{}.json", name, process::id(),
+                                     question::random_token()))
+    }
+
+    /// Removes a cache file and its companion files.
+    fn remove_cache(path: &Path) {
+        for suffix in ["", ".lock", ".tmp"].iter() {
+            let _ = fs::remove_file(sibling(path, suffix));
+        }
+    }
+
+    fn verifier(tozny: &FakeTozny, grace_secs: i64, log: Arc<Mutex<Vec<String>>>
+                ) -> OfflineVerifier {
+        verifier_with(unreachable_realm(tozny), grace_secs, log)
+    }
+
+    fn verifier_with(realm: Realm, grace_secs: i64, log: Arc<Mutex<Vec<String>>>
+                     ) -> OfflineVerifier {
+        let cache = OfflineCache::in_memory(Secret::from_slice("local cache key"));
+        OfflineVerifier::new(realm, cache, grace_secs)
+            .log_with(move |d| log.lock().unwrap().push(d.to_string()))
+    }
+
+    #[test]
+    fn it_allows_cached_logins_within_the_grace_period() {
+        let tozny = FakeTozny::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut v = verifier(&tozny, 300, log.clone());
+        let (data, sig) = signed_login(&tozny, Duration::minutes(10));
+        let login = v.verify_login(&data, &sig).unwrap();
+        assert!(v.check_valid_login(&login.user_id, &login.session_id, &login.expires_at)
+                .unwrap());
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 1);
+        assert!(log[0].starts_with("offline: allowed user_id=sid_alice"));
+    }
+
+    #[test]
+    fn it_treats_server_errors_and_gateway_pages_as_unreachable() {
+        let tozny = FakeTozny::new();
+        for &(status, expected) in [("502 Bad Gateway", "server_error"),
+                                    ("200 OK", "parse")].iter() {
+            let realm = gateway_realm(&tozny, status);
+            let (data, sig) = signed_login(&tozny, Duration::minutes(10));
+            let login = realm.verify_login(&data, &sig).unwrap();
+            let err = realm.check_valid_login(&login.user_id, &login.session_id,
+                                              &login.expires_at).unwrap_err();
+            assert_eq!(trace::classify_error(&err), expected);
+
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let mut v = verifier_with(realm, 300, log.clone());
+            v.verify_login(&data, &sig).unwrap();
+            assert!(v.check_valid_login(&login.user_id, &login.session_id, &login.expires_at)
+                    .unwrap());
+            assert!(log.lock().unwrap()[0].starts_with("offline: allowed"));
+        }
+    }
+
+    #[test]
+    fn it_denies_logins_after_the_grace_period() {
+        let tozny = FakeTozny::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut v = verifier(&tozny, 0, log.clone());
+        let (data, sig) = signed_login(&tozny, Duration::minutes(10));
+        let login = v.verify_login(&data, &sig).unwrap();
+        assert!(v.check_valid_login(&login.user_id, &login.session_id, &login.expires_at)
+                .is_err());
+        assert!(log.lock().unwrap()[0].contains("reason=grace period elapsed"));
+    }
+
+    #[test]
+    fn it_denies_logins_that_were_never_verified() {
+        let tozny = FakeTozny::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut v = verifier(&tozny, 300, log.clone());
+        let expires_at = Timestamp::new(Utc::now() + Duration::minutes(10));
+        assert!(v.check_valid_login(&UserId::from_slice("sid_alice"),
+                                    &SessionId::from_slice("sess_1"),
+                                    &expires_at).is_err());
+        assert!(log.lock().unwrap()[0].contains("reason=not cached"));
+    }
+
+    #[test]
+    fn it_ignores_records_signed_with_another_key() {
+        // Timestamps are stored in whole seconds.
+        let now = Utc::now().with_nanosecond(0).unwrap();
+        let record = OfflineRecord {
+            user_id:      UserId::from_slice("sid_alice"),
+            session_id:   SessionId::from_slice("sess_1"),
+            realm_key_id: KeyId::from_slice("sid_realm"),
+            expires_at:   Timestamp::new(now + Duration::minutes(10)),
+            verified_at:  Timestamp::new(now),
+        };
+        let path = temp_path("keys");
+        let mut cache = OfflineCache::open(Secret::from_slice("key one"), &path).unwrap();
+        cache.put(&record).unwrap();
+        assert_eq!(OfflineCache::open(Secret::from_slice("key one"), &path).unwrap()
+                   .get(&record.session_id).unwrap(), Some(record.clone()));
+        assert!(OfflineCache::open(Secret::from_slice("key two"), &path).unwrap()
+                .get(&record.session_id).is_err());
+        remove_cache(&path);
+    }
+
+    #[test]
+    fn file_caches_share_records() {
+        let now = Utc::now().with_nanosecond(0).unwrap();
+        let record = |sid: &str| OfflineRecord {
+            user_id:      UserId::from_slice("sid_alice"),
+            session_id:   SessionId::from_slice(sid),
+            realm_key_id: KeyId::from_slice("sid_realm"),
+            expires_at:   Timestamp::new(now + Duration::minutes(10)),
+            verified_at:  Timestamp::new(now),
+        };
+        let path = temp_path("shared");
+        let key = Secret::from_slice("key one");
+        let mut first = OfflineCache::open(key.clone(), &path).unwrap();
+        let mut second = OfflineCache::open(key.clone(), &path).unwrap();
+        first.put(&record("sess_1")).unwrap();
+        second.put(&record("sess_2")).unwrap();
+        assert_eq!(first.get(&SessionId::from_slice("sess_2")).unwrap(),
+                   Some(record("sess_2")));
+        first.remove(&SessionId::from_slice("sess_2")).unwrap();
+        assert!(second.get(&SessionId::from_slice("sess_2")).unwrap().is_none());
+        assert_eq!(second.get(&SessionId::from_slice("sess_1")).unwrap(),
+                   Some(record("sess_1")));
+        remove_cache(&path);
+    }
+
+    #[test]
+    fn it_rejects_records_moved_to_another_session_or_realm() {
+        let tozny = FakeTozny::new();
+        let path = temp_path("moved");
+        let key = Secret::from_slice("local cache key");
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let log_to = log.clone();
+        let mut v = OfflineVerifier::new(unreachable_realm(&tozny),
+                                         OfflineCache::open(key.clone(), &path).unwrap(), 300)
+            .log_with(move |d| log_to.lock().unwrap().push(d.to_string()));
+        let (data, sig) = signed_login(&tozny, Duration::minutes(10));
+        let login = v.verify_login(&data, &sig).unwrap();
+
+        // Copy the validly signed record for sess_1 to the key of sess_2.
+        let mut entries = read_entries(&path).unwrap();
+        let signed = entries["sess_1"].clone();
+        entries.insert("sess_2".to_string(), signed);
+        write_entries(&path, &entries).unwrap();
+        assert!(v.check_valid_login(&login.user_id, &SessionId::from_slice("sess_2"),
+                                    &login.expires_at).is_err());
+        assert!(log.lock().unwrap()[0].contains("reason=invalid cache record"));
+
+        // The same file, shared with a verifier for another realm.
+        let other = Realm::new(KeyId::from_slice("sid_other_realm"), tozny.secret().clone(),
+                               Url::parse("http://127.0.0.1:1/index.php").unwrap());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let log_to = log.clone();
+        let mut v = OfflineVerifier::new(other, OfflineCache::open(key, &path).unwrap(), 300)
+            .log_with(move |d| log_to.lock().unwrap().push(d.to_string()));
+        assert!(v.check_valid_login(&login.user_id, &login.session_id, &login.expires_at)
+                .is_err());
+        assert!(log.lock().unwrap()[0].contains("reason=invalid cache record"));
+        remove_cache(&path);
+    }
+
+    #[test]
+    fn records_and_session_tokens_are_not_interchangeable() {
+        let key = Secret::from_slice("shared key");
+        let now = Utc::now().with_nanosecond(0).unwrap();
+        let mut cache = OfflineCache::in_memory(key.clone());
+        cache.put(&OfflineRecord {
+            user_id:      UserId::from_slice("sid_alice"),
+            session_id:   SessionId::from_slice("sess_1"),
+            realm_key_id: KeyId::from_slice("sid_realm"),
+            expires_at:   Timestamp::new(now + Duration::minutes(10)),
+            verified_at:  Timestamp::new(now),
+        }).unwrap();
+        let record = cache.entries["sess_1"].clone();
+        match SessionManager::new(key.clone()).validate(&record) {
+            Err(SessionError::InvalidSignature) => (),
+            r => panic!("expected invalid signature, got {:?}", r),
+        }
+
+        let login = Login {
+            user_id:        UserId::from_slice("sid_alice"),
+            session_id:     SessionId::from_slice("sess_2"),
+            realm_key_id:   KeyId::from_slice("sid_realm"),
+            user_display:   "Alice".to_string(),
+            expires_at:     Timestamp::new(now + Duration::minutes(10)),
+            signature_type: SignatureType::from_slice("HMAC"),
+        };
+        let token = SessionManager::new(key.clone()).mint(&login).unwrap();
+        cache.entries.insert("sess_2".to_string(), token);
+        match cache.get(&SessionId::from_slice("sess_2")) {
+            Err(OfflineError::InvalidSignature) => (),
+            r => panic!("expected invalid signature, got {:?}", r),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_caches_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = temp_path("mode");
+        let now = Utc::now().with_nanosecond(0).unwrap();
+        OfflineCache::open(Secret::from_slice("key one"), &path).unwrap().put(&OfflineRecord {
+            user_id:      UserId::from_slice("sid_alice"),
+            session_id:   SessionId::from_slice("sess_1"),
+            realm_key_id: KeyId::from_slice("sid_realm"),
+            expires_at:   Timestamp::new(now + Duration::minutes(10)),
+            verified_at:  Timestamp::new(now),
+        }).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        remove_cache(&path);
+    }
+}
@@ -0,0 +1,651 @@
+//! OpenID Connect provider backed by Tozny logins.
+//!
+//! `OidcProvider` implements the authorization code flow, so that
+//! off-the-shelf applications that speak OpenID Connect can use Tozny to log
+//! users in.  It serves these endpoints:
+//!
+//! - `GET /.well-known/openid-configuration`: discovery document
+//! - `GET /jwks`: public key used to sign ID tokens
+//! - `GET /authorize`: displays a QR code from `login_challenge`
+//! - `GET /authorize/status`: polled by the authorization page; redirects back
+//!   to the client once the login is verified with `verify_login`
+//! - `POST /token`: exchanges an authorization code for an ID token and an
+//!   access token
+//! - `GET /userinfo`: describes the user that an access token belongs to
+//!
+//! The provider talks to Tozny through the `LoginFlow` trait, so it can be run
+//! against `testing::FakeTozny` locally.  `OidcProvider` implements
+//! `web::WebHandler`, and can also be driven directly with
+//! `OidcProvider::handle`.
+//!
+//! ID tokens are signed with Ed25519 (`EdDSA`).  This module is only available
+//! when the `oidc` cargo feature is enabled.
+
+use chrono::{Duration, Utc};
+use std::collections::BTreeMap;
+use serde_json::{Map, Value};
+use std::sync::{Mutex};
+
+use flow::LoginFlow;
+use jwt::{JwtError, JwtIssuer, SigningKey};
+use login::Login;
+use protocol::{Newtype, Secret, SessionId, Timestamp};
+use question;
+use web::{WebHandler, WebRequest, WebResponse, login_page, parse_form, url_encode};
+
+/// How long an authorization code may be exchanged for tokens.
+const CODE_LIFETIME_SECS: i64 = 60;
+
+/// Authorizations that have not completed within this many seconds are
+/// forgotten; Tozny challenges have expired by then.
+const PENDING_LIFETIME_SECS: i64 = 600;
+
+/// Limits on the number of outstanding authorization codes and access
+/// tokens.  Expired entries are dropped before a limit is checked.
+const MAX_CODES:         usize = 10000;
+const MAX_ACCESS_TOKENS: usize = 100000;
+
+/// A relying party that is allowed to use the provider.
+#[derive(Clone, Debug)]
+pub struct OidcClient {
+    pub client_id:     String,
+    pub client_secret: Secret,
+    pub redirect_uris: Vec<String>,
+}
+
+/// Settings for an `OidcProvider`.
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    /// Public base URL of the provider, without a trailing slash.  This is
+    /// used as the `iss` claim in ID tokens.
+    pub issuer:  String,
+    pub clients: Vec<OidcClient>,
+}
+
+/// An HTTP request, reduced to the parts that the provider looks at.
+#[derive(Clone, Debug)]
+pub struct OidcRequest {
+    pub method:        String,
+    pub path:          String,
+    pub query:         BTreeMap<String, String>,
+    /// Decoded `application/x-www-form-urlencoded` body.
+    pub form:          BTreeMap<String, String>,
+    /// Value of the `Authorization` header, if any.
+    pub authorization: Option<String>,
+}
+
+/// An HTTP response produced by the provider.
+#[derive(Clone, Debug)]
+pub struct OidcResponse {
+    pub status:       u16,
+    pub content_type: &'static str,
+    pub location:     Option<String>,
+    pub body:         String,
+}
+
+impl OidcResponse {
+    fn json(status: u16, body: Value) -> OidcResponse {
+        OidcResponse {
+            status:       status,
+            content_type: "application/json",
+            location:     None,
+            body:         body.to_string(),
+        }
+    }
+
+    fn error(status: u16, error: &str) -> OidcResponse {
+        OidcResponse::json(status, json!({ "error": error }))
+    }
+}
+
+struct PendingAuthorization {
+    client_id:    String,
+    redirect_uri: String,
+    state:        Option<String>,
+    nonce:        Option<String>,
+    expires_at:   Timestamp,
+}
+
+struct Grant {
+    client_id:    String,
+    redirect_uri: String,
+    nonce:        Option<String>,
+    login:        Login,
+    expires_at:   Timestamp,
+}
+
+/// OpenID Connect provider.  See the module documentation.
+pub struct OidcProvider<F> {
+    config:        OidcConfig,
+    flow:          F,
+    signer:        JwtIssuer,
+    public_key:    Vec<u8>,
+    pending:       Mutex<BTreeMap<String, PendingAuthorization>>,
+    codes:         Mutex<BTreeMap<String, Grant>>,
+    access_tokens: Mutex<BTreeMap<String, Login>>,
+    max_pending:   usize,
+}
+
+impl<F: LoginFlow> OidcProvider<F> {
+    /// `signing_seed` is a 32-byte secret from which the Ed25519 key that
+    /// signs ID tokens is derived.  Fails if the seed has any other length.
+    pub fn new(config: OidcConfig, flow: F, signing_seed: &[u8]
+               ) -> Result<OidcProvider<F>, JwtError> {
+        let key = SigningKey::ed25519(signing_seed)?;
+        let public_key = key.ed25519_public_key().ok_or(JwtError::InvalidKey)?.to_vec();
+        Ok(OidcProvider {
+            config:        config,
+            flow:          flow,
+            signer:        JwtIssuer::new(key),
+            public_key:    public_key,
+            pending:       Mutex::new(BTreeMap::new()),
+            codes:         Mutex::new(BTreeMap::new()),
+            access_tokens: Mutex::new(BTreeMap::new()),
+            max_pending:   1000,
+        })
+    }
+
+    /// Maximum number of authorizations that may wait for a Tozny login at
+    /// once.  Further requests to `/authorize` are refused with
+    /// `temporarily_unavailable` until some complete or expire.  Defaults to
+    /// 1000.
+    pub fn max_pending(mut self, n: usize) -> OidcProvider<F> {
+        self.max_pending = n;
+        self
+    }
+
+    /// Dispatches a request to the appropriate endpoint.
+    pub fn handle(&self, req: &OidcRequest) -> OidcResponse {
+        match (&req.method[..], &req.path[..]) {
+            ("GET",  "/.well-known/openid-configuration") => self.discovery(),
+            ("GET",  "/jwks")                             => self.jwks(),
+            ("GET",  "/authorize")                        => self.authorize(req),
+            ("GET",  "/authorize/status")                 => self.authorize_status(req),
+            ("POST", "/token")                            => self.token(req),
+            ("GET",  "/userinfo")                         => self.userinfo(req),
+            _ => OidcResponse::error(404, "not_found"),
+        }
+    }
+
+    fn discovery(&self) -> OidcResponse {
+        let issuer = &self.config.issuer;
+        OidcResponse::json(200, json!({
+            "issuer":                                issuer,
+            "authorization_endpoint":                format!("{}/authorize", issuer),
+            "token_endpoint":                        format!("{}/token", issuer),
+            "userinfo_endpoint":                     format!("{}/userinfo", issuer),
+            "jwks_uri":                              format!("{}/jwks", issuer),
+            "response_types_supported":              ["code"],
+            "subject_types_supported":               ["public"],
+            "id_token_signing_alg_values_supported": ["EdDSA"],
+            "scopes_supported":                      ["openid", "profile"],
+            "token_endpoint_auth_methods_supported":
+                ["client_secret_basic", "client_secret_post"],
+        }))
+    }
+
+    fn jwks(&self) -> OidcResponse {
+        OidcResponse::json(200, json!({
+            "keys": [{
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "use": "sig",
+                "x":   question::encode_base64(&self.public_key),
+            }],
+        }))
+    }
+
+    fn authorize(&self, req: &OidcRequest) -> OidcResponse {
+        let q = &req.query;
+        let client = match q.get("client_id").and_then(|id| self.client(id)) {
+            Some(c) => c,
+            None    => return OidcResponse::error(400, "unauthorized_client"),
+        };
+        let redirect_uri = match q.get("redirect_uri") {
+            Some(uri) if client.redirect_uris.contains(uri) => uri.clone(),
+            _ => return OidcResponse::error(400, "invalid_request"),
+        };
+        if q.get("response_type").map(|t| &t[..]) != Some("code") {
+            return OidcResponse::error(400, "unsupported_response_type")
+        }
+        let has_openid_scope = q.get("scope")
+            .map(|s| s.split(' ').any(|scope| scope == "openid"))
+            .unwrap_or(false);
+        if !has_openid_scope {
+            return OidcResponse::error(400, "invalid_scope")
+        }
+        // Checked before contacting Tozny, so that anonymous requests cannot
+        // fill the table (or use up the realm's API quota) without bound.
+        if !has_room(&self.pending, self.max_pending, |p| &p.expires_at) {
+            return OidcResponse::error(503, "temporarily_unavailable")
+        }
+        let challenge = match self.flow.login_challenge() {
+            Ok(c)  => c,
+            Err(_) => return OidcResponse::error(502, "temporarily_unavailable"),
+        };
+        self.pending.lock().unwrap().insert(
+            challenge.session_id.as_slice().to_string(),
+            PendingAuthorization {
+                client_id:    client.client_id.clone(),
+                redirect_uri: redirect_uri,
+                state:        q.get("state").cloned(),
+                nonce:        q.get("nonce").cloned(),
+                expires_at:   Timestamp::new(Utc::now() +
+                                             Duration::seconds(PENDING_LIFETIME_SECS)),
+            });
+        OidcResponse {
+            status:       200,
+            content_type: "text/html; charset=utf-8",
+            location:     None,
+            body:         login_page(challenge.session_id.as_slice(),
+                                     challenge.qr_url.as_str(),
+                                     challenge.mobile_url.as_str(),
+                                     "authorize/status"),
+        }
+    }
+
+    fn authorize_status(&self, req: &OidcRequest) -> OidcResponse {
+        let session_id = match req.query.get("session_id") {
+            Some(sid) if self.pending.lock().unwrap().contains_key(sid) => {
+                SessionId::from_slice(sid)
+            },
+            _ => return OidcResponse::error(404, "invalid_request"),
+        };
+        let question = match self.flow.check_session_status(&session_id) {
+            Ok(Some(q)) => q,
+            Ok(None)    => return status_response("pending", None),
+            Err(_)      => return OidcResponse::error(502, "temporarily_unavailable"),
+        };
+        // Checked before the pending entry is consumed, so that a full code
+        // table makes the page retry rather than losing a completed login.
+        if !has_room(&self.codes, MAX_CODES, |g| &g.expires_at) {
+            return OidcResponse::error(503, "temporarily_unavailable")
+        }
+        let pending = self.pending.lock().unwrap().remove(session_id.as_slice());
+        let pending = match pending {
+            Some(ref p) if *p.expires_at.as_slice() <= Utc::now() => {
+                return OidcResponse::error(404, "invalid_request")
+            },
+            Some(p) => p,
+            None    => return OidcResponse::error(404, "invalid_request"),
+        };
+        let login = match self.flow.verify_login(&question.signed_data, &question.signature) {
+            Ok(login) if login.session_id == session_id => login,
+            _ => return status_response("denied", None),
+        };
+        let code = question::random_token();
+        let mut redirect = format!("{}{}code={}", pending.redirect_uri,
+                                   if pending.redirect_uri.contains('?') { '&' } else { '?' },
+                                   code);
+        if let Some(ref state) = pending.state {
+            redirect.push_str("&state=");
+            redirect.push_str(&url_encode(state));
+        }
+        self.codes.lock().unwrap().insert(code, Grant {
+            client_id:    pending.client_id,
+            redirect_uri: pending.redirect_uri,
+            nonce:        pending.nonce,
+            login:        login,
+            expires_at:   Timestamp::new(Utc::now() + Duration::seconds(CODE_LIFETIME_SECS)),
+        });
+        status_response("complete", Some(redirect))
+    }
+
+    fn token(&self, req: &OidcRequest) -> OidcResponse {
+        let form = &req.form;
+        if form.get("grant_type").map(|t| &t[..]) != Some("authorization_code") {
+            return OidcResponse::error(400, "unsupported_grant_type")
+        }
+        let client = match self.authenticate_client(req) {
+            Some(c) => c,
+            None    => return OidcResponse::error(401, "invalid_client"),
+        };
+        // Codes are single-use: remove the grant whether or not the exchange
+        // succeeds.
+        let grant = form.get("code").and_then(|code| self.codes.lock().unwrap().remove(code));
+        let grant = match grant {
+            Some(g) => g,
+            None    => return OidcResponse::error(400, "invalid_grant"),
+        };
+        if grant.client_id != client.client_id
+            || form.get("redirect_uri") != Some(&grant.redirect_uri)
+            || *grant.expires_at.as_slice() <= Utc::now() {
+            return OidcResponse::error(400, "invalid_grant")
+        }
+
+        let mut claims = Map::new();
+        claims.insert("iss".to_string(), Value::from(self.config.issuer.as_str()));
+        claims.insert("sub".to_string(), Value::from(&grant.login.user_id));
+        claims.insert("aud".to_string(), Value::from(client.client_id.as_str()));
+        claims.insert("sid".to_string(), Value::from(&grant.login.session_id));
+        claims.insert("name".to_string(), Value::from(grant.login.user_display.as_str()));
+        claims.insert("iat".to_string(), Value::from(&Timestamp::new(Utc::now())));
+        claims.insert("exp".to_string(), Value::from(&grant.login.expires_at));
+        if let Some(ref nonce) = grant.nonce {
+            claims.insert("nonce".to_string(), Value::from(nonce.as_str()));
+        }
+        let id_token = match self.signer.encode(&claims) {
+            Ok(t)  => t,
+            Err(_) => return OidcResponse::error(500, "server_error"),
+        };
+        let expires_in = grant.login.expires_at.as_slice().timestamp()
+            - Utc::now().timestamp();
+        if !has_room(&self.access_tokens, MAX_ACCESS_TOKENS, |login| &login.expires_at) {
+            return OidcResponse::error(503, "temporarily_unavailable")
+        }
+        let access_token = question::random_token();
+        self.access_tokens.lock().unwrap().insert(access_token.clone(), grant.login);
+
+        OidcResponse::json(200, json!({
+            "access_token": access_token,
+            "token_type":   "Bearer",
+            "expires_in":   expires_in,
+            "id_token":     id_token,
+        }))
+    }
+
+    fn userinfo(&self, req: &OidcRequest) -> OidcResponse {
+        let token = match req.authorization {
+            Some(ref h) if h.starts_with("Bearer ") => h[7..].trim(),
+            _ => return OidcResponse::error(401, "invalid_token"),
+        };
+        let mut tokens = self.access_tokens.lock().unwrap();
+        let expired = match tokens.get(token) {
+            Some(login) => *login.expires_at.as_slice() <= Utc::now(),
+            None        => return OidcResponse::error(401, "invalid_token"),
+        };
+        if expired {
+            tokens.remove(token);
+            return OidcResponse::error(401, "invalid_token")
+        }
+        let login = &tokens[token];
+        OidcResponse::json(200, json!({
+            "sub":  login.user_id,
+            "name": login.user_display,
+        }))
+    }
+
+    fn client(&self, client_id: &str) -> Option<&OidcClient> {
+        self.config.clients.iter().find(|c| c.client_id == client_id)
+    }
+
+    /// Accepts client credentials via HTTP Basic authentication or in the
+    /// request body.
+    fn authenticate_client(&self, req: &OidcRequest) -> Option<&OidcClient> {
+        let basic = req.authorization.as_ref()
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|b| question::decode_base64(b.trim()).ok())
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .and_then(|creds| {
+                let mut parts = creds.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(id), Some(secret)) => Some((id.to_string(), secret.to_string())),
+                    _                        => None,
+                }
+            });
+        let (id, secret) = match basic {
+            Some(creds) => creds,
+            None => match (req.form.get("client_id"), req.form.get("client_secret")) {
+                (Some(id), Some(secret)) => (id.clone(), secret.clone()),
+                _                        => return None,
+            },
+        };
+        self.client(&id).and_then(|client| {
+            if secrets_match(client.client_secret.as_slice(), &secret) {
+                Some(client)
+            }
+            else {
+                None
+            }
+        })
+    }
+}
+
+impl<F: LoginFlow + Send + Sync> WebHandler for OidcProvider<F> {
+    fn handle(&self, req: WebRequest) -> WebResponse {
+        let oidc_req = OidcRequest {
+            form:          parse_form(&String::from_utf8_lossy(&req.body)),
+            authorization: req.header("Authorization").map(|a| a.to_string()),
+            method:        req.method,
+            path:          req.path,
+            query:         req.query,
+        };
+        let resp = OidcProvider::handle(self, &oidc_req);
+        WebResponse {
+            status:       resp.status,
+            content_type: resp.content_type,
+            location:     resp.location,
+            set_cookie:   None,
+            body:         resp.body,
+        }
+    }
+}
+
+/// Drops expired entries from a table, then reports whether there is room for
+/// another.
+fn has_room<V, E>(table: &Mutex<BTreeMap<String, V>>, max: usize, expires_at: E) -> bool
+    where E: Fn(&V) -> &Timestamp {
+    let now = Utc::now();
+    let mut table = table.lock().unwrap();
+    table.retain(|_, v| *expires_at(v).as_slice() > now);
+    table.len() < max
+}
+
+fn status_response(status: &str, redirect: Option<String>) -> OidcResponse {
+    let mut obj = json!({ "status": status });
+    if let Some(r) = redirect {
+        obj["redirect"] = Value::from(r);
+    }
+    OidcResponse::json(200, obj)
+}
+
+/// Compares two secrets in constant time by comparing their MACs.
+fn secrets_match(expected: &str, given: &str) -> bool {
+    let key = Secret::new(question::random_token());
+    question::sign(&key, expected) == question::sign(&key, given)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde_json;
+    use serde_json::{Value};
+
+    use super::*;
+    use jwt::{JwtValidator, VerifyingKey};
+    use protocol::{KeyId, Secret, SessionId, UserId};
+    use testing::FakeTozny;
+
+    const REDIRECT: &'static str = "https://app.example.com/callback";
+
+    fn provider() -> OidcProvider<FakeTozny> {
+        let config = OidcConfig {
+            issuer:  "https://login.example.com".to_string(),
+            clients: vec![OidcClient {
+                client_id:     "app".to_string(),
+                client_secret: Secret::from_slice("app secret"),
+                redirect_uris: vec![REDIRECT.to_string()],
+            }],
+        };
+        OidcProvider::new(config, FakeTozny::new(), &[3u8; 32]).unwrap()
+    }
+
+    fn get(path: &str, query: Vec<(&str, &str)>) -> OidcRequest {
+        OidcRequest {
+            method:        "GET".to_string(),
+            path:          path.to_string(),
+            query:         query.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            form:          BTreeMap::new(),
+            authorization: None,
+        }
+    }
+
+    fn json(resp: &OidcResponse) -> Value {
+        serde_json::from_str(&resp.body).unwrap()
+    }
+
+    /// Extracts the Tozny session id from an authorization page.
+    fn page_session(page: &OidcResponse) -> String {
+        let start = page.body.find("data-session=\"").unwrap() + 14;
+        let end = start + page.body[start..].find('"').unwrap();
+        page.body[start..end].to_string()
+    }
+
+    /// Runs the authorization endpoint, confirms the login in the fake, and
+    /// returns the authorization code.
+    fn authorize(provider: &OidcProvider<FakeTozny>) -> String {
+        let page = provider.handle(&get("/authorize", vec![
+            ("response_type", "code"), ("client_id", "app"), ("redirect_uri", REDIRECT),
+            ("scope", "openid profile"), ("state", "xyz"), ("nonce", "n-1"),
+        ]));
+        assert_eq!(page.status, 200);
+        let sid = page_session(&page);
+
+        let pending = provider.handle(&get("/authorize/status", vec![("session_id", &sid)]));
+        assert_eq!(json(&pending).get("status").unwrap().as_str(), Some("pending"));
+
+        assert!(provider.flow.confirm(&SessionId::new(sid.clone()),
+                                      &UserId::from_slice("sid_alice"), "Alice"));
+        let done = provider.handle(&get("/authorize/status", vec![("session_id", &sid)]));
+        let redirect = json(&done).get("redirect").unwrap().as_str().unwrap().to_string();
+        assert!(redirect.starts_with(REDIRECT));
+        assert!(redirect.ends_with("&state=xyz"));
+        let start = redirect.find("code=").unwrap() + 5;
+        let end = redirect.find("&state").unwrap();
+        redirect[start..end].to_string()
+    }
+
+    fn token_request(code: &str, secret: &str) -> OidcRequest {
+        let mut req = get("/token", vec![]);
+        req.method = "POST".to_string();
+        req.form.insert("grant_type".to_string(), "authorization_code".to_string());
+        req.form.insert("code".to_string(), code.to_string());
+        req.form.insert("redirect_uri".to_string(), REDIRECT.to_string());
+        req.authorization = Some(format!("Basic {}",
+                                         STANDARD.encode(format!("app:{}", secret))));
+        req
+    }
+
+    #[test]
+    fn it_publishes_discovery_and_keys() {
+        let provider = provider();
+        let doc = json(&provider.handle(&get("/.well-known/openid-configuration", vec![])));
+        assert_eq!(doc.get("issuer").unwrap().as_str(), Some("https://login.example.com"));
+        let jwks = json(&provider.handle(&get("/jwks", vec![])));
+        assert_eq!(jwks.get("keys").unwrap().as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_completes_the_authorization_code_flow() {
+        let provider = provider();
+        let code = authorize(&provider);
+        let resp = provider.handle(&token_request(&code, "app secret"));
+        assert_eq!(resp.status, 200);
+        let tokens = json(&resp);
+        assert!(tokens.get("id_token").unwrap().as_str().unwrap().split('.').count() == 3);
+        let access_token = tokens.get("access_token").unwrap().as_str().unwrap();
+
+        let mut req = get("/userinfo", vec![]);
+        req.authorization = Some(format!("Bearer {}", access_token));
+        let info = json(&provider.handle(&req));
+        assert_eq!(info.get("sub").unwrap().as_str(), Some("sid_alice"));
+        assert_eq!(info.get("name").unwrap().as_str(), Some("Alice"));
+    }
+
+    #[test]
+    fn it_issues_id_tokens_that_verify_against_the_published_keys() {
+        let provider = provider();
+        let doc = json(&provider.handle(&get("/.well-known/openid-configuration", vec![])));
+        let issuer = doc.get("issuer").unwrap().as_str().unwrap();
+        let jwks_uri = doc.get("jwks_uri").unwrap().as_str().unwrap();
+        assert!(jwks_uri.starts_with(issuer));
+        let jwks = json(&provider.handle(&get(&jwks_uri[issuer.len()..], vec![])));
+        let key = &jwks.get("keys").unwrap()[0];
+        assert_eq!(key.get("alg").unwrap().as_str(), Some("EdDSA"));
+        let x = question::decode_base64(key.get("x").unwrap().as_str().unwrap()).unwrap();
+
+        let code = authorize(&provider);
+        let tokens = json(&provider.handle(&token_request(&code, "app secret")));
+        let id_token = tokens.get("id_token").unwrap().as_str().unwrap();
+        let claims = JwtValidator::new(VerifyingKey::EdDsa(x)).validate(id_token).unwrap();
+        assert_eq!(claims.iss, KeyId::from_slice(issuer));
+        assert_eq!(claims.sub, UserId::from_slice("sid_alice"));
+        assert_eq!(claims.extra.get("aud").unwrap().as_str(), Some("app"));
+        assert_eq!(claims.extra.get("nonce").unwrap().as_str(), Some("n-1"));
+
+        let other = JwtValidator::new(VerifyingKey::EdDsa(vec![7u8; 32]));
+        assert!(other.validate(id_token).is_err());
+    }
+
+    #[test]
+    fn it_keeps_completed_logins_while_the_code_table_is_full() {
+        let provider = provider();
+        let page = provider.handle(&get("/authorize", vec![
+            ("response_type", "code"), ("client_id", "app"), ("redirect_uri", REDIRECT),
+            ("scope", "openid"),
+        ]));
+        let sid = page_session(&page);
+        assert!(provider.flow.confirm(&SessionId::new(sid.clone()),
+                                      &UserId::from_slice("sid_alice"), "Alice"));
+
+        let question = provider.flow.check_session_status(&SessionId::new(sid.clone()))
+            .unwrap().unwrap();
+        let expires_at = Timestamp::new(Utc::now() + Duration::seconds(CODE_LIFETIME_SECS));
+        for i in 0..MAX_CODES {
+            provider.codes.lock().unwrap().insert(i.to_string(), Grant {
+                client_id:    "app".to_string(),
+                redirect_uri: REDIRECT.to_string(),
+                nonce:        None,
+                login:        provider.flow.verify_login(&question.signed_data,
+                                                         &question.signature).unwrap(),
+                expires_at:   expires_at.clone(),
+            });
+        }
+        let status = || provider.handle(&get("/authorize/status", vec![("session_id", &sid)]));
+        assert_eq!(status().status, 503);
+        assert!(provider.pending.lock().unwrap().contains_key(&sid));
+
+        provider.codes.lock().unwrap().clear();
+        assert_eq!(json(&status()).get("status").unwrap().as_str(), Some("complete"));
+    }
+
+    #[test]
+    fn it_rejects_wrong_client_secrets_and_reused_codes() {
+        let provider = provider();
+        let code = authorize(&provider);
+        assert_eq!(provider.handle(&token_request(&code, "wrong")).status, 401);
+        assert_eq!(provider.handle(&token_request(&code, "app secret")).status, 200);
+        assert_eq!(provider.handle(&token_request(&code, "app secret")).status, 400);
+    }
+
+    #[test]
+    fn it_rejects_unregistered_redirect_uris() {
+        let provider = provider();
+        let resp = provider.handle(&get("/authorize", vec![
+            ("response_type", "code"), ("client_id", "app"),
+            ("redirect_uri", "https://evil.example.com/"), ("scope", "openid"),
+        ]));
+        assert_eq!(resp.status, 400);
+    }
+
+    #[test]
+    fn it_forgets_expired_authorizations_and_limits_pending_ones() {
+        let provider = provider().max_pending(2);
+        let authorize = || provider.handle(&get("/authorize", vec![
+            ("response_type", "code"), ("client_id", "app"), ("redirect_uri", REDIRECT),
+            ("scope", "openid"),
+        ]));
+        assert_eq!(authorize().status, 200);
+        assert_eq!(authorize().status, 200);
+        assert_eq!(authorize().status, 503);
+
+        for p in provider.pending.lock().unwrap().values_mut() {
+            p.expires_at = Timestamp::new(Utc::now() - Duration::seconds(1));
+        }
+        assert_eq!(authorize().status, 200);
+        assert_eq!(provider.pending.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,303 @@
+//! Storage for `Presence` values.
+//!
+//! The response from `login_challenge` includes a `Presence` value that
+//! identifies the device that completes the login.  Keeping that value around
+//! makes it possible to send a push notification to the same device the next
+//! time the user logs in, instead of displaying a QR code.
+//!
+//! Stores are keyed by an arbitrary string.  That will usually be a local
+//! username, or the value of a `UserId` (use `user_id.as_slice()`).
+
+use std::collections::BTreeMap;
+use serde_json;
+use serde_json::{Map, Value};
+use std::{fmt, fs, io};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use protocol::{Presence};
+use question::{QuestionError};
+
+/// Interface for persisting the `Presence` value last used by each user.
+pub trait PresenceStore {
+    /// Looks up the presence value stored under the given key.
+    fn get(&self, key: &str) -> Result<Option<Presence>, PresenceError>;
+
+    /// Stores a presence value, replacing any value previously stored under
+    /// the given key.
+    fn put(&mut self, key: &str, presence: Presence) -> Result<(), PresenceError>;
+
+    /// Forgets the presence value stored under the given key, if any.
+    fn remove(&mut self, key: &str) -> Result<(), PresenceError>;
+}
+
+/// A `PresenceStore` that keeps values in memory.  Values are lost when the
+/// store is dropped.
+#[derive(Debug, Default)]
+pub struct MemoryPresenceStore {
+    entries: BTreeMap<String, Presence>,
+}
+
+impl MemoryPresenceStore {
+    pub fn new() -> MemoryPresenceStore {
+        MemoryPresenceStore { entries: BTreeMap::new() }
+    }
+}
+
+impl PresenceStore for MemoryPresenceStore {
+    fn get(&self, key: &str) -> Result<Option<Presence>, PresenceError> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, presence: Presence) -> Result<(), PresenceError> {
+        self.entries.insert(key.to_string(), presence);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), PresenceError> {
+        self.entries.remove(key);
+        Ok(())
+    }
+}
+
+/// A `PresenceStore` that keeps values in a JSON file.  The file is read on
+/// every lookup and rewritten on every change, so several processes may share
+/// one store.  Changes are made while holding an exclusive lock on a
+/// companion file, `<path>.lock`, so that concurrent updates are not lost.
+///
+/// Anyone who can read the file can send push notifications to the stored
+/// devices.  On Unix the store is written with mode `0600`; make sure that it
+/// is owned by the service that uses it.
+#[derive(Debug)]
+pub struct FilePresenceStore {
+    path: PathBuf,
+}
+
+impl FilePresenceStore {
+    /// The file does not need to exist yet - it will be created on the first
+    /// call to `put`.
+    pub fn new<P: AsRef<Path>>(path: P) -> FilePresenceStore {
+        FilePresenceStore { path: path.as_ref().to_path_buf() }
+    }
+
+    fn read_entries(&self) -> Result<Map<String, Value>, PresenceError> {
+        let mut file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Map::new())
+            },
+            Err(err) => return Err(PresenceError::IoError(err)),
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).map_err(PresenceError::IoError)?;
+        match serde_json::from_str(&contents).map_err(PresenceError::ParserError)? {
+            Value::Object(obj) => Ok(obj),
+            _                  => Err(PresenceError::BadlyFormedStore),
+        }
+    }
+
+    /// Writes to a temporary file first, then moves it into place, so that
+    /// readers never see a partially written store.  Must be called while
+    /// holding the lock from `lock`.
+    fn write_entries(&self, entries: &Map<String, Value>) -> Result<(), PresenceError> {
+        let encoded = serde_json::to_string(entries).map_err(PresenceError::EncoderError)?;
+        let tmp_path = self.sibling(".tmp");
+        let _ = fs::remove_file(&tmp_path);
+        private_file(&tmp_path)
+            .and_then(|mut file| file.write_all(encoded.as_bytes()).and_then(|_| file.sync_all()))
+            .map_err(PresenceError::IoError)?;
+        fs::rename(&tmp_path, &self.path).map_err(PresenceError::IoError)
+    }
+
+    /// Takes an exclusive lock on the store for a read-modify-write cycle.
+    fn lock(&self) -> Result<fs::File, PresenceError> {
+        lock_file(&self.sibling(".lock")).map_err(PresenceError::IoError)
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    fn update<F>(&self, change: F) -> Result<(), PresenceError>
+        where F: FnOnce(&mut Map<String, Value>) -> bool {
+        let _lock = self.lock()?;
+        let mut entries = self.read_entries()?;
+        if change(&mut entries) {
+            self.write_entries(&entries)
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+/// Takes an exclusive lock on the file at `path`, creating it if necessary.
+/// The lock is released when the returned file is closed.  Also used by
+/// `offline::OfflineCache`.
+pub(crate) fn lock_file(path: &Path) -> io::Result<fs::File> {
+    let file = private_file(path)?;
+    lock_exclusive(&file)?;
+    Ok(file)
+}
+
+/// Opens a file for writing, creating it readable only by its owner.  An
+/// existing file is neither truncated nor changed to that mode.  Also used by
+/// `offline::OfflineCache`.
+#[cfg(unix)]
+pub(crate) fn private_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create(true).truncate(false).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn private_file(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().write(true).create(true).truncate(false).open(path)
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    loop {
+        if unsafe { ::libc::flock(file.as_raw_fd(), ::libc::LOCK_EX) } == 0 {
+            return Ok(())
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+impl PresenceStore for FilePresenceStore {
+    fn get(&self, key: &str) -> Result<Option<Presence>, PresenceError> {
+        let entries = self.read_entries()?;
+        match entries.get(key) {
+            Some(&Value::String(ref s)) => Ok(Some(Presence::from_slice(s))),
+            Some(_)                     => Err(PresenceError::BadlyFormedStore),
+            None                        => Ok(None),
+        }
+    }
+
+    fn put(&mut self, key: &str, presence: Presence) -> Result<(), PresenceError> {
+        self.update(|entries| {
+            entries.insert(key.to_string(), Value::from(&presence));
+            true
+        })
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), PresenceError> {
+        self.update(|entries| entries.remove(key).is_some())
+    }
+}
+
+/// Errors that may occur while reading or writing a presence store, or while
+/// performing a push login.
+#[derive(Debug)]
+pub enum PresenceError {
+    IoError(io::Error),
+    ParserError(serde_json::Error),
+    EncoderError(serde_json::Error),
+    BadlyFormedStore,
+    QuestionError(QuestionError),
+}
+
+impl fmt::Display for PresenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &PresenceError::IoError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error accessing presence store: {}", err))
+            },
+            &PresenceError::ParserError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error reading presence store: {}", err))
+            },
+            &PresenceError::EncoderError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error writing presence store: {}", err))
+            },
+            &PresenceError::BadlyFormedStore => {
+                f.write_str("Presence store does not contain a JSON object of strings.")
+            },
+            &PresenceError::QuestionError(ref err) => {
+                fmt::Display::fmt(err, f)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::thread;
+
+    use super::*;
+    use protocol::{Presence};
+
+    fn exercise<S: PresenceStore>(store: &mut S) {
+        assert!(store.get("alice").unwrap().is_none());
+        store.put("alice", Presence::from_slice("p_1")).unwrap();
+        store.put("bob", Presence::from_slice("p_2")).unwrap();
+        store.put("alice", Presence::from_slice("p_3")).unwrap();
+        assert_eq!(store.get("alice").unwrap(), Some(Presence::from_slice("p_3")));
+        assert_eq!(store.get("bob").unwrap(), Some(Presence::from_slice("p_2")));
+        store.remove("alice").unwrap();
+        assert!(store.get("alice").unwrap().is_none());
+        assert_eq!(store.get("bob").unwrap(), Some(Presence::from_slice("p_2")));
+    }
+
+    #[test]
+    fn memory_store_remembers_presence_values() {
+        exercise(&mut MemoryPresenceStore::new());
+    }
+
+    #[test]
+    fn file_store_remembers_presence_values() {
+        let path = env::temp_dir().join("tozny_auth_presence_test.json");
+        let _ = fs::remove_file(&path);
+        exercise(&mut FilePresenceStore::new(&path));
+        let reopened = FilePresenceStore::new(&path);
+        assert_eq!(reopened.get("bob").unwrap(), Some(Presence::from_slice("p_2")));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_does_not_lose_concurrent_updates() {
+        let path = env::temp_dir().join("tozny_auth_presence_concurrent_test.json");
+        let _ = fs::remove_file(&path);
+        let writers: Vec<_> = (0..8).map(|i| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut store = FilePresenceStore::new(&path);
+                for j in 0..10 {
+                    store.put(&format!("user{}_{}", i, j), Presence::from_slice("p")).unwrap();
+                }
+            })
+        }).collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        let store = FilePresenceStore::new(&path);
+        assert_eq!(store.read_entries().unwrap().len(), 80);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_store_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = env::temp_dir().join("tozny_auth_presence_mode_test.json");
+        let _ = fs::remove_file(&path);
+        FilePresenceStore::new(&path).put("alice", Presence::from_slice("p_1")).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+}
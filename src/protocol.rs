@@ -1,10 +1,9 @@
 //! This module defines several types and provides some low-level functions.
 
-use chrono::datetime::{DateTime};
-use chrono::naive::datetime::{NaiveDateTime};
-use chrono::offset::utc::{UTC};
-use rustc_serialize::{Decodable, Decoder, Encodable, Encoder, json};
-use rustc_serialize::json::{Json};
+use chrono::{DateTime, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Value};
+use std::fmt;
 use std::ops::Deref;
 
 /// Abstraction for a type wrapper around a generic type.
@@ -19,7 +18,8 @@ pub trait Newtype<T: Deref> {
 }
 
 macro_rules! typed_string {
-    ($t:ident) => (
+    ($(#[$attr:meta])* $t:ident) => (
+        $(#[$attr])*
         #[derive(PartialEq, Eq, Clone, Debug)]
         pub struct $t(String);
 
@@ -40,126 +40,189 @@ macro_rules! typed_string {
             }
             fn as_slice(&self) -> &str {
                 let &$t(ref v) = self;
-                v.as_slice()
+                v.as_str()
             }
         }
 
-        impl Decodable for $t {
-            fn decode<D: Decoder>(d: &mut D) -> Result<$t, D::Error> {
-                d.read_str().map($t::new)
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<$t, D::Error> {
+                String::deserialize(d).map($t::new)
             }
         }
 
-        impl Encodable for $t {
-            fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
-                s.emit_str(self.as_slice())
+        impl Serialize for $t {
+            fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.serialize_str(self.as_slice())
             }
         }
 
-        impl json::ToJson for $t {
-            fn to_json(&self) -> Json {
-                Json::String(self.as_slice().to_string())
+        impl<'a> From<&'a $t> for Value {
+            fn from(v: &'a $t) -> Value {
+                Value::String(v.as_slice().to_string())
             }
         }
     )
 }
 
-/// Value that will be an input in signature made by the Tozny app
-typed_string!(Challenge);
-
-/// Type for realm key ids
-///
-/// A realm key id identifies a realm; and when paired with its corresponding
-/// secret acts as a credential to make realm-level API calls.
-typed_string!(KeyId);
-
-/// When making an API call, specifies which API method will be invoked.
-typed_string!(Method);
-
-/// Token that matches up with a specific mobile device.
-///
-/// A `Presence` value is used to send a push notification to a device, so that
-/// the user does not have to scan a QR code.  The response from the
-/// `login_challenge` method on `UserApi` includes a `Presence` value.  Store
-/// the value to keep track of which device was last used to log into an
-/// account.
-typed_string!(Presence);
-
-/// Shared secret that proves ownership of a realm key.
-typed_string!(Secret);
-
-/// Generated by Tozny; matches a successful authentication event with an
-/// application session.
-///
-/// The response from the `login_challenge` method on `UserApi` includes
-/// a `SessionId`.  Use that value when calling `check_session_status` to
-/// determine whether the user has authenticated.
-typed_string!(SessionId);
-
-/// Specifies which cryptographic algorithm was used to sign a message.
-typed_string!(SignatureType);
-
-/// Identifies a Tozny user.
-typed_string!(UserId);
-
-/// Wraps a `DateTime<UTC>` value; implements serialization into the format
+typed_string!(
+    /// Value that will be an input in signature made by the Tozny app
+    Challenge
+);
+
+typed_string!(
+    /// Identifies one of a user's enrolled devices.
+    DeviceId
+);
+
+typed_string!(
+    /// Type for realm key ids
+    ///
+    /// A realm key id identifies a realm; and when paired with its corresponding
+    /// secret acts as a credential to make realm-level API calls.
+    KeyId
+);
+
+typed_string!(
+    /// When making an API call, specifies which API method will be invoked.
+    Method
+);
+
+typed_string!(
+    /// Token that matches up with a specific mobile device.
+    ///
+    /// A `Presence` value is used to send a push notification to a device, so that
+    /// the user does not have to scan a QR code.  The response from the
+    /// `login_challenge` method on `UserApi` includes a `Presence` value.  Store
+    /// the value to keep track of which device was last used to log into an
+    /// account.
+    Presence
+);
+
+typed_string!(
+    /// Shared secret that proves ownership of a realm key.
+    Secret
+);
+
+typed_string!(
+    /// Generated by Tozny; matches a successful authentication event with an
+    /// application session.
+    ///
+    /// The response from the `login_challenge` method on `UserApi` includes
+    /// a `SessionId`.  Use that value when calling `check_session_status` to
+    /// determine whether the user has authenticated.
+    SessionId
+);
+
+typed_string!(
+    /// Specifies which cryptographic algorithm was used to sign a message.
+    SignatureType
+);
+
+typed_string!(
+    /// Identifies a Tozny user.
+    UserId
+);
+
+/// Wraps a `DateTime<Utc>` value; implements serialization into the format
 /// expected by the Tozny API.
 #[derive(PartialEq, Eq, Clone, Debug)]
-pub struct Timestamp(DateTime<UTC>);
+pub struct Timestamp(DateTime<Utc>);
 
 impl Timestamp {
-    pub fn new(t: DateTime<UTC>) -> Timestamp {
+    pub fn new(t: DateTime<Utc>) -> Timestamp {
         Timestamp(t)
     }
 
-    pub fn unwrap(self) -> DateTime<UTC> {
+    pub fn unwrap(self) -> DateTime<Utc> {
         let Timestamp(t) = self;
         t
     }
 
-    pub fn as_slice(&self) -> &DateTime<UTC> {
+    pub fn as_slice(&self) -> &DateTime<Utc> {
         let &Timestamp(ref t) = self;
         t
     }
 }
 
-fn int_to_timestamp(seconds: i64) -> Timestamp {
-    let naive = NaiveDateTime::from_num_seconds_from_unix_epoch(seconds, 0);
-    Timestamp(DateTime::from_utc(naive, UTC))
+fn int_to_timestamp(seconds: i64) -> Option<Timestamp> {
+    DateTime::from_timestamp(seconds, 0).map(Timestamp)
 }
 
-impl Decodable for Timestamp {
-    fn decode<D: Decoder>(d: &mut D) -> Result<Timestamp, D::Error> {
-        d.read_i64().map(int_to_timestamp)
+/// Accepts a number of seconds since January 1, 1970, either as a JSON number
+/// or as a string of digits - the Tozny API uses both.
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Timestamp, D::Error> {
+        d.deserialize_any(TimestampVisitor)
     }
 }
 
-/// Given a time, produces a string representing that time as the number of
-/// seconds since January 1, 1970.
-impl Encodable for Timestamp {
-    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+struct TimestampVisitor;
+
+impl<'de> de::Visitor<'de> for TimestampVisitor {
+    type Value = Timestamp;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number of seconds since January 1, 1970")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Timestamp, E> {
+        int_to_timestamp(v).ok_or_else(|| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Timestamp, E> {
+        if v > i64::MAX as u64 {
+            return Err(E::invalid_value(de::Unexpected::Unsigned(v), &self))
+        }
+        int_to_timestamp(v as i64)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Timestamp, E> {
+        v.parse::<i64>().ok()
+            .and_then(int_to_timestamp)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+    }
+}
+
+/// Given a time, produces the number of seconds since January 1, 1970.
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
         let &Timestamp(ref t) = self;
-        let seconds = t.num_seconds_from_unix_epoch();
-        s.emit_i64(seconds)
+        s.serialize_i64(t.timestamp())
     }
 }
 
-impl json::ToJson for Timestamp {
-    fn to_json(&self) -> Json {
-        Json::I64(self.as_slice().num_seconds_from_unix_epoch())
+impl<'a> From<&'a Timestamp> for Value {
+    fn from(t: &'a Timestamp) -> Value {
+        Value::from(t.as_slice().timestamp())
     }
 }
 
 /// Extracts error messages from a Tozny API response.
-pub fn error_response(json: &Json) -> Option<&Json> {
-    json.find("return")
-    .and_then(|val| { val.as_string() })
+pub fn error_response(json: &Value) -> Option<&Value> {
+    json.get("return")
+    .and_then(|val| { val.as_str() })
     .and_then(|ret| {
         if ret == "error" {
-            json.find("errors")
+            json.get("errors")
         }
         else {
             None
         }
     })
 }
+
+/// Serializes `Url` values as strings.  Use with `#[serde(with = "...")]`.
+pub mod url_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use url::Url;
+
+    pub fn serialize<S: Serializer>(url: &Url, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(url.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Url, D::Error> {
+        let s = String::deserialize(d)?;
+        Url::parse(&s).map_err(|e| de::Error::custom(format!("invalid URL: {}", e)))
+    }
+}
@@ -0,0 +1,251 @@
+//! Renders QR codes locally, so that a login can be presented without
+//! fetching the image at `LoginChallenge.qr_url` from Tozny.
+//!
+//! This module is only available when the `qr` cargo feature is enabled.
+
+use image::{ColorType, ImageEncoder, ImageError};
+use image::codecs::png::{PngEncoder};
+use qrcode::{Color, QrCode};
+use qrcode::types::{QrError as EncodeError};
+use std::fmt;
+use url::{Url};
+
+/// Number of light modules drawn around each side of a code, as required by
+/// the QR specification.
+const QUIET_ZONE: usize = 4;
+
+/// A square grid of QR modules.  `true` represents a dark module.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct QrMatrix {
+    width:   usize,
+    modules: Vec<bool>,
+}
+
+impl QrMatrix {
+    /// Encodes arbitrary bytes as a QR code.
+    pub fn encode(data: &[u8]) -> Result<QrMatrix, QrError> {
+        QrCode::new(data)
+            .map_err(QrError::EncodeError)
+        .map(|code| {
+            QrMatrix {
+                width:   code.width(),
+                modules: code.into_colors().into_iter().map(|c| c == Color::Dark).collect(),
+            }
+        })
+    }
+
+    /// Encodes a URL, such as `LoginChallenge.mobile_url`, as a QR code.
+    pub fn from_url(url: &Url) -> Result<QrMatrix, QrError> {
+        QrMatrix::encode(url.as_str().as_bytes())
+    }
+
+    /// Number of modules along each side of the code, not counting the quiet
+    /// zone.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns `true` if the module at the given position is dark.  Positions
+    /// outside of the code are light.
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.width && self.modules[y * self.width + x]
+    }
+
+    /// Renders the code as lines of Unicode half-block characters, two module
+    /// rows per line of text.  Dark modules are drawn as filled blocks, which
+    /// suits terminals with a light background.
+    pub fn to_unicode(&self) -> String {
+        self.render_blocks(true)
+    }
+
+    /// Like `to_unicode`, but draws light modules as filled blocks.  Use this
+    /// on terminals with a dark background.
+    pub fn to_unicode_inverted(&self) -> String {
+        self.render_blocks(false)
+    }
+
+    /// Renders the code as an SVG document.  Each module is drawn as a square
+    /// `scale` pixels wide; `scale` must be at least 1.
+    pub fn to_svg(&self, scale: usize) -> Result<String, QrError> {
+        if scale == 0 {
+            return Err(QrError::InvalidScale)
+        }
+        let size = (self.width + 2 * QUIET_ZONE) * scale;
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" \
+             width=\"{0}\" height=\"{0}\" viewBox=\"0 0 {0} {0}\">\
+             <rect width=\"{0}\" height=\"{0}\" fill=\"#fff\"/><path fill=\"#000\" d=\"",
+            size);
+        for y in 0..self.width {
+            for x in 0..self.width {
+                if self.is_dark(x, y) {
+                    svg.push_str(&format!("M{},{}h{}v{}h-{}z",
+                                          (x + QUIET_ZONE) * scale,
+                                          (y + QUIET_ZONE) * scale,
+                                          scale, scale, scale));
+                }
+            }
+        }
+        svg.push_str("\"/></svg>");
+        Ok(svg)
+    }
+
+    /// Renders the code as a greyscale PNG image.  Each module is drawn as
+    /// a square `scale` pixels wide; `scale` must be at least 1.
+    pub fn to_png(&self, scale: usize) -> Result<Vec<u8>, QrError> {
+        if scale == 0 {
+            return Err(QrError::InvalidScale)
+        }
+        let size = (self.width + 2 * QUIET_ZONE) * scale;
+        let mut pixels = Vec::with_capacity(size * size);
+        for py in 0..size {
+            for px in 0..size {
+                let dark = self.is_dark_with_quiet_zone(px / scale, py / scale);
+                pixels.push(if dark { 0u8 } else { 255u8 });
+            }
+        }
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(&pixels, size as u32, size as u32, ColorType::L8)
+            .map_err(QrError::ImageError)?;
+        Ok(png)
+    }
+
+    fn is_dark_with_quiet_zone(&self, x: usize, y: usize) -> bool {
+        x >= QUIET_ZONE && y >= QUIET_ZONE && self.is_dark(x - QUIET_ZONE, y - QUIET_ZONE)
+    }
+
+    fn render_blocks(&self, dark_is_filled: bool) -> String {
+        let size = self.width + 2 * QUIET_ZONE;
+        let mut out = String::new();
+        let mut y = 0;
+        while y < size {
+            for x in 0..size {
+                let top    = self.is_dark_with_quiet_zone(x, y) == dark_is_filled;
+                let bottom = y + 1 < size &&
+                    self.is_dark_with_quiet_zone(x, y + 1) == dark_is_filled;
+                out.push(match (top, bottom) {
+                    (true,  true)  => '\u{2588}',
+                    (true,  false) => '\u{2580}',
+                    (false, true)  => '\u{2584}',
+                    (false, false) => ' ',
+                });
+            }
+            out.push('\n');
+            y += 2;
+        }
+        out
+    }
+}
+
+/// Errors that may occur while encoding or rendering a QR code.
+#[derive(Debug)]
+pub enum QrError {
+    EncodeError(EncodeError),
+    ImageError(ImageError),
+    /// Images must be drawn at least one pixel per module.
+    InvalidScale,
+}
+
+impl fmt::Display for QrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &QrError::EncodeError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error encoding QR code: {}", err))
+            },
+            &QrError::ImageError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error writing QR code image: {}", err))
+            },
+            &QrError::InvalidScale => {
+                f.write_str("QR code scale must be at least 1.")
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard() -> QrMatrix {
+        QrMatrix {
+            width:   2,
+            modules: vec![true, false, false, true],
+        }
+    }
+
+    #[test]
+    fn it_treats_positions_outside_the_code_as_light() {
+        let qr = checkerboard();
+        assert!(qr.is_dark(0, 0));
+        assert!(!qr.is_dark(1, 0));
+        assert!(!qr.is_dark(2, 0));
+        assert!(!qr.is_dark(0, 5));
+    }
+
+    #[test]
+    fn it_renders_two_module_rows_per_line() {
+        let text = checkerboard().to_unicode();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2].chars().nth(4), Some('\u{2580}'));
+        assert_eq!(lines[2].chars().nth(5), Some('\u{2584}'));
+        assert_eq!(lines[0].trim(), "");
+    }
+
+    #[test]
+    fn it_inverts_unicode_output() {
+        let text = checkerboard().to_unicode_inverted();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0].chars().next(), Some('\u{2588}'));
+        assert_eq!(lines[2].chars().nth(4), Some('\u{2584}'));
+    }
+
+    #[test]
+    fn it_draws_one_svg_square_per_dark_module() {
+        let svg = checkerboard().to_svg(10).unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("width=\"100\""));
+        assert!(svg.contains("M40,40h10v10h-10z"));
+        assert!(svg.contains("M50,50h10v10h-10z"));
+        assert!(!svg.contains("M50,40"));
+    }
+
+    #[test]
+    fn it_encodes_login_urls() {
+        let url = Url::parse("tozauth://api.tozny.com/?s=sid_52fa6d2d0f9d3&c=1c0e4b5f").unwrap();
+        let qr = QrMatrix::from_url(&url).unwrap();
+        assert_eq!(qr.width(), 33);
+        // Finder patterns: dark corners, with a light separator inside.
+        for &(x, y) in [(0, 0), (qr.width() - 1, 0), (0, qr.width() - 1)].iter() {
+            assert!(qr.is_dark(x, y));
+        }
+        assert!(!qr.is_dark(1, 1));
+        assert!(qr.is_dark(3, 3));
+        assert_eq!(qr, QrMatrix::encode(url.as_str().as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn it_renders_png_images() {
+        let png = checkerboard().to_png(3).unwrap();
+        let img = image::load_from_memory_with_format(&png, image::ImageFormat::Png)
+                  .unwrap().to_luma8();
+        assert_eq!(img.dimensions(), (30, 30));
+        assert_eq!(img.get_pixel(0, 0).0, [255]);
+        assert_eq!(img.get_pixel(12, 12).0, [0]);
+        assert_eq!(img.get_pixel(14, 14).0, [0]);
+        assert_eq!(img.get_pixel(15, 12).0, [255]);
+        assert_eq!(img.get_pixel(15, 15).0, [0]);
+    }
+
+    #[test]
+    fn it_rejects_a_zero_scale() {
+        match checkerboard().to_png(0) {
+            Err(QrError::InvalidScale) => (),
+            r => panic!("expected an invalid scale, got {:?}", r.map(|_| ())),
+        }
+        assert!(checkerboard().to_svg(0).is_err());
+    }
+}
@@ -1,31 +1,32 @@
-/// Provides types and function for signing messages, and for verifying
-/// signatures.
-///
-/// This is a low-level interface.  It is recommended that application authors
-/// use higher-level methods on `Realm` or `UserApi`.
-
-use chrono::{Duration, UTC};
-use core::ops::Add;
-use crypto::hmac::{Hmac};
-use crypto::mac::{Mac, MacResult};
-use crypto::sha2::{Sha256};
-use hyper;
-use hyper::client::{Client};
-use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
-use rustc_serialize::{base64, json, Decodable, Encodable};
-use rustc_serialize::json::{Json, ToJson};
-use rand::{Rng, OsRng};
-use std::{fmt, old_io, str};
+//! Provides types and function for signing messages, and for verifying
+//! signatures.
+//!
+//! This is a low-level interface.  It is recommended that application authors
+//! use higher-level methods on `Realm` or `UserApi`.
+
+use base64;
+use base64::Engine;
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use chrono::{DateTime, Duration, Utc};
+use serde::de::{DeserializeOwned};
+use serde_json;
+use serde_json::{Map, Value};
+use std::{fmt, io, str};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicI64, Ordering};
+use ureq;
 
+use canonical;
+use crypto_backend::{CryptoBackend, DefaultBackend};
 use protocol;
-use protocol::{KeyId, Secret, Method, Newtype, Timestamp};
+use protocol::{KeyId, Secret, Method, Newtype, SignatureType, Timestamp};
+use trace::{CallSpan};
 use url;
 
 /// Type representing a signed message.  The data in a `Question` is signed
 /// using HMAC-SHA256.
-#[derive(Debug)]
-#[derive(RustcDecodable)]
-#[derive(RustcEncodable)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Question {
     pub signed_data: String,
     pub signature:   String
@@ -34,21 +35,33 @@ pub struct Question {
 impl Question {
     /// Constructs a message to send to the Tozny API.  The format value for
     /// `params` will vary depending on the choice of `method`.
-    pub fn new(key_id: &KeyId, secret: &Secret, method: &Method, params: &json::Object
-               ) -> Result<Question, json::EncoderError> {
-        let nonce      = get_nonce();
-        let expires_at = get_expires();
+    pub fn new(key_id: &KeyId, secret: &Secret, method: &Method, params: &Map<String, Value>
+               ) -> Result<Question, serde_json::Error> {
+        Question::with_clock(key_id, secret, method, params, &QuestionClock::new())
+    }
+
+    /// Like `new`, with the expiration time given by `clock`.
+    pub fn with_clock(key_id: &KeyId, secret: &Secret, method: &Method,
+                      params: &Map<String, Value>, clock: &QuestionClock
+                      ) -> Result<Question, serde_json::Error> {
+        Question::with_nonce(key_id, secret, method, params, &get_nonce(), &clock.expires_at())
+    }
 
+    /// Like `new`, with a given nonce and expiration time.  The signed data is
+    /// the canonical JSON encoding of the parameters; see the `canonical`
+    /// module.
+    fn with_nonce(key_id: &KeyId, secret: &Secret, method: &Method, params: &Map<String, Value>,
+                  nonce: &[u8; 32], expires_at: &Timestamp
+                  ) -> Result<Question, serde_json::Error> {
         let mut req = params.clone();
-        req.insert("nonce"       .to_string(), nonce     .to_json());
-        req.insert("expires_at"  .to_string(), expires_at.to_json());
-        req.insert("realm_key_id".to_string(), key_id    .to_json());
-        req.insert("method"      .to_string(), method    .to_json());
-
-        json::encode(&req).map(|js| {
-            let encoded = js.as_bytes().to_base64(URL_SAFE);
-            let mac = sign(secret, &encoded);
-            let signature = mac.code().to_base64(URL_SAFE);
+        req.insert("nonce"       .to_string(), Value::from(&nonce[..]));
+        req.insert("expires_at"  .to_string(), Value::from(expires_at));
+        req.insert("realm_key_id".to_string(), Value::from(key_id));
+        req.insert("method"      .to_string(), Value::from(method));
+
+        canonical::encode(&Value::Object(req)).map(|js| {
+            let encoded = encode_base64(js.as_bytes());
+            let signature = encode_base64(&sign(secret, &encoded));
             Question {
                 signed_data: encoded,
                 signature: signature
@@ -57,21 +70,109 @@ impl Question {
     }
 }
 
+/// Clock offsets smaller than this are not corrected; `Date` headers only
+/// have a resolution of one second.
+const MIN_CLOCK_CORRECTION_SECS: i64 = 2;
+
+/// Sets the `expires_at` time of outgoing questions: the current time plus an
+/// expiry window, which defaults to five minutes.
+///
+/// The current time is the local time corrected by an estimate of how far the
+/// local clock is from the Tozny API's.  The estimate is updated from the
+/// `Date` header of every API response, including error responses, so a host
+/// with a skewed clock still produces questions that the API accepts.
+#[derive(Debug)]
+pub struct QuestionClock {
+    window:    Duration,
+    offset_ms: AtomicI64,
+}
+
+impl Default for QuestionClock {
+    fn default() -> QuestionClock {
+        QuestionClock::new()
+    }
+}
+
+impl PartialEq for QuestionClock {
+    fn eq(&self, other: &QuestionClock) -> bool {
+        self.window == other.window
+    }
+}
+
+impl Eq for QuestionClock {}
+
+impl QuestionClock {
+    pub fn new() -> QuestionClock {
+        QuestionClock {
+            window:    Duration::minutes(5),
+            offset_ms: AtomicI64::new(0),
+        }
+    }
+
+    /// Sets how long questions remain valid after they are made.
+    pub fn window(mut self, window: Duration) -> QuestionClock {
+        self.window = window;
+        self
+    }
+
+    /// The estimated time on the Tozny API's clock minus the local time.
+    pub fn offset(&self) -> Duration {
+        Duration::milliseconds(self.offset_ms.load(Ordering::SeqCst))
+    }
+
+    /// The current time according to the Tozny API's clock, as far as it is
+    /// known.
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset()
+    }
+
+    /// The `expires_at` time for a question made now.
+    pub fn expires_at(&self) -> Timestamp {
+        Timestamp::new(self.now() + self.window)
+    }
+
+    /// Updates the clock offset from the `Date` header of an API response,
+    /// received at `received_at` local time.  Returns `true` if the offset
+    /// changed.  Headers that cannot be parsed are ignored.
+    pub fn observe_date(&self, date: &str, received_at: DateTime<Utc>) -> bool {
+        let server = match DateTime::parse_from_rfc2822(date) {
+            Ok(t)  => t.with_timezone(&Utc),
+            Err(_) => return false,
+        };
+        let measured = server - received_at;
+        let offset = if measured.num_seconds().abs() < MIN_CLOCK_CORRECTION_SECS {
+            Duration::zero()
+        }
+        else {
+            measured
+        };
+        let previous = self.offset_ms.swap(offset.num_milliseconds(), Ordering::SeqCst);
+        (previous - offset.num_milliseconds()).abs() >= MIN_CLOCK_CORRECTION_SECS * 1000
+    }
+}
+
 /// Enumerates the possible errors that may occur while signing a message,
 /// verifying the signature of a message, or transmitting a signed message to
 /// the Tozny API.
 #[derive(Debug)]
 pub enum QuestionError {
-    DecoderError(json::DecoderError),
-    EncoderError(json::EncoderError),
-    ParserError(json::ParserError),
-    Base64Error(base64::FromBase64Error),
-    HttpError(hyper::HttpError),
-    IoError(old_io::IoError),
+    DecoderError(serde_json::Error),
+    EncoderError(serde_json::Error),
+    ParserError(serde_json::Error),
+    Base64Error(base64::DecodeError),
+    HttpError(Box<ureq::Transport>),
+    IoError(io::Error),
     Utf8Error(str::Utf8Error),
     InvalidSignature,
+    UnsupportedSignatureType(SignatureType),
     BadlyFormedResponse,
-    ErrorResponse(Json),
+    ErrorResponse(Value),
+    /// The API server, or a proxy in front of it, responded with a 5xx
+    /// status.
+    ServerError(u16),
+    /// A validly signed login was for a different session than the one that
+    /// was being waited for.
+    SessionMismatch,
 }
 
 impl fmt::Display for QuestionError {
@@ -108,6 +209,10 @@ impl fmt::Display for QuestionError {
             &QuestionError::InvalidSignature => {
                 f.write_str("Invalid signature.")
             },
+            &QuestionError::UnsupportedSignatureType(ref t) => {
+                f.write_fmt(format_args!(
+                        "Unsupported signature type: {}", t.as_slice()))
+            },
             &QuestionError::BadlyFormedResponse => {
                 f.write_str("Message from API server is missing expected field(s).")
             },
@@ -115,146 +220,348 @@ impl fmt::Display for QuestionError {
                 f.write_fmt(format_args!(
                         "Received error response from API server: {}", errs))
             },
+            &QuestionError::ServerError(status) => {
+                f.write_fmt(format_args!(
+                        "API server responded with status {}", status))
+            },
+            &QuestionError::SessionMismatch => {
+                f.write_str("Login is for a different session.")
+            },
         }
     }
 }
 
-/// Unpacks a base64-encoded JSON value.
-pub fn unpack<T: Decodable>(payload: &str) -> Result<T, QuestionError> {
-    payload.from_base64()
+/// Checks the signature on a signed message, then unpacks it.  The signature
+/// is checked over `signed_data` exactly as received, so messages from
+/// senders that do not produce canonical JSON verify as well.
+pub fn verify<T: DeserializeOwned>(secret: &Secret, signed_data: &str, signature: &str
+                                   ) -> Result<T, QuestionError> {
+    if check_signature(secret, signature, signed_data) {
+        unpack(signed_data)
+    }
+    else {
+        Err(QuestionError::InvalidSignature)
+    }
+}
+
+/// Unpacks a base64-encoded JSON value.  The value is decoded from the
+/// received bytes, exactly as they were signed.
+pub fn unpack<T: DeserializeOwned>(payload: &str) -> Result<T, QuestionError> {
+    decode_base64(payload)
         .map_err(QuestionError::Base64Error)
     .and_then(|b64| {
         str::from_utf8(&b64)
             .map_err(QuestionError::Utf8Error)
         .and_then(|decoded| {
-            json::decode(&decoded)
+            serde_json::from_str(decoded)
                 .map_err(QuestionError::DecoderError)
         })
     })
 }
 
-/// Low-level function to dispatch a `Question` to the Tozny API.
+/// Low-level function to dispatch a `Question` to the Tozny API.  All calls
+/// go through one shared `ApiClient`, so they reuse connections and the
+/// learned clock offset.
 pub fn send_request(api_url: &url::Url,
                     key_id:  &KeyId,
                     secret:  &Secret,
                     method:  &Method,
-                    params:  &json::Object) -> Result<Json, QuestionError> {
-    Question::new(key_id, secret, method, params)
-        .map_err(QuestionError::EncoderError)
-    .and_then(|req| {
-        let mut client = Client::new();
-        let js = json::encode(&req).unwrap();
-        client.post(translate_url(api_url))
-            .body(js.as_slice())
-            .send()
-            .map_err(QuestionError::HttpError)
-    })
-    .and_then(|mut res| {
-        Json::from_reader(&mut res)
-            .map_err(QuestionError::ParserError)
-    })
-    .and_then(|json| {
-        match protocol::error_response(&json.clone()) {
-            Some(errs) => Err(QuestionError::ErrorResponse(errs.clone())),
-            None       => Ok(json),
+                    params:  &Map<String, Value>) -> Result<Value, QuestionError> {
+    static CLIENT: OnceLock<ApiClient> = OnceLock::new();
+    CLIENT.get_or_init(ApiClient::new).send(api_url, key_id, secret, method, params)
+}
+
+/// Sends questions to the Tozny API.  Calls made through one client share an
+/// HTTP agent, which reuses connections, and a `QuestionClock`.  A client may
+/// be used from several threads at once.
+pub struct ApiClient {
+    agent: ureq::Agent,
+    clock: QuestionClock,
+}
+
+impl Default for ApiClient {
+    fn default() -> ApiClient {
+        ApiClient::new()
+    }
+}
+
+impl PartialEq for ApiClient {
+    fn eq(&self, other: &ApiClient) -> bool {
+        self.clock == other.clock
+    }
+}
+
+impl Eq for ApiClient {}
+
+impl fmt::Debug for ApiClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_fmt(format_args!("ApiClient({:?})", self.clock))
+    }
+}
+
+impl ApiClient {
+    pub fn new() -> ApiClient {
+        ApiClient {
+            agent: ureq::agent(),
+            clock: QuestionClock::new(),
         }
-    })
+    }
+
+    /// Sets how long each question remains valid after it is signed.
+    pub fn question_expiry(mut self, window: Duration) -> ApiClient {
+        self.clock = self.clock.window(window);
+        self
+    }
+
+    pub fn clock(&self) -> &QuestionClock {
+        &self.clock
+    }
+
+    /// Signs and sends a question.  The clock learns the API's clock offset
+    /// from the response.  If the API rejects the question as expired and the
+    /// response shows that the offset estimate was wrong, the question is
+    /// sent once more with a corrected expiration time.  Other errors are
+    /// never retried, since calls such as `realm.user_device_revoke` are not
+    /// safe to repeat.
+    pub fn send(&self, api_url: &url::Url, key_id: &KeyId, secret: &Secret, method: &Method,
+                params: &Map<String, Value>) -> Result<Value, QuestionError> {
+        let mut span = CallSpan::start("realm", method.as_slice(), key_id, params);
+        let mut result = self.ask(api_url, key_id, secret, method, params, &mut span);
+        let retry = match result {
+            (Err(QuestionError::ErrorResponse(ref errs)), true) => is_expired(errs),
+            _                                                    => false,
+        };
+        if retry {
+            result = self.ask(api_url, key_id, secret, method, params, &mut span);
+        }
+        let result = result.0;
+        span.finish(&result);
+        result
+    }
+
+    /// Sends one question.  Also reports whether the response corrected the
+    /// clock offset.
+    fn ask(&self, api_url: &url::Url, key_id: &KeyId, secret: &Secret, method: &Method,
+           params: &Map<String, Value>, span: &mut CallSpan
+           ) -> (Result<Value, QuestionError>, bool) {
+        let mut corrected = false;
+        let result = Question::with_clock(key_id, secret, method, params, &self.clock)
+            .map_err(QuestionError::EncoderError)
+        .and_then(|req| {
+            let js = serde_json::to_string(&req).unwrap();
+            response(self.agent.post(api_url.as_str()).send_string(&js))
+        })
+        .and_then(|res| {
+            span.status(res.status());
+            if let Some(date) = res.header("Date") {
+                corrected = self.clock.observe_date(date, Utc::now());
+            }
+            serde_json::from_reader(res.into_reader())
+                .map_err(QuestionError::ParserError)
+        })
+        .and_then(|json: Value| {
+            match protocol::error_response(&json.clone()) {
+                Some(errs) => Err(QuestionError::ErrorResponse(errs.clone())),
+                None       => Ok(json),
+            }
+        });
+        (result, corrected)
+    }
+}
+
+/// Whether the `errors` of an error response say that the question had
+/// expired.  Tozny reports this in the `error_code` or `error_message` of an
+/// error.
+fn is_expired(errs: &Value) -> bool {
+    let mentions_expiry = |err: &Value| {
+        ["error_code", "error_message"].iter().any(|field| {
+            err.get(field)
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_ascii_lowercase().contains("expire"))
+                .unwrap_or(false)
+        })
+    };
+    match *errs {
+        Value::Array(ref errs) => errs.iter().any(mentions_expiry),
+        ref err                => mentions_expiry(err),
+    }
 }
 
 /// Produces a signature using HMAC-SHA256.
-pub fn sign(secret: &Secret, message: &str) -> MacResult {
-    let key  = secret.as_slice().as_bytes();
-    let data = message.as_bytes();
-    let mut hmac = Hmac::new(Sha256::new(), key);
-    hmac.input(data);
-    hmac.result()
+pub fn sign(secret: &Secret, message: &str) -> [u8; 32] {
+    DefaultBackend::hmac_sha256(secret.as_slice().as_bytes(), message.as_bytes())
 }
 
 /// Verifies a signature using a constant-time comparison.
 pub fn check_signature(secret: &Secret, signature: &str, message: &str) -> bool {
     let mac = sign(secret, message);
-    signature.from_base64().map(|sig| {
-        let expected = MacResult::new_from_owned(sig);
-        expected.eq(&mac)
-    })
-    .unwrap_or(false)
+    decode_base64(signature)
+        .map(|sig| DefaultBackend::constant_time_eq(&sig, &mac))
+        .unwrap_or(false)
+}
+
+/// A public key that Tozny may sign messages with.  Holding one allows
+/// checking `RSA` and `ED25519` signatures, but not producing them.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PublicKey {
+    /// RSA key, given as its big-endian modulus and public exponent.
+    /// Signatures use RSASSA-PKCS1-v1_5 with SHA-256.
+    Rsa { modulus: Vec<u8>, exponent: Vec<u8> },
+    /// 32-byte Ed25519 public key.
+    Ed25519(Vec<u8>),
+}
+
+impl PublicKey {
+    /// The `signature_type` of messages signed with this key.
+    pub fn signature_type(&self) -> SignatureType {
+        match *self {
+            PublicKey::Rsa { .. }  => SignatureType::from_slice("RSA"),
+            PublicKey::Ed25519(_) => SignatureType::from_slice("ED25519"),
+        }
+    }
+}
+
+/// Verifies a signature made with the private half of `key`.
+pub fn check_public_signature(key: &PublicKey, signature: &str, message: &str) -> bool {
+    let sig = match decode_base64(signature) {
+        Ok(sig) => sig,
+        Err(_)  => return false,
+    };
+    match *key {
+        PublicKey::Rsa { ref modulus, ref exponent } => {
+            DefaultBackend::verify_rsa_sha256(modulus, exponent, message.as_bytes(), &sig)
+        },
+        PublicKey::Ed25519(ref public) => {
+            DefaultBackend::verify_ed25519(public, message.as_bytes(), &sig)
+        },
+    }
+}
+
+/// Produces 32 random bytes, encoded as URL-safe base64.  Useful for
+/// generating unguessable identifiers such as authorization codes.
+pub fn random_token() -> String {
+    encode_base64(&get_nonce())
+}
+
+/// Base64 engine for `signed_data`, signatures and tokens: URL-safe alphabet,
+/// no padding when encoding.  Decoding accepts input with or without
+/// padding, and ignores unused trailing bits.
+const ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent)
+        .with_decode_allow_trailing_bits(true));
+
+/// Encodes bytes as URL-safe base64, without padding.
+pub fn encode_base64(bytes: &[u8]) -> String {
+    ENGINE.encode(bytes)
+}
+
+/// Decodes base64 in either the URL-safe or the standard alphabet, with or
+/// without padding, and ignoring line breaks - the same input that
+/// `rustc_serialize`, which earlier versions of this crate used, accepts.
+pub fn decode_base64(encoded: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let normalized: String = encoded.chars()
+        .filter(|&c| c != '\r' && c != '\n')
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c   => c,
+        })
+        .collect();
+    ENGINE.decode(normalized)
 }
 
 fn get_nonce() -> [u8; 32] {
-    let mut rng = OsRng::new().ok().expect("Error reading from /dev/urandom");
     let mut bytes = [0u8; 32];
-    rng.fill_bytes(&mut bytes);
+    DefaultBackend::fill_random(&mut bytes);
     bytes
 }
 
-fn get_expires() -> Timestamp {
-    Timestamp::new(UTC::now().add(Duration::minutes(5)))
-}
-
-/// Translates a `url::Url` value to a `hyper::Url` value.
-pub fn translate_url(url: &url::Url) -> hyper::Url {
-    let url::Url { scheme, scheme_data, query, fragment } = url.clone();
-    hyper::Url {
-        scheme:      scheme,
-        scheme_data: scheme_data,
-        query:       query,
-        fragment:    fragment,
+/// Gets the response to an HTTP request.  Responses with 4xx statuses are
+/// returned as well, since the Tozny API describes errors in the body.  A 5xx
+/// status means that the request did not get an answer from the API, and is
+/// returned as `QuestionError::ServerError`.
+pub fn response(result: Result<ureq::Response, ureq::Error>
+                ) -> Result<ureq::Response, QuestionError> {
+    match result {
+        Ok(res)                                   => Ok(res),
+        Err(ureq::Error::Status(s, _)) if s >= 500 => Err(QuestionError::ServerError(s)),
+        Err(ureq::Error::Status(_, res))          => Ok(res),
+        Err(ureq::Error::Transport(err))          => {
+            Err(QuestionError::HttpError(Box::new(err)))
+        },
     }
 }
 
-/// Gets a value of a `Decodable` type out of a `Json` value.
-pub fn from_json<T: Decodable>(js: &Json) -> Result<T, json::DecoderError> {
-    // TODO: What a hack!  Where is the `FromJson` trait?
-    let s = json::encode(js).unwrap();
-    json::decode(&s)
+/// Gets a value of a `Deserialize` type out of a JSON value, without
+/// re-encoding it.
+pub fn from_json<T: DeserializeOwned>(js: &Value) -> Result<T, serde_json::Error> {
+    T::deserialize(js)
 }
 
 #[cfg(test)]
 mod tests {
-    use collections::BTreeMap;
-    use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
-    use rustc_serialize::json::{Json, ToJson};
+    use chrono::{DateTime, Duration, Utc};
+    use serde_json;
+    use serde_json::{Map, Value};
     use std::str;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
 
     use super::*;
-    use protocol::{KeyId, Method, Secret};
+    use login::Login;
+    use protocol::{KeyId, Method, Secret, SessionId, Timestamp, UserId};
+    use testing::{serve_http, FakeResponse};
 
     #[test]
     fn it_encodes_base64() {
-        let encoded = DATA.as_bytes().to_base64(URL_SAFE);
+        let encoded = encode_base64(DATA.as_bytes());
         assert_eq!(encoded, ENCODED);
     }
 
     #[test]
     fn it_decodes_base64() {
-        let decoded = ENCODED.from_base64();
+        let decoded = decode_base64(ENCODED);
         assert!(decoded.is_ok());
         let bytes = decoded.unwrap();
         let s = str::from_utf8(&bytes).unwrap();
         assert_eq!(s, DATA);
     }
 
+    #[test]
+    fn it_decodes_standard_and_padded_base64() {
+        // 0xfb 0xff encodes to "-_8" in the URL-safe alphabet, "+/8=" in the
+        // standard one.
+        assert_eq!(decode_base64("-_8").unwrap(), vec![0xfb, 0xff]);
+        assert_eq!(decode_base64("+/8=").unwrap(), vec![0xfb, 0xff]);
+        assert_eq!(decode_base64("-_\n8").unwrap(), vec![0xfb, 0xff]);
+        assert_eq!(encode_base64(&[0xfb, 0xff]), "-_8");
+        assert!(decode_base64("-_8!").is_err());
+    }
+
     #[test]
     fn base64_to_text_to_base64_is_stable() {
-        let decoded = SIGNATURE.from_base64().unwrap();
-        let reencoded = decoded.to_base64(URL_SAFE);
+        let decoded = decode_base64(SIGNATURE).unwrap();
+        let reencoded = encode_base64(&decoded);
         assert_eq!(reencoded, SIGNATURE);
     }
 
     #[test]
     fn text_to_base64_to_text_is_stable() {
-        let encoded = DATA.as_bytes().to_base64(URL_SAFE);
-        let bytes = encoded.from_base64().unwrap();
+        let encoded = encode_base64(DATA.as_bytes());
+        let bytes = decode_base64(&encoded).unwrap();
         let redecoded = str::from_utf8(&bytes).unwrap();
         assert_eq!(redecoded, DATA);
     }
 
     #[test]
     fn it_signs_messages() {
-        let encoded = DATA.as_bytes().to_base64(URL_SAFE);
+        let encoded = encode_base64(DATA.as_bytes());
         let secret = Secret::from_slice(SECRET);
         let sig = sign(&secret, &encoded);
-        assert_eq!(sig.code().to_base64(URL_SAFE), SIGNATURE);
+        assert_eq!(encode_base64(&sig), SIGNATURE);
     }
 
     #[test]
@@ -278,18 +585,199 @@ mod tests {
         let key_id = KeyId::from_slice(REALM_KEY_ID);
         let secret = Secret::from_slice(SECRET);
         let method = Method::from_slice("realm.user_get");
-        let mut params = BTreeMap::new();
-        params.insert("user_id".to_string(), "sid_1234".to_json());
+        let mut params = Map::new();
+        params.insert("user_id".to_string(), Value::from("sid_1234"));
         let question = Question::new(&key_id, &secret, &method, &params).unwrap();
-        let bytes = question.signed_data.from_base64().unwrap();
-        let decoded = str::from_utf8(bytes.as_slice()).unwrap();
-        let req = Json::from_str(decoded).unwrap();
-        let expires_at = req.find("expires_at").unwrap();
+        let bytes = decode_base64(&question.signed_data).unwrap();
+        let decoded = str::from_utf8(&bytes).unwrap();
+        let req: Value = serde_json::from_str(decoded).unwrap();
+        let expires_at = req.get("expires_at").unwrap();
         assert!(expires_at.is_number());
         assert!(expires_at.as_i64().unwrap() > 1000000000);
         assert!(expires_at.as_i64().unwrap() < 9999999999);
     }
 
+    /// A golden file: the payload and signed question for a fixed nonce and
+    /// expiration time.  See `testdata/README.md` for how they were made.
+    struct Golden {
+        payload:  String,
+        question: Question,
+    }
+
+    fn golden(contents: &str) -> Golden {
+        let js: Value = serde_json::from_str(contents).unwrap();
+        Golden {
+            payload:  js["payload"].as_str().unwrap().to_string(),
+            question: from_json(&js["question"]).unwrap(),
+        }
+    }
+
+    fn timestamp(seconds: i64) -> Timestamp {
+        Timestamp::new(DateTime::from_timestamp(seconds, 0).unwrap())
+    }
+
+    #[test]
+    fn it_produces_byte_for_byte_identical_questions() {
+        let expected = golden(include_str!("../testdata/question_user_get.json"));
+        let mut params = Map::new();
+        params.insert("user_id".to_string(), Value::from("sid_1234"));
+        let mut nonce = [0u8; 32];
+        for (i, b) in nonce.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let question = Question::with_nonce(&KeyId::from_slice(REALM_KEY_ID),
+                                            &Secret::from_slice(SECRET),
+                                            &Method::from_slice("realm.user_get"),
+                                            &params, &nonce, &timestamp(1414541972)).unwrap();
+        let bytes = decode_base64(&question.signed_data).unwrap();
+        assert_eq!(str::from_utf8(&bytes).unwrap(), expected.payload);
+        assert_eq!(question.signed_data, expected.question.signed_data);
+        assert_eq!(question.signature, expected.question.signature);
+    }
+
+    #[test]
+    fn it_decodes_signed_logins_from_golden_file() {
+        let expected = golden(include_str!("../testdata/login.json"));
+        let q = expected.question;
+        assert!(check_signature(&Secret::from_slice(SECRET), &q.signature, &q.signed_data));
+        let login: Login = unpack(&q.signed_data).unwrap();
+        assert_eq!(login.user_id, UserId::from_slice("sid_52fa6d2d0f9d3"));
+        assert_eq!(login.session_id, SessionId::from_slice("a1b2c3d4e5f6"));
+        assert_eq!(login.user_display, "Alice");
+        assert_eq!(login.expires_at, timestamp(1414542272));
+    }
+
+    #[test]
+    fn it_signs_the_canonical_encoding() {
+        let expected = golden(include_str!("../testdata/question_canonical.json"));
+        let mut params = Map::new();
+        params.insert("user_id".to_string(), Value::from("sid_1234"));
+        params.insert("user_display".to_string(), Value::from("Zo\u{eb} \"Z\" /\t\u{2603}"));
+        let mut nonce = [0u8; 32];
+        for (i, b) in nonce.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let question = Question::with_nonce(&KeyId::from_slice(REALM_KEY_ID),
+                                            &Secret::from_slice(SECRET),
+                                            &Method::from_slice("realm.user_get"),
+                                            &params, &nonce, &timestamp(1414541972)).unwrap();
+        let bytes = decode_base64(&question.signed_data).unwrap();
+        assert_eq!(str::from_utf8(&bytes).unwrap(), expected.payload);
+        assert_eq!(question.signature, expected.question.signature);
+    }
+
+    #[test]
+    fn it_verifies_non_canonical_payloads_over_the_received_bytes() {
+        let secret = Secret::from_slice(SECRET);
+        let req: Map<String, Value> = verify(&secret, ENCODED, SIGNATURE).unwrap();
+        assert_eq!(req.get("user_id"), Some(&Value::from("sid_1234")));
+        let recoded = encode_base64(canonical::encode(&Value::Object(req)).unwrap().as_bytes());
+        match verify::<Value>(&secret, &recoded, SIGNATURE) {
+            Err(QuestionError::InvalidSignature) => (),
+            r => panic!("expected invalid signature, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_refuses_to_sign_fractional_numbers() {
+        let mut params = Map::new();
+        params.insert("amount".to_string(), Value::from(1.5));
+        assert!(Question::with_nonce(&KeyId::from_slice(REALM_KEY_ID),
+                                     &Secret::from_slice(SECRET),
+                                     &Method::from_slice("realm.user_get"),
+                                     &params, &[0u8; 32], &timestamp(1414541972)).is_err());
+    }
+
+    #[test]
+    fn it_uses_the_configured_expiry_window() {
+        let clock = QuestionClock::new().window(Duration::seconds(30));
+        let remaining = *clock.expires_at().as_slice() - Utc::now();
+        assert!(remaining > Duration::seconds(25) && remaining <= Duration::seconds(30));
+    }
+
+    #[test]
+    fn it_corrects_expiry_for_a_skewed_clock() {
+        let clock = QuestionClock::new();
+        let received_at = timestamp(1414541972);
+        // The API's clock is ten minutes ahead.
+        assert!(clock.observe_date("Wed, 29 Oct 2014 00:29:32 GMT", *received_at.as_slice()));
+        assert_eq!(clock.offset(), Duration::minutes(10));
+        let remaining = *clock.expires_at().as_slice() - Utc::now();
+        assert!(remaining > Duration::minutes(14) && remaining <= Duration::minutes(15));
+
+        // The same observation again is not a correction.
+        assert!(!clock.observe_date("Wed, 29 Oct 2014 00:29:32 GMT", *received_at.as_slice()));
+    }
+
+    #[test]
+    fn it_ignores_sub_second_skew_and_bad_dates() {
+        let clock = QuestionClock::new();
+        let received_at = *timestamp(1414541972).as_slice();
+        assert!(!clock.observe_date("Wed, 29 Oct 2014 00:19:33 GMT", received_at));
+        assert_eq!(clock.offset(), Duration::zero());
+        assert!(!clock.observe_date("yesterday", received_at));
+        assert_eq!(clock.offset(), Duration::zero());
+    }
+
+    /// An API whose clock is ten minutes ahead, and which answers every
+    /// question with `error`.  Also returns a count of the questions asked.
+    fn skewed_api(error: Value) -> (url::Url, Arc<AtomicUsize>) {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        let url = serve_http(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let date = (Utc::now() + Duration::minutes(10)).format("%a, %d %b %Y %H:%M:%S GMT");
+            FakeResponse::json(&json!({ "return": "error", "errors": [error] }))
+                .header("Date", &date.to_string())
+        });
+        (url, asked)
+    }
+
+    fn ask(url: &url::Url) -> Result<Value, QuestionError> {
+        ApiClient::new().send(url, &KeyId::from_slice(REALM_KEY_ID), &Secret::from_slice(SECRET),
+                              &Method::from_slice("realm.user_device_revoke"), &Map::new())
+    }
+
+    #[test]
+    fn it_retries_expired_questions_after_correcting_the_clock() {
+        let (url, asked) = skewed_api(json!({
+            "error_code":    "e_expired",
+            "error_message": "The request has expired.",
+        }));
+        assert!(ask(&url).is_err());
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn it_does_not_retry_other_errors() {
+        let (url, asked) = skewed_api(json!({
+            "error_code":    "e_device_not_found",
+            "error_message": "No such device.",
+        }));
+        match ask(&url) {
+            Err(QuestionError::ErrorResponse(_)) => (),
+            r => panic!("expected an error response, got {:?}", r),
+        }
+        assert_eq!(asked.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn it_decodes_the_reference_payload() {
+        let req: Map<String, Value> = unpack(ENCODED).unwrap();
+        assert_eq!(req.get("method"), Some(&Value::from("realm.user_get")));
+        let expires_at: Timestamp = from_json(&req["expires_at"]).unwrap();
+        assert_eq!(expires_at, timestamp(1414541972));
+    }
+
+    #[test]
+    fn timestamps_round_trip_as_numbers() {
+        let t = timestamp(1414541972);
+        assert_eq!(serde_json::to_string(&t).unwrap(), "1414541972");
+        assert_eq!(serde_json::from_str::<Timestamp>("1414541972").unwrap(), t);
+        assert_eq!(serde_json::from_str::<Timestamp>("\"1414541972\"").unwrap(), t);
+        assert!(serde_json::from_str::<Timestamp>("\"soon\"").is_err());
+    }
+
     const REALM_KEY_ID: &'static str = "sid_d915e7226947b";
     const SECRET: &'static str = "8f8c9b8df39f8c8be4a39378bece4ac01cba948f9b4ef7b90acad3f49d5358f2";
     #[allow(dead_code)]
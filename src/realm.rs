@@ -3,25 +3,50 @@
 //! API calls defined in this module require a realm key id and a corresponding
 //! realm secret.
 
-use collections::BTreeMap;
-use rustc_serialize::{Decodable, json};
-use rustc_serialize::json::{Json, ToJson};
+use chrono::{Duration, Utc};
+use serde::{Serialize};
+use serde::de::{DeserializeOwned};
+use serde_json;
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::{fmt, num, str};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use url::{Url};
 
+use audit::{AuditEvent, AuditEventKind, AuditOutcome, AuditSink, Auditor};
+use login;
 use login::Login;
+use metrics::MetricsRecorder;
+use presence::{PresenceError, PresenceStore};
+use protocol;
 use protocol::{
-    KeyId, Method, Secret, SessionId, Timestamp, UserId
+    DeviceId, KeyId, Method, Secret, SessionId, Timestamp, UserId
 };
-use user::User;
+use user::{Device, User};
 use question;
-use question::{QuestionError, from_json};
+use question::{ApiClient, PublicKey, QuestionError, from_json};
+use trace::classify_error;
 
 /// Type representing a particular Tozny realm.
-#[derive(PartialEq, Eq, Debug, RustcDecodable, RustcEncodable)]
+///
+/// Serializes as the key id, secret and API URL; public keys, the API client,
+/// audit sinks and metrics recorders are not serialized.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Realm {
     key_id:  KeyId,
     secret:  Secret,
-    api_url: Url
+    #[serde(with = "protocol::url_string")]
+    api_url: Url,
+    #[serde(skip)]
+    public_keys: Vec<PublicKey>,
+    #[serde(skip)]
+    client:  ApiClient,
+    #[serde(skip)]
+    audit:   Auditor,
+    #[serde(skip)]
+    metrics: MetricsRecorder,
 }
 
 impl Realm {
@@ -34,70 +59,150 @@ impl Realm {
             key_id: key_id,
             secret: secret,
             api_url: url,
+            public_keys: Vec::new(),
+            client: ApiClient::new(),
+            audit: Auditor::none(),
+            metrics: MetricsRecorder::none(),
         }
     }
 
+    /// Adds a Tozny public key that `verify_login` accepts for `RSA` and
+    /// `ED25519` signed logins.  Logins signed with `HMAC` are checked with the
+    /// realm secret as before.
+    pub fn with_public_key(mut self, key: PublicKey) -> Realm {
+        self.public_keys.push(key);
+        self
+    }
+
+    /// Sets how long each realm-level API call remains valid after it is
+    /// signed.  Defaults to five minutes; shorter windows limit how long an
+    /// intercepted call could be replayed.
+    pub fn question_expiry(mut self, window: Duration) -> Realm {
+        self.client = self.client.question_expiry(window);
+        self
+    }
+
+    /// The estimated offset of the Tozny API's clock from the local clock,
+    /// learned from API responses and applied to the expiration time of each
+    /// call.  See `question::QuestionClock`.
+    pub fn clock_offset(&self) -> Duration {
+        self.client.clock().offset()
+    }
+
+    /// Reports `verify_login` and `check_valid_login` results to an audit
+    /// sink.  See the `audit` module.
+    pub fn with_audit<S: AuditSink + 'static>(mut self, sink: Arc<S>) -> Realm {
+        self.audit = Auditor::new(sink);
+        self
+    }
+
+    /// Reports API calls and verified logins to a metrics recorder.  See the
+    /// `metrics` module.
+    pub fn with_metrics(mut self, metrics: MetricsRecorder) -> Realm {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn key_id(&self) -> &KeyId {
+        &self.key_id
+    }
+
+    /// The auditor used by this realm, for reporting related events.
+    pub fn audit(&self) -> &Auditor {
+        &self.audit
+    }
+
     /// Low-level method to make arbitrary realm-level API calls.
-    pub fn raw_call(&self, method: &Method, params: &json::Object
-                    ) -> Result<Json, QuestionError> {
-        let &Realm{ ref key_id, ref secret, ref api_url } = self;
-        question::send_request(api_url, key_id, secret, method, params)
+    pub fn raw_call(&self, method: &Method, params: &Map<String, Value>
+                    ) -> Result<Value, QuestionError> {
+        let &Realm{ ref key_id, ref secret, ref api_url, ref client, .. } = self;
+        let started = Utc::now();
+        let result = client.send(api_url, key_id, secret, method, params);
+        self.metrics.api_call(method, started, &result);
+        result
     }
 
     /// Given a response from the `check_session_status` call in UserApi,
     /// verifies that the response is signed by Tozny, and decodes a `Login`
-    /// value.
+    /// value.  The signature is checked according to the login's
+    /// `signature_type`; see `login::verify`.
     ///
     /// This function runs locally - it does not make any network requests.
     pub fn verify_login(&self, signed_data: &str, signature: &str
                         ) -> Result<Login, QuestionError> {
-        if question::check_signature(&self.secret, signature, signed_data) {
-            question::unpack::<Login>(signed_data)
-        }
-        else {
-            Err(QuestionError::InvalidSignature)
+        let result = login::verify(Some(&self.secret), &self.public_keys, signed_data, signature);
+        match result {
+            Ok(ref login)                         => self.metrics.login_verified(&login.session_id),
+            Err(QuestionError::InvalidSignature)  => self.metrics.invalid_signature(),
+            Err(_)                                => (),
         }
+        let kind = AuditEventKind::VerifyLogin;
+        self.audit.record(match result {
+            Ok(ref login) => {
+                AuditEvent::new(kind, AuditOutcome::Success, &self.key_id)
+                    .user_id(&login.user_id)
+                    .session_id(&login.session_id)
+                    .expires_at(&login.expires_at)
+            },
+            Err(ref err) => {
+                AuditEvent::new(kind, AuditOutcome::Failure, &self.key_id)
+                    .reason(classify_error(err))
+            },
+        });
+        result
     }
 
     /// Checks whether a given session is valid for a given user.  This is an
     /// alternative to using `check_session_status` and `verify_login`.
     pub fn check_valid_login(&self, uid: &UserId, sid: &SessionId, expires_at: &Timestamp
                              ) -> Result<bool, question::QuestionError> {
-        let mut q: json::Object = BTreeMap::new();
-        q.insert("user_id"   .to_string(), uid       .to_json());
-        q.insert("session_id".to_string(), sid       .to_json());
-        q.insert("expires_at".to_string(), expires_at.to_json());
-        self.raw_call(&Method::from_slice("realm.check_valid_login"), &q)
+        let mut q = Map::new();
+        q.insert("user_id"   .to_string(), Value::from(uid));
+        q.insert("session_id".to_string(), Value::from(sid));
+        q.insert("expires_at".to_string(), Value::from(expires_at));
+        let result = self.raw_call(&Method::from_slice("realm.check_valid_login"), &q)
         .and_then(|resp| {
             match resp {
-                Json::Object(obj) => Ok(obj),
-                _                 => Err(QuestionError::BadlyFormedResponse),
+                Value::Object(obj) => Ok(obj),
+                _                  => Err(QuestionError::BadlyFormedResponse),
             }
         })
         .and_then(|obj| {
             match obj.get("return") {
-                Some(&Json::String(ref s)) => Ok(s.as_slice() == "true"),
-                _                          => Err(QuestionError::BadlyFormedResponse),
+                Some(&Value::String(ref s)) => Ok(s == "true"),
+                _                           => Err(QuestionError::BadlyFormedResponse),
             }
-        })
+        });
+        let event = match result {
+            Ok(true)     => AuditEvent::new(AuditEventKind::CheckValidLogin,
+                                            AuditOutcome::Success, &self.key_id),
+            Ok(false)    => AuditEvent::new(AuditEventKind::CheckValidLogin,
+                                            AuditOutcome::Denied, &self.key_id),
+            Err(ref err) => AuditEvent::new(AuditEventKind::CheckValidLogin,
+                                            AuditOutcome::Failure, &self.key_id)
+                                .reason(classify_error(err)),
+        };
+        self.audit.record(event.user_id(uid).session_id(sid).expires_at(expires_at));
+        result
     }
 
     pub fn question_challenge<A, B>(&self, question: &A, user_id: &Option<UserId>
                                    ) -> Result<B, QuestionError>
-        where A: ToJson, B: Decodable {
-        let mut q: json::Object = BTreeMap::new();
-        q.insert("question".to_string(), question.to_json());
+        where A: Serialize, B: DeserializeOwned {
+        let mut q = Map::new();
+        q.insert("question".to_string(),
+                 serde_json::to_value(question).map_err(QuestionError::EncoderError)?);
 
         match user_id {
-            &Some(ref uid) => q.insert("user_id".to_string(), uid.to_json()),
+            &Some(ref uid) => q.insert("user_id".to_string(), Value::from(uid)),
             _              => None,
         };
 
         self.raw_call(&Method::from_slice("realm.question_challenge"), &q)
         .and_then(|resp| {
             match resp {  // TODO: extract response-unpacking boilerplate into helper
-                Json::Object(obj) => Ok(obj),
-                _                 => Err(QuestionError::BadlyFormedResponse),
+                Value::Object(obj) => Ok(obj),
+                _                  => Err(QuestionError::BadlyFormedResponse),
             }
         })
         .and_then(|obj| {
@@ -111,20 +216,491 @@ impl Realm {
     /// Given a Tozny user id, retrieves additional information associated with
     /// that user.
     pub fn user_get(&self, user_id: &UserId) -> Result<User, QuestionError> {
-        let mut q: json::Object = BTreeMap::new();
-        q.insert("user_id".to_string(), user_id.to_json());
+        let mut q = Map::new();
+        q.insert("user_id".to_string(), Value::from(user_id));
         self.raw_call(&Method::from_slice("realm.user_get"), &q)
-        .and_then(|resp| {
-            match resp {
-                Json::Object(obj) => Ok(obj),
-                _                 => Err(QuestionError::BadlyFormedResponse),
+        .and_then(|resp| results(&resp))
+    }
+
+    /// Lists the devices that a user has enrolled.
+    pub fn user_devices(&self, user_id: &UserId) -> Result<Vec<Device>, QuestionError> {
+        let mut q = Map::new();
+        q.insert("user_id".to_string(), Value::from(user_id));
+        self.raw_call(&Method::from_slice("realm.user_devices"), &q)
+        .and_then(|resp| results(&resp))
+    }
+
+    /// Changes the name shown for one of a user's devices.
+    pub fn rename_device(&self, user_id: &UserId, device_id: &DeviceId, name: &str
+                         ) -> Result<(), QuestionError> {
+        let mut q = Map::new();
+        q.insert("user_id"  .to_string(), Value::from(user_id));
+        q.insert("device_id".to_string(), Value::from(device_id));
+        q.insert("name"     .to_string(), Value::from(name));
+        self.raw_call(&Method::from_slice("realm.user_device_rename"), &q).map(|_| ())
+    }
+
+    /// Revokes one of a user's devices.  It can no longer be used to log in,
+    /// and its `Presence` stops working.  Copies of the presence kept in a
+    /// `PresenceStore`, such as those saved by `flow::login_with_push`,
+    /// are not removed: callers must delete them, or use
+    /// `revoke_device_and_forget`.  The result is reported to the audit sink.
+    pub fn revoke_device(&self, user_id: &UserId, device_id: &DeviceId
+                         ) -> Result<(), QuestionError> {
+        let mut q = Map::new();
+        q.insert("user_id"  .to_string(), Value::from(user_id));
+        q.insert("device_id".to_string(), Value::from(device_id));
+        let result = self.raw_call(&Method::from_slice("realm.user_device_revoke"), &q)
+                     .map(|_| ());
+        let kind = AuditEventKind::RevokeDevice;
+        let event = match result {
+            Ok(_)        => AuditEvent::new(kind, AuditOutcome::Success, &self.key_id),
+            Err(ref err) => AuditEvent::new(kind, AuditOutcome::Failure, &self.key_id)
+                                .reason(classify_error(err)),
+        };
+        self.audit.record(event.user_id(user_id).device_id(device_id));
+        result
+    }
+
+    /// Revokes `device` with `revoke_device`, then removes its `Presence`
+    /// from `store` if it is the value stored under `key`.  A value under
+    /// `key` that belongs to another of the user's devices is kept.
+    pub fn revoke_device_and_forget<S: PresenceStore>(&self,
+                                                      user_id: &UserId,
+                                                      device:  &Device,
+                                                      store:   &mut S,
+                                                      key:     &str,
+                                                      ) -> Result<(), PresenceError> {
+        self.revoke_device(user_id, &device.id).map_err(PresenceError::QuestionError)?;
+        if store.get(key)?.as_ref() == Some(&device.presence) {
+            store.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Retrieves the users with the given ids, making up to `concurrency`
+    /// calls at a time over this realm's API client.  Results are returned in
+    /// the order of `user_ids`; a failed lookup is reported in its own slot
+    /// and does not affect the others.
+    pub fn user_get_batch(&self, user_ids: &[UserId], concurrency: usize
+                          ) -> Vec<Result<User, QuestionError>> {
+        in_parallel(user_ids, concurrency, |user_id| self.user_get(user_id))
+    }
+
+    /// Lists the users of the realm that match `query`, fetching them a page
+    /// at a time from the `realm.users_get` API call as the iterator
+    /// advances.
+    pub fn users(&self, query: UserQuery) -> Users<'_> {
+        let method = Method::from_slice("realm.users_get");
+        Users::new(query, Box::new(move |params| self.raw_call(&method, params)))
+    }
+}
+
+/// Filters and paging for `Realm::users`.  By default all users are listed,
+/// 100 per API call.
+#[derive(Clone, Debug)]
+pub struct UserQuery {
+    status:        Option<String>,
+    meta:          Map<String, Value>,
+    created_after: Option<Timestamp>,
+    page_size:     u64,
+    cursor:        UserCursor,
+}
+
+impl Default for UserQuery {
+    fn default() -> UserQuery {
+        UserQuery::new()
+    }
+}
+
+impl UserQuery {
+    pub fn new() -> UserQuery {
+        UserQuery {
+            status:        None,
+            meta:          Map::new(),
+            created_after: None,
+            page_size:     100,
+            cursor:        UserCursor { offset: 0 },
+        }
+    }
+
+    /// Lists only users with the given status, such as `"active"`.
+    pub fn status(mut self, status: &str) -> UserQuery {
+        self.status = Some(status.to_string());
+        self
+    }
+
+    /// Lists only users whose `meta` record has the given value for the
+    /// given field.  May be given more than once; all fields must match.
+    pub fn meta_field(mut self, field: &str, value: &str) -> UserQuery {
+        self.meta.insert(field.to_string(), Value::from(value));
+        self
+    }
+
+    /// Lists only users created after the given time.
+    pub fn created_after(mut self, t: Timestamp) -> UserQuery {
+        self.created_after = Some(t);
+        self
+    }
+
+    /// Sets how many users are fetched per API call.
+    pub fn page_size(mut self, rows: u64) -> UserQuery {
+        self.page_size = rows.max(1);
+        self
+    }
+
+    /// Continues a listing from where an earlier one stopped; see
+    /// `Users::cursor`.
+    pub fn resume_from(mut self, cursor: UserCursor) -> UserQuery {
+        self.cursor = cursor;
+        self
+    }
+
+    fn params(&self, offset: u64) -> Map<String, Value> {
+        let mut q = Map::new();
+        q.insert("rows"  .to_string(), Value::from(self.page_size));
+        q.insert("offset".to_string(), Value::from(offset));
+        if let Some(ref status) = self.status {
+            q.insert("status".to_string(), Value::from(status.clone()));
+        }
+        if !self.meta.is_empty() {
+            q.insert("meta_fields".to_string(), Value::Object(self.meta.clone()));
+        }
+        if let Some(ref t) = self.created_after {
+            q.insert("created_after".to_string(), Value::from(t));
+        }
+        q
+    }
+}
+
+/// Position in a user listing.  Displays as, and parses from, an opaque
+/// string that can be stored to continue the listing later.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserCursor {
+    offset: u64,
+}
+
+impl fmt::Display for UserCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_fmt(format_args!("{}", self.offset))
+    }
+}
+
+impl str::FromStr for UserCursor {
+    type Err = num::ParseIntError;
+    fn from_str(s: &str) -> Result<UserCursor, num::ParseIntError> {
+        s.parse().map(|offset| UserCursor { offset: offset })
+    }
+}
+
+/// Makes one `realm.users_get` call.
+type FetchPage<'a> = Box<dyn FnMut(&Map<String, Value>) -> Result<Value, QuestionError> + 'a>;
+
+/// Iterator over the users of a realm, returned by `Realm::users`.  An error
+/// from the API ends the iteration; the cursor can be used to retry from
+/// that point.
+pub struct Users<'a> {
+    query:  UserQuery,
+    fetch:  FetchPage<'a>,
+    offset: u64,
+    page:   VecDeque<User>,
+    done:   bool,
+}
+
+impl<'a> Users<'a> {
+    fn new(query: UserQuery, fetch: FetchPage<'a>) -> Users<'a> {
+        let offset = query.cursor.offset;
+        Users {
+            query:  query,
+            fetch:  fetch,
+            offset: offset,
+            page:   VecDeque::new(),
+            done:   false,
+        }
+    }
+
+    /// The position of the next user the iterator will produce.  A listing
+    /// resumed from this cursor starts with that user.
+    pub fn cursor(&self) -> UserCursor {
+        UserCursor { offset: self.offset }
+    }
+
+    fn fetch_page(&mut self) -> Result<(), QuestionError> {
+        let resp = (self.fetch)(&self.query.params(self.offset))?;
+        let users: Vec<User> = results(&resp)?;
+        let end = self.offset + users.len() as u64;
+        let total = resp.get("total").and_then(|t| t.as_u64());
+        self.done = (users.len() as u64) < self.query.page_size ||
+                    total.map(|t| end >= t).unwrap_or(false);
+        self.page.extend(users);
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Users<'a> {
+    type Item = Result<User, QuestionError>;
+
+    fn next(&mut self) -> Option<Result<User, QuestionError>> {
+        if self.page.is_empty() && !self.done {
+            if let Err(err) = self.fetch_page() {
+                self.done = true;
+                return Some(Err(err))
             }
+        }
+        self.page.pop_front().map(|user| {
+            self.offset += 1;
+            Ok(user)
         })
-        .and_then(|obj| {
-            match obj.get("results") {
-                Some(js) => from_json(js).map_err(QuestionError::DecoderError),
-                None     => Err(QuestionError::BadlyFormedResponse),
+    }
+}
+
+/// Decodes the `results` field of an API response.
+fn results<T: DeserializeOwned>(resp: &Value) -> Result<T, QuestionError> {
+    match resp.get("results") {
+        Some(js) => from_json(js).map_err(QuestionError::DecoderError),
+        None     => Err(QuestionError::BadlyFormedResponse),
+    }
+}
+
+/// Applies `f` to each item on up to `concurrency` threads, and returns the
+/// results in the order of `items`.
+fn in_parallel<T, R, F>(items: &[T], concurrency: usize, f: F) -> Vec<R>
+    where T: Sync, R: Send, F: Fn(&T) -> R + Sync {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<Option<R>>>());
+    let workers = concurrency.max(1).min(items.len());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= items.len() {
+                        break
+                    }
+                    let result = f(&items[i]);
+                    results.lock().unwrap()[i] = Some(result);
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter()
+        .map(|r| r.expect("every item is processed"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use audit::MemoryAuditSink;
+    use presence::MemoryPresenceStore;
+    use protocol::{Newtype, Presence};
+    use testing::{question_params, serve_http, FakeResponse};
+
+    /// A realm of `count` users, named `sid_0`, `sid_1`, ..., served the way
+    /// the API pages them.  Records the parameters of each call.
+    fn fake_realm(count: u64, calls: &mut Vec<Map<String, Value>>, params: &Map<String, Value>
+                  ) -> Result<Value, QuestionError> {
+        calls.push(params.clone());
+        let offset = params["offset"].as_u64().unwrap();
+        let rows = params["rows"].as_u64().unwrap();
+        let results: Vec<Value> = (offset..count.min(offset + rows))
+            .map(|i| json!({ "id": format!("sid_{}", i), "logins": 0 }))
+            .collect();
+        Ok(json!({ "return": "ok", "total": count, "results": results }))
+    }
+
+    fn ids(users: Vec<Result<User, QuestionError>>) -> Vec<String> {
+        users.into_iter().map(|u| u.unwrap().id.as_slice().to_string()).collect()
+    }
+
+    #[test]
+    fn it_walks_every_page() {
+        let mut calls = Vec::new();
+        let users: Vec<_> = Users::new(UserQuery::new().page_size(3),
+                                       Box::new(|p| fake_realm(7, &mut calls, p))).collect();
+        assert_eq!(ids(users), (0..7).map(|i| format!("sid_{}", i)).collect::<Vec<_>>());
+        let offsets: Vec<u64> = calls.iter().map(|p| p["offset"].as_u64().unwrap()).collect();
+        assert_eq!(offsets, vec![0, 3, 6]);
+    }
+
+    #[test]
+    fn it_resumes_from_a_cursor() {
+        let mut calls = Vec::new();
+        let cursor = {
+            let mut users = Users::new(UserQuery::new().page_size(3),
+                                       Box::new(|p| fake_realm(7, &mut calls, p)));
+            users.next();
+            users.next();
+            users.cursor().to_string()
+        };
+        let query = UserQuery::new().page_size(3).resume_from(cursor.parse().unwrap());
+        let users: Vec<_> = Users::new(query, Box::new(|p| fake_realm(7, &mut calls, p))).collect();
+        assert_eq!(ids(users)[0], "sid_2");
+        assert!("page 2".parse::<UserCursor>().is_err());
+    }
+
+    #[test]
+    fn it_sends_filters() {
+        let mut calls = Vec::new();
+        let since = Timestamp::new(::chrono::DateTime::from_timestamp(1414541972, 0).unwrap());
+        let query = UserQuery::new().status("active").meta_field("team", "ops")
+            .created_after(since);
+        let users: Vec<_> = Users::new(query, Box::new(|p| fake_realm(0, &mut calls, p))).collect();
+        assert!(users.is_empty());
+        assert_eq!(calls[0]["status"], json!("active"));
+        assert_eq!(calls[0]["meta_fields"], json!({ "team": "ops" }));
+        assert_eq!(calls[0]["created_after"], json!(1414541972));
+    }
+
+    #[test]
+    fn it_stops_after_an_error() {
+        let mut users = Users::new(UserQuery::new(),
+                                   Box::new(|_| Err(QuestionError::BadlyFormedResponse)));
+        assert!(users.next().unwrap().is_err());
+        assert!(users.next().is_none());
+        assert_eq!(users.cursor(), UserCursor { offset: 0 });
+    }
+
+    #[test]
+    fn it_decodes_device_lists() {
+        let resp = json!({ "return": "ok", "results": [
+            { "id": "dev_1", "name": "Work phone", "presence": "pres_1",
+              "last_used": 1414541972 },
+            { "id": "dev_2", "name": "Tablet", "presence": "pres_2", "last_used": null },
+        ]});
+        let devices: Vec<Device> = results(&resp).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].id, DeviceId::from_slice("dev_1"));
+        assert_eq!(devices[0].last_used.as_ref().map(|t| t.as_slice().timestamp()),
+                   Some(1414541972));
+        assert!(devices[1].last_used.is_none());
+        match results::<Vec<Device>>(&json!({ "return": "ok" })) {
+            Err(QuestionError::BadlyFormedResponse) => (),
+            r => panic!("expected badly formed response, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_preserves_order_and_failures() {
+        let items: Vec<u32> = (0..50).collect();
+        let results = in_parallel(&items, 8, |&n| {
+            // Finish out of order.
+            thread::sleep(Duration::from_millis(((50 - n) % 7) as u64));
+            if n % 10 == 3 { Err(n) } else { Ok(n * 2) }
+        });
+        assert_eq!(results.len(), 50);
+        for (n, r) in results.into_iter().enumerate() {
+            let n = n as u32;
+            if n % 10 == 3 {
+                assert_eq!(r, Err(n));
             }
-        })
+            else {
+                assert_eq!(r, Ok(n * 2));
+            }
+        }
+    }
+
+    #[test]
+    fn it_bounds_concurrency() {
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let items: Vec<u32> = (0..20).collect();
+        in_parallel(&items, 3, |_| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn it_handles_empty_batches_and_zero_concurrency() {
+        let none: Vec<u32> = Vec::new();
+        assert!(in_parallel(&none, 4, |&n| n).is_empty());
+        assert_eq!(in_parallel(&[1, 2, 3], 0, |&n| n + 1), vec![2, 3, 4]);
+    }
+
+    /// A realm whose API answers every question with `{"return": "ok"}`, and
+    /// records the decoded parameters of each one.
+    fn recording_realm(calls: Arc<Mutex<Vec<Map<String, Value>>>>) -> Realm {
+        let url = serve_http(move |body| {
+            calls.lock().unwrap().push(question_params(body).unwrap());
+            FakeResponse::json(&json!({ "return": "ok" }))
+        });
+        Realm::new(KeyId::from_slice("sid_realm"), Secret::from_slice("realm secret"), url)
+    }
+
+    fn device(id: &str, presence: &str) -> Device {
+        Device {
+            id:        DeviceId::from_slice(id),
+            name:      "Work phone".to_string(),
+            presence:  Presence::from_slice(presence),
+            last_used: None,
+        }
+    }
+
+    #[test]
+    fn it_sends_device_renames_and_revocations() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::new(MemoryAuditSink::new());
+        let realm = recording_realm(calls.clone()).with_audit(sink.clone());
+        let alice = UserId::from_slice("sid_alice");
+        let dev = DeviceId::from_slice("dev_1");
+        realm.rename_device(&alice, &dev, "Old phone").unwrap();
+        realm.revoke_device(&alice, &dev).unwrap();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0]["method"], json!("realm.user_device_rename"));
+        assert_eq!(calls[0]["user_id"], json!("sid_alice"));
+        assert_eq!(calls[0]["device_id"], json!("dev_1"));
+        assert_eq!(calls[0]["name"], json!("Old phone"));
+        assert_eq!(calls[1]["method"], json!("realm.user_device_revoke"));
+        assert_eq!(calls[1]["user_id"], json!("sid_alice"));
+        assert_eq!(calls[1]["device_id"], json!("dev_1"));
+        assert_eq!(calls[1]["realm_key_id"], json!("sid_realm"));
+
+        let events = sink.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AuditEventKind::RevokeDevice);
+        assert_eq!(events[0].outcome, AuditOutcome::Success);
+        assert_eq!(events[0].user_id, Some(alice));
+        assert_eq!(events[0].device_id, Some(dev));
+    }
+
+    #[test]
+    fn failed_revocations_are_audited() {
+        let sink = Arc::new(MemoryAuditSink::new());
+        let realm = Realm::new(KeyId::from_slice("sid_realm"), Secret::from_slice("realm secret"),
+                               Url::parse("http://127.0.0.1:1/index.php").unwrap())
+            .with_audit(sink.clone());
+        assert!(realm.revoke_device(&UserId::from_slice("sid_alice"),
+                                    &DeviceId::from_slice("dev_1")).is_err());
+        let events = sink.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AuditEventKind::RevokeDevice);
+        assert_eq!(events[0].outcome, AuditOutcome::Failure);
+        assert_eq!(events[0].reason, Some("http".to_string()));
+    }
+
+    #[test]
+    fn it_forgets_the_presence_of_revoked_devices() {
+        let realm = recording_realm(Arc::new(Mutex::new(Vec::new())));
+        let alice = UserId::from_slice("sid_alice");
+        let mut store = MemoryPresenceStore::new();
+        store.put("alice", Presence::from_slice("pres_1")).unwrap();
+
+        realm.revoke_device_and_forget(&alice, &device("dev_2", "pres_2"), &mut store, "alice")
+            .unwrap();
+        assert_eq!(store.get("alice").unwrap(), Some(Presence::from_slice("pres_1")));
+
+        realm.revoke_device_and_forget(&alice, &device("dev_1", "pres_1"), &mut store, "alice")
+            .unwrap();
+        assert_eq!(store.get("alice").unwrap(), None);
     }
 }
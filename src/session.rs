@@ -0,0 +1,380 @@
+//! Application sessions backed by verified Tozny logins.
+//!
+//! After `Realm::verify_login` produces a `Login`, a `SessionManager` can mint
+//! a token to hand to the client (in a cookie, for example).  Tokens are
+//! signed with a server-side key using HMAC-SHA256, expire at the same time as
+//! the Tozny login, and may be revoked.  Sessions can also be re-checked
+//! against the Tozny API periodically with `Realm::check_valid_login`.
+
+use chrono::{Duration, Utc};
+use std::collections::{BTreeMap};
+use serde_json;
+use std::fmt;
+
+use login::Login;
+use protocol::{KeyId, Newtype, Secret, SessionId, Timestamp, UserId};
+use question;
+use question::{QuestionError};
+use realm::Realm;
+
+/// Prepended to the payload of a token before it is signed, so that values
+/// signed with the same key for another purpose, such as
+/// `offline::OfflineCache` records, are not accepted as tokens.
+const SIGNATURE_CONTEXT: &'static str = "tozny_auth.session.v1:";
+
+/// Contents of an application session token.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub user_id:      UserId,
+    pub session_id:   SessionId,
+    pub realm_key_id: KeyId,
+    pub issued_at:    Timestamp,
+    pub expires_at:   Timestamp,
+}
+
+impl Session {
+    /// Returns `true` if the underlying Tozny login has expired.
+    pub fn is_expired(&self) -> bool {
+        *self.expires_at.as_slice() <= Utc::now()
+    }
+}
+
+/// Mints and validates application session tokens.
+///
+/// Revocations and re-validation times are kept in memory, so each process
+/// that validates tokens should share one `SessionManager`.  Both are
+/// forgotten once the session they belong to has expired, since `validate`
+/// rejects expired tokens anyway.
+pub struct SessionManager {
+    key:             Secret,
+    revalidate_secs: Option<i64>,
+    /// Revoked session ids, with the expiry time of the session.
+    revoked:         BTreeMap<String, Timestamp>,
+    last_checked:    BTreeMap<String, Checked>,
+}
+
+/// When a session was last confirmed, and when it expires.
+struct Checked {
+    at:         Timestamp,
+    expires_at: Timestamp,
+}
+
+impl SessionManager {
+    /// `key` is a server-side secret used to sign tokens.  It should be
+    /// different from the realm secret.
+    pub fn new(key: Secret) -> SessionManager {
+        SessionManager {
+            key:             key,
+            revalidate_secs: None,
+            revoked:         BTreeMap::new(),
+            last_checked:    BTreeMap::new(),
+        }
+    }
+
+    /// Configures `check` to confirm sessions with the Tozny API when more
+    /// than `secs` seconds have passed since the last confirmation.
+    pub fn revalidate_every(mut self, secs: i64) -> SessionManager {
+        self.revalidate_secs = Some(secs);
+        self
+    }
+
+    /// Creates a session token for a verified login.  The login should come
+    /// from `Realm::verify_login`.
+    pub fn mint(&mut self, login: &Login) -> Result<String, SessionError> {
+        let now = Utc::now();
+        let session = Session {
+            user_id:      login.user_id.clone(),
+            session_id:   login.session_id.clone(),
+            realm_key_id: login.realm_key_id.clone(),
+            issued_at:    Timestamp::new(now),
+            expires_at:   login.expires_at.clone(),
+        };
+        if session.is_expired() {
+            return Err(SessionError::Expired)
+        }
+        let encoded = serde_json::to_string(&session).map_err(SessionError::EncoderError)?;
+        let payload = question::encode_base64(encoded.as_bytes());
+        let message = signed_message(&payload);
+        let signature = question::encode_base64(&question::sign(&self.key, &message));
+        self.prune();
+        self.last_checked.insert(session.session_id.as_slice().to_string(), Checked {
+            at:         Timestamp::new(now),
+            expires_at: session.expires_at.clone(),
+        });
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    /// Checks the signature and expiration time of a token, and whether it
+    /// has been revoked.  This runs locally - it does not make any network
+    /// requests.
+    pub fn validate(&self, token: &str) -> Result<Session, SessionError> {
+        let mut parts = token.splitn(2, '.');
+        let (payload, signature) = match (parts.next(), parts.next()) {
+            (Some(p), Some(s)) => (p, s),
+            _                  => return Err(SessionError::MalformedToken),
+        };
+        if !question::check_signature(&self.key, signature, &signed_message(payload)) {
+            return Err(SessionError::InvalidSignature)
+        }
+        let session: Session = question::unpack(payload)
+                               .map_err(|_| SessionError::MalformedToken)?;
+        if self.revoked.contains_key(session.session_id.as_slice()) {
+            Err(SessionError::Revoked)
+        }
+        else if session.is_expired() {
+            Err(SessionError::Expired)
+        }
+        else {
+            Ok(session)
+        }
+    }
+
+    /// Validates a token, and if re-validation is configured and due, also
+    /// confirms the session with `Realm::check_valid_login`.  Sessions that
+    /// Tozny reports as invalid are revoked.
+    pub fn check(&mut self, realm: &Realm, token: &str) -> Result<Session, SessionError> {
+        let session = self.validate(token)?;
+        if self.revalidation_due(&session) {
+            let valid = self.revalidate(realm, &session)?;
+            if !valid {
+                return Err(SessionError::Revoked)
+            }
+        }
+        Ok(session)
+    }
+
+    /// Confirms with the Tozny API that a session is still valid, regardless
+    /// of when it was last confirmed.  Revokes the session if it is not.
+    pub fn revalidate(&mut self, realm: &Realm, session: &Session) -> Result<bool, SessionError> {
+        let valid = realm.check_valid_login(&session.user_id,
+                                            &session.session_id,
+                                            &session.expires_at)
+                    .map_err(SessionError::QuestionError)?;
+        let sid = session.session_id.as_slice().to_string();
+        if valid {
+            self.last_checked.insert(sid, Checked {
+                at:         Timestamp::new(Utc::now()),
+                expires_at: session.expires_at.clone(),
+            });
+        }
+        else {
+            self.revoke(session);
+        }
+        Ok(valid)
+    }
+
+    /// Revokes every token minted for the same Tozny session as `session`.
+    /// The revocation is kept until the session expires.
+    pub fn revoke(&mut self, session: &Session) {
+        self.prune();
+        let sid = session.session_id.as_slice().to_string();
+        self.last_checked.remove(&sid);
+        let keep_until = match self.revoked.remove(&sid) {
+            Some(ref t) if t.as_slice() > session.expires_at.as_slice() => t.clone(),
+            _ => session.expires_at.clone(),
+        };
+        self.revoked.insert(sid, keep_until);
+    }
+
+    /// Forgets revocations and re-validation times of expired sessions.
+    fn prune(&mut self) {
+        let now = Utc::now();
+        self.revoked.retain(|_, expires_at| *expires_at.as_slice() > now);
+        self.last_checked.retain(|_, checked| *checked.expires_at.as_slice() > now);
+    }
+
+    fn revalidation_due(&self, session: &Session) -> bool {
+        match self.revalidate_secs {
+            None       => false,
+            Some(secs) => {
+                let last = self.last_checked.get(session.session_id.as_slice())
+                    .map(|c| &c.at)
+                    .unwrap_or(&session.issued_at);
+                *last.as_slice() + Duration::seconds(secs) <= Utc::now()
+            },
+        }
+    }
+}
+
+/// The message that is signed for a token payload.
+fn signed_message(payload: &str) -> String {
+    format!("{}{}", SIGNATURE_CONTEXT, payload)
+}
+
+/// Reasons that a session token may be rejected.
+#[derive(Debug)]
+pub enum SessionError {
+    EncoderError(serde_json::Error),
+    QuestionError(QuestionError),
+    MalformedToken,
+    InvalidSignature,
+    Expired,
+    Revoked,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &SessionError::EncoderError(ref err) => {
+                f.write_fmt(format_args!(
+                        "Error encoding session token: {}", err))
+            },
+            &SessionError::QuestionError(ref err) => {
+                fmt::Display::fmt(err, f)
+            },
+            &SessionError::MalformedToken => {
+                f.write_str("Session token is malformed.")
+            },
+            &SessionError::InvalidSignature => {
+                f.write_str("Session token has an invalid signature.")
+            },
+            &SessionError::Expired => {
+                f.write_str("Session has expired.")
+            },
+            &SessionError::Revoked => {
+                f.write_str("Session has been revoked.")
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use std::sync::Arc;
+
+    use super::*;
+    use flow::LoginFlow;
+    use login::Login;
+    use protocol::{KeyId, Secret, SessionId, SignatureType, Timestamp, UserId};
+    use testing::FakeTozny;
+
+    fn login(expires_in: Duration) -> Login {
+        Login {
+            user_id:        UserId::from_slice("sid_1234"),
+            session_id:     SessionId::from_slice("session_5678"),
+            realm_key_id:   KeyId::from_slice("sid_d915e7226947b"),
+            user_display:   "Alice".to_string(),
+            expires_at:     Timestamp::new(Utc::now() + expires_in),
+            signature_type: SignatureType::from_slice("HMAC"),
+        }
+    }
+
+    fn manager() -> SessionManager {
+        SessionManager::new(Secret::from_slice("server side key"))
+    }
+
+    #[test]
+    fn it_validates_minted_tokens() {
+        let mut sessions = manager();
+        let token = sessions.mint(&login(Duration::minutes(5))).unwrap();
+        let session = sessions.validate(&token).unwrap();
+        assert_eq!(session.user_id, UserId::from_slice("sid_1234"));
+        assert_eq!(session.session_id, SessionId::from_slice("session_5678"));
+    }
+
+    #[test]
+    fn it_rejects_tampered_tokens() {
+        let mut sessions = manager();
+        let token = sessions.mint(&login(Duration::minutes(5))).unwrap();
+        let mut tampered = token.clone();
+        tampered.insert(0, 'e');
+        assert!(sessions.validate(&tampered).is_err());
+        assert!(manager().revalidate_every(60).validate("garbage").is_err());
+    }
+
+    #[test]
+    fn it_rejects_tokens_signed_with_another_key() {
+        let token = manager().mint(&login(Duration::minutes(5))).unwrap();
+        let other = SessionManager::new(Secret::from_slice("another key"));
+        match other.validate(&token) {
+            Err(SessionError::InvalidSignature) => (),
+            r => panic!("expected invalid signature, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_rejects_revoked_sessions() {
+        let mut sessions = manager();
+        let token = sessions.mint(&login(Duration::minutes(5))).unwrap();
+        let session = sessions.validate(&token).unwrap();
+        sessions.revoke(&session);
+        match sessions.validate(&token) {
+            Err(SessionError::Revoked) => (),
+            r => panic!("expected revoked session, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_forgets_revocations_of_expired_sessions() {
+        let mut sessions = manager();
+        let token = sessions.mint(&login(Duration::minutes(5))).unwrap();
+        let mut session = sessions.validate(&token).unwrap();
+        session.expires_at = Timestamp::new(Utc::now() - Duration::seconds(1));
+        sessions.revoke(&session);
+        assert_eq!(sessions.revoked.len(), 1);
+
+        let mut other = login(Duration::minutes(5));
+        other.session_id = SessionId::from_slice("session_9abc");
+        sessions.mint(&other).unwrap();
+        assert!(sessions.revoked.is_empty());
+        assert_eq!(sessions.last_checked.len(), 1);
+    }
+
+    /// Mints a token for a login confirmed on `tozny`, and returns the token
+    /// and the realm that serves `tozny`'s API.
+    fn confirmed_login(tozny: &Arc<FakeTozny>, sessions: &mut SessionManager) -> (String, Realm) {
+        let challenge = tozny.login_challenge().unwrap();
+        tozny.confirm(&challenge.session_id, &UserId::from_slice("sid_alice"), "Alice");
+        let question = tozny.check_session_status(&challenge.session_id).unwrap().unwrap();
+        let realm = FakeTozny::serve(tozny.clone());
+        let login = realm.verify_login(&question.signed_data, &question.signature).unwrap();
+        (sessions.mint(&login).unwrap(), realm)
+    }
+
+    #[test]
+    fn it_revalidates_sessions_with_the_api() {
+        let tozny = Arc::new(FakeTozny::new());
+        let mut sessions = manager();
+        let (token, realm) = confirmed_login(&tozny, &mut sessions);
+        let session = sessions.validate(&token).unwrap();
+        assert!(sessions.revalidate(&realm, &session).unwrap());
+        assert_eq!(sessions.validate(&token).unwrap(), session);
+    }
+
+    #[test]
+    fn it_revokes_sessions_that_the_api_reports_invalid() {
+        let tozny = Arc::new(FakeTozny::new());
+        let mut sessions = manager().revalidate_every(0);
+        let (token, realm) = confirmed_login(&tozny, &mut sessions);
+        assert!(sessions.check(&realm, &token).is_ok());
+
+        let session = sessions.validate(&token).unwrap();
+        tozny.revoke(&session.session_id);
+        match sessions.check(&realm, &token) {
+            Err(SessionError::Revoked) => (),
+            r => panic!("expected revoked session, got {:?}", r),
+        }
+        match sessions.validate(&token) {
+            Err(SessionError::Revoked) => (),
+            r => panic!("expected revoked session, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_only_revalidates_when_due() {
+        let tozny = Arc::new(FakeTozny::new());
+        let mut sessions = manager().revalidate_every(3600);
+        let (token, realm) = confirmed_login(&tozny, &mut sessions);
+        tozny.revoke(&sessions.validate(&token).unwrap().session_id);
+        assert!(sessions.check(&realm, &token).is_ok());
+    }
+
+    #[test]
+    fn it_refuses_to_mint_expired_logins() {
+        match manager().mint(&login(Duration::minutes(-5))) {
+            Err(SessionError::Expired) => (),
+            r => panic!("expected expired session, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Browser-facing endpoint that reports when a login session completes.
+//!
+//! A login page needs to learn when the user has confirmed a session in the
+//! Tozny app.  Rather than have every browser poll Tozny (or have the server
+//! proxy `check_session_status` once per browser request), a `StatusHub` runs
+//! a single background poller per `SessionId` and lets any number of browser
+//! requests wait on it.  Waiters are answered either with a long-poll JSON
+//! response or with a server-sent event.
+//!
+//! On completion the response carries the signed `Question` from
+//! `check_session_status`.  The browser should submit it back to the
+//! application server, which must check it with `Realm::verify_login` - the
+//! hub does not verify anything.
+
+use chrono::{Duration, Utc};
+use std::collections::BTreeMap;
+use serde_json::{Value};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{thread, time};
+
+use flow::LoginFlow;
+use protocol::{Newtype, SessionId, Timestamp};
+use question::{Question};
+use web::{WebHandler, WebRequest, WebResponse};
+
+/// How long a finished result is kept for waiters that arrive late.
+const RESULT_GRACE_SECS: i64 = 60;
+
+/// A poller with no waiters stops after this long.
+const IDLE_SECS: i64 = 30;
+
+/// Pollers stop after this long regardless; Tozny challenges have expired by
+/// then.
+const MAX_POLL_SECS: i64 = 600;
+
+/// Outcome of waiting for a session.
+#[derive(Clone, Debug)]
+pub enum WaitResult {
+    /// The user confirmed the session.  Verify the question with
+    /// `Realm::verify_login`.
+    Complete(Question),
+    /// The session is still pending; wait again.
+    Pending,
+    /// Polling failed, or the hub is too busy to watch another session.
+    Failed(String),
+}
+
+struct Watch {
+    outcome:       Option<WaitResult>,
+    waiters:       usize,
+    started:       Timestamp,
+    last_interest: Timestamp,
+}
+
+struct Shared<F> {
+    flow:    F,
+    watches: Mutex<BTreeMap<String, Watch>>,
+    changed: Condvar,
+    pollers: AtomicUsize,
+}
+
+/// Multiplexes waiters onto one poller per session.  Cloning a hub produces
+/// another handle to the same pollers.
+pub struct StatusHub<F> {
+    shared:           Arc<Shared<F>>,
+    poll_interval_ms: u32,
+    wait_timeout_ms:  u32,
+    max_watches:      usize,
+}
+
+impl<F> Clone for StatusHub<F> {
+    fn clone(&self) -> StatusHub<F> {
+        StatusHub {
+            shared:           self.shared.clone(),
+            poll_interval_ms: self.poll_interval_ms,
+            wait_timeout_ms:  self.wait_timeout_ms,
+            max_watches:      self.max_watches,
+        }
+    }
+}
+
+impl<F: LoginFlow + Send + Sync + 'static> StatusHub<F> {
+    pub fn new(flow: F) -> StatusHub<F> {
+        StatusHub {
+            shared: Arc::new(Shared {
+                flow:    flow,
+                watches: Mutex::new(BTreeMap::new()),
+                changed: Condvar::new(),
+                pollers: AtomicUsize::new(0),
+            }),
+            poll_interval_ms: 2000,
+            wait_timeout_ms:  25000,
+            max_watches:      1000,
+        }
+    }
+
+    /// How often each poller calls `check_session_status`.  Defaults to two
+    /// seconds.
+    pub fn poll_interval_ms(mut self, ms: u32) -> StatusHub<F> {
+        self.poll_interval_ms = ms;
+        self
+    }
+
+    /// How long a waiter is held before being told that the session is still
+    /// pending.  Defaults to 25 seconds, which is below common proxy
+    /// timeouts.
+    pub fn wait_timeout_ms(mut self, ms: u32) -> StatusHub<F> {
+        self.wait_timeout_ms = ms;
+        self
+    }
+
+    /// Maximum number of sessions watched at once.  Defaults to 1000.
+    pub fn max_watches(mut self, n: usize) -> StatusHub<F> {
+        self.max_watches = n;
+        self
+    }
+
+    /// Number of background pollers that are running.
+    pub fn pollers(&self) -> usize {
+        self.shared.pollers.load(Ordering::SeqCst)
+    }
+
+    /// Blocks until the session completes, polling fails, or the wait times
+    /// out.  Starts a poller for the session if there is none.
+    pub fn wait(&self, session_id: &SessionId) -> WaitResult {
+        let key = session_id.as_slice().to_string();
+        let mut watches = self.shared.watches.lock().unwrap();
+        prune(&mut watches);
+        let now = Timestamp::new(Utc::now());
+        if !watches.contains_key(&key) {
+            if watches.len() >= self.max_watches {
+                return WaitResult::Failed("Too many sessions are being watched.".to_string())
+            }
+            watches.insert(key.clone(), Watch {
+                outcome:       None,
+                waiters:       0,
+                started:       now.clone(),
+                last_interest: now.clone(),
+            });
+            self.spawn_poller(session_id.clone());
+        }
+        {
+            let watch = watches.get_mut(&key).unwrap();
+            if let Some(ref outcome) = watch.outcome {
+                return outcome.clone()
+            }
+            watch.waiters += 1;
+            watch.last_interest = now;
+        }
+
+        let deadline = Utc::now() + Duration::milliseconds(self.wait_timeout_ms as i64);
+        let mut result = WaitResult::Pending;
+        loop {
+            if let Some(outcome) = watches.get(&key).and_then(|w| w.outcome.clone()) {
+                result = outcome;
+                break
+            }
+            let remaining = (deadline - Utc::now()).num_milliseconds();
+            if remaining <= 0 {
+                break
+            }
+            let (guard, _) = self.shared.changed
+                .wait_timeout(watches, time::Duration::from_millis(remaining as u64)).unwrap();
+            watches = guard;
+        }
+        if let Some(watch) = watches.get_mut(&key) {
+            watch.waiters -= 1;
+            watch.last_interest = Timestamp::new(Utc::now());
+        }
+        result
+    }
+
+    /// Answers a long-poll request.
+    pub fn long_poll(&self, session_id: &SessionId) -> WebResponse {
+        let result = self.wait(session_id);
+        WebResponse::json(200, result_json(&result))
+    }
+
+    /// Answers a request from an `EventSource` with a single event, named
+    /// `complete`, `pending`, or `failed`.  After a `pending` event the
+    /// browser reconnects automatically.
+    pub fn server_sent_event(&self, session_id: &SessionId) -> WebResponse {
+        let result = self.wait(session_id);
+        let event = match result {
+            WaitResult::Complete(_) => "complete",
+            WaitResult::Pending     => "pending",
+            WaitResult::Failed(_)   => "failed",
+        };
+        WebResponse {
+            status:       200,
+            content_type: "text/event-stream",
+            location:     None,
+            set_cookie:   None,
+            body:         format!("retry: 1000\nevent: {}\ndata: {}\n\n",
+                                  event, result_json(&result)),
+        }
+    }
+
+    fn spawn_poller(&self, session_id: SessionId) {
+        let shared = self.shared.clone();
+        let interval = self.poll_interval_ms;
+        shared.pollers.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            let key = session_id.as_slice().to_string();
+            let mut first = true;
+            loop {
+                if !first {
+                    thread::sleep(time::Duration::from_millis(interval as u64));
+                }
+                first = false;
+                let status = shared.flow.check_session_status(&session_id);
+                let mut watches = shared.watches.lock().unwrap();
+                let now = Utc::now();
+                let outcome = match status {
+                    Ok(Some(q)) => Some(WaitResult::Complete(q)),
+                    Err(err)    => Some(WaitResult::Failed(format!("{}", err))),
+                    Ok(None)    => None,
+                };
+                let stop = match watches.get_mut(&key) {
+                    None => true,
+                    Some(watch) => match outcome {
+                        Some(o) => {
+                            watch.outcome = Some(o);
+                            watch.last_interest = Timestamp::new(now);
+                            true
+                        },
+                        None => {
+                            let idle = watch.waiters == 0 &&
+                                *watch.last_interest.as_slice() + Duration::seconds(IDLE_SECS) <= now;
+                            let expired =
+                                *watch.started.as_slice() + Duration::seconds(MAX_POLL_SECS) <= now;
+                            if expired {
+                                watch.outcome = Some(WaitResult::Failed(
+                                    "Session expired.".to_string()));
+                            }
+                            idle || expired
+                        },
+                    },
+                };
+                if stop {
+                    if watches.get(&key).map(|w| w.outcome.is_none()).unwrap_or(false) {
+                        watches.remove(&key);
+                    }
+                    shared.pollers.fetch_sub(1, Ordering::SeqCst);
+                    shared.changed.notify_all();
+                    break
+                }
+            }
+        });
+    }
+}
+
+impl<F: LoginFlow + Send + Sync + 'static> WebHandler for StatusHub<F> {
+    /// Expects a `session_id` query parameter.  Responds with a server-sent
+    /// event if the request accepts `text/event-stream`, and with a long-poll
+    /// JSON response otherwise.
+    fn handle(&self, req: WebRequest) -> WebResponse {
+        let wants_events = req.header("Accept")
+            .map(|a| a.contains("text/event-stream"))
+            .unwrap_or(false);
+        match req.query.get("session_id") {
+            Some(sid) if wants_events => self.server_sent_event(&SessionId::from_slice(sid)),
+            Some(sid)                 => self.long_poll(&SessionId::from_slice(sid)),
+            None => {
+                WebResponse::json(400, json!({ "error": "session_id is required" }))
+            },
+        }
+    }
+}
+
+/// Drops finished results once their grace period is over.
+fn prune(watches: &mut BTreeMap<String, Watch>) {
+    let cutoff = Utc::now() - Duration::seconds(RESULT_GRACE_SECS);
+    let finished: Vec<String> = watches.iter()
+        .filter(|&(_, w)| w.outcome.is_some() && w.waiters == 0 &&
+                *w.last_interest.as_slice() < cutoff)
+        .map(|(k, _)| k.clone())
+        .collect();
+    for k in finished {
+        watches.remove(&k);
+    }
+}
+
+fn result_json(result: &WaitResult) -> Value {
+    match *result {
+        WaitResult::Complete(ref q) => json!({
+            "status":      "complete",
+            "signed_data": q.signed_data,
+            "signature":   q.signature,
+        }),
+        WaitResult::Pending => json!({ "status": "pending" }),
+        WaitResult::Failed(ref msg) => json!({ "status": "failed", "error": msg }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use flow::LoginFlow;
+    use protocol::{SessionId, UserId};
+    use testing::FakeTozny;
+
+    #[test]
+    fn it_shares_one_poller_among_waiters() {
+        let hub = StatusHub::new(FakeTozny::new()).poll_interval_ms(20).wait_timeout_ms(5000);
+        let sid = hub.shared.flow.login_challenge().unwrap().session_id;
+        let waiters: Vec<_> = (0..5).map(|_| {
+            let hub = hub.clone();
+            let sid = sid.clone();
+            thread::spawn(move || hub.wait(&sid))
+        }).collect();
+        // Wait until every waiter is registered, however long the threads
+        // take to start.
+        let mut watches = hub.shared.watches.lock().unwrap();
+        while watches.get(sid.as_slice()).map(|w| w.waiters) != Some(5) {
+            watches = hub.shared.changed
+                .wait_timeout(watches, time::Duration::from_millis(10)).unwrap().0;
+        }
+        drop(watches);
+        assert_eq!(hub.pollers(), 1);
+
+        hub.shared.flow.confirm(&sid, &UserId::from_slice("sid_alice"), "Alice");
+        for w in waiters {
+            match w.join().unwrap() {
+                WaitResult::Complete(q) => {
+                    assert!(hub.shared.flow.verify_login(&q.signed_data, &q.signature).is_ok())
+                },
+                r => panic!("expected completion, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn it_reports_pending_sessions_after_the_timeout() {
+        let hub = StatusHub::new(FakeTozny::new()).poll_interval_ms(10).wait_timeout_ms(50);
+        let sid = hub.shared.flow.login_challenge().unwrap().session_id;
+        match hub.wait(&sid) {
+            WaitResult::Pending => (),
+            r => panic!("expected pending, got {:?}", r),
+        }
+        let resp = hub.long_poll(&sid);
+        assert!(resp.body.contains("\"pending\""));
+    }
+
+    #[test]
+    fn it_reports_failures_as_events() {
+        let hub = StatusHub::new(FakeTozny::new()).poll_interval_ms(10).wait_timeout_ms(1000);
+        let resp = hub.server_sent_event(&SessionId::from_slice("no_such_session"));
+        assert_eq!(resp.content_type, "text/event-stream");
+        assert!(resp.body.contains("event: failed\n"));
+    }
+}
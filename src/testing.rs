@@ -0,0 +1,313 @@
+//! An in-process stand-in for the Tozny API, for exercising login
+//! integrations without network access.
+//!
+//! `FakeTozny` implements `LoginFlow`.  Challenges it issues stay pending until
+//! the test calls `confirm`, after which `check_session_status` returns a
+//! `Question` signed with the fake realm secret - exactly what the real API
+//! would return.
+//!
+//! `FakeTozny::serve` answers realm API calls over HTTP, for code that takes
+//! a `Realm`.  `serve_http` runs a minimal local HTTP server, for testing code that makes
+//! API calls over the network, such as error handling and retries.
+//!
+//! This module is only available when the `testing` cargo feature is
+//! enabled.  Enable it for dev-dependencies only.
+
+use chrono::{Duration, Utc};
+use std::collections::BTreeMap;
+use serde_json;
+use serde_json::{Map, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use url::{Url};
+
+use flow::LoginFlow;
+use login::Login;
+use protocol::{Challenge, KeyId, Newtype, Presence, Secret, SessionId, SignatureType,
+               Timestamp, UserId};
+use question;
+use question::{Question, QuestionError};
+use realm::Realm;
+use user::LoginChallenge;
+
+/// Fake Tozny realm.  See the module documentation.
+pub struct FakeTozny {
+    key_id:   KeyId,
+    secret:   Secret,
+    sessions: Mutex<BTreeMap<String, FakeSession>>,
+    pushes:   Mutex<Vec<(SessionId, Presence)>>,
+    checks:   AtomicUsize,
+}
+
+struct FakeSession {
+    presence: Presence,
+    user:     Option<(UserId, String)>,
+    revoked:  bool,
+}
+
+impl Default for FakeTozny {
+    fn default() -> FakeTozny {
+        FakeTozny::new()
+    }
+}
+
+impl FakeTozny {
+    pub fn new() -> FakeTozny {
+        FakeTozny {
+            key_id:   KeyId::from_slice("sid_fake_realm"),
+            secret:   Secret::from_slice("fake realm secret"),
+            sessions: Mutex::new(BTreeMap::new()),
+            pushes:   Mutex::new(Vec::new()),
+            checks:   AtomicUsize::new(0),
+        }
+    }
+
+    pub fn key_id(&self) -> &KeyId {
+        &self.key_id
+    }
+
+    pub fn secret(&self) -> &Secret {
+        &self.secret
+    }
+
+    /// Returns a `Realm` that holds the fake realm's credentials, so that
+    /// logins produced by the fake can be checked with `Realm::verify_login`.
+    pub fn realm(&self) -> Realm {
+        Realm::new(self.key_id.clone(), self.secret.clone(),
+                   Url::parse("http://tozny.invalid/api/").unwrap())
+    }
+
+    /// Serves the fake's realm API over HTTP, and returns a `Realm` that calls
+    /// it.  `realm.check_valid_login` is answered from the fake's sessions:
+    /// a login is valid if the session was confirmed by that user and has
+    /// not been revoked with `revoke`.  Questions must be signed with the
+    /// fake realm secret.
+    pub fn serve(tozny: Arc<FakeTozny>) -> Realm {
+        let (key_id, secret) = (tozny.key_id.clone(), tozny.secret.clone());
+        let url = serve_http(move |body| tozny.answer(body));
+        Realm::new(key_id, secret, url)
+    }
+
+    fn answer(&self, body: &[u8]) -> FakeResponse {
+        let error = |msg: &str| FakeResponse::json(&json!({
+            "return": "error",
+            "errors": [{ "error_message": msg }],
+        }));
+        let q: Question = match serde_json::from_slice(body) {
+            Ok(q)  => q,
+            Err(_) => return error("Malformed request."),
+        };
+        if !question::check_signature(&self.secret, &q.signature, &q.signed_data) {
+            return error("Invalid signature.")
+        }
+        let params: Map<String, Value> = match question::unpack(&q.signed_data) {
+            Ok(params) => params,
+            Err(_)     => return error("Malformed request."),
+        };
+        let param = |name: &str| params.get(name).and_then(|v| v.as_str()).unwrap_or("");
+        match param("method") {
+            "realm.check_valid_login" => {
+                let sessions = self.sessions.lock().unwrap();
+                let valid = match sessions.get(param("session_id")) {
+                    Some(&FakeSession { user: Some((ref user_id, _)), revoked, .. }) => {
+                        !revoked && user_id.as_slice() == param("user_id")
+                    },
+                    _ => false,
+                };
+                FakeResponse::json(&json!({ "return": if valid { "true" } else { "false" } }))
+            },
+            _ => error("Unknown method."),
+        }
+    }
+
+    /// Simulates a confirmed session being logged out or revoked, so that
+    /// `realm.check_valid_login` reports it invalid.  Returns `false` if the
+    /// session is unknown.
+    pub fn revoke(&self, session_id: &SessionId) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(session_id.as_slice()) {
+            Some(session) => {
+                session.revoked = true;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Simulates the user with the given id confirming a pending session in
+    /// the Tozny app.  Returns `false` if the session is unknown.
+    pub fn confirm(&self, session_id: &SessionId, user_id: &UserId, user_display: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(session_id.as_slice()) {
+            Some(session) => {
+                session.user = Some((user_id.clone(), user_display.to_string()));
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Lists the sessions that have been issued but not yet confirmed.
+    pub fn pending_sessions(&self) -> Vec<SessionId> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.iter()
+            .filter(|&(_, s)| s.user.is_none())
+            .map(|(sid, _)| SessionId::from_slice(sid))
+            .collect()
+    }
+
+    /// Returns the presence value that was issued with a challenge.
+    pub fn presence_for(&self, session_id: &SessionId) -> Option<Presence> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(session_id.as_slice()).map(|s| s.presence.clone())
+    }
+
+    /// Lists the push notifications that have been sent so far.
+    pub fn pushes(&self) -> Vec<(SessionId, Presence)> {
+        self.pushes.lock().unwrap().clone()
+    }
+
+    /// Counts the calls to `check_session_status` made so far.
+    pub fn status_checks(&self) -> usize {
+        self.checks.load(Ordering::SeqCst)
+    }
+}
+
+impl LoginFlow for FakeTozny {
+    fn login_challenge(&self) -> Result<LoginChallenge, QuestionError> {
+        let session_id = SessionId::new(question::random_token());
+        let presence = Presence::new(question::random_token());
+        let mobile_url = format!("tozauth://api.tozny.invalid/{}", session_id.as_slice());
+        let qr_url = format!("http://tozny.invalid/qr/{}", session_id.as_slice());
+        self.sessions.lock().unwrap().insert(session_id.as_slice().to_string(), FakeSession {
+            presence: presence.clone(),
+            user:     None,
+            revoked:  false,
+        });
+        Ok(LoginChallenge {
+            challenge:    Challenge::new(question::random_token()),
+            realm_key_id: self.key_id.clone(),
+            session_id:   session_id,
+            qr_url:       Url::parse(&qr_url).unwrap(),
+            mobile_url:   Url::parse(&mobile_url).unwrap(),
+            created_at:   Timestamp::new(Utc::now()),
+            presence:     presence,
+        })
+    }
+
+    fn push(&self, session_id: &SessionId, presence: &Presence) -> Result<(), QuestionError> {
+        self.pushes.lock().unwrap().push((session_id.clone(), presence.clone()));
+        Ok(())
+    }
+
+    fn check_session_status(&self, session_id: &SessionId
+                            ) -> Result<Option<Question>, QuestionError> {
+        self.checks.fetch_add(1, Ordering::SeqCst);
+        let sessions = self.sessions.lock().unwrap();
+        let user = match sessions.get(session_id.as_slice()) {
+            Some(&FakeSession { user: Some(ref user), .. }) => user.clone(),
+            Some(_) => return Ok(None),
+            None    => return Err(QuestionError::BadlyFormedResponse),
+        };
+        let (user_id, user_display) = user;
+        let login = Login {
+            user_id:        user_id,
+            session_id:     session_id.clone(),
+            realm_key_id:   self.key_id.clone(),
+            user_display:   user_display,
+            expires_at:     Timestamp::new(Utc::now() + Duration::minutes(5)),
+            signature_type: SignatureType::from_slice("HMAC"),
+        };
+        let encoded = serde_json::to_string(&login).map_err(QuestionError::EncoderError)?;
+        let signed_data = question::encode_base64(encoded.as_bytes());
+        let signature = question::encode_base64(&question::sign(&self.secret, &signed_data));
+        Ok(Some(Question {
+            signed_data: signed_data,
+            signature:   signature,
+        }))
+    }
+
+    fn verify_login(&self, signed_data: &str, signature: &str) -> Result<Login, QuestionError> {
+        self.realm().verify_login(signed_data, signature)
+    }
+}
+
+/// A response for `serve_http` to send.
+#[derive(Clone, Debug)]
+pub struct FakeResponse {
+    /// Status code and reason, such as `"502 Bad Gateway"`.
+    pub status:  &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body:    String,
+}
+
+impl FakeResponse {
+    pub fn new(status: &'static str, content_type: &str, body: &str) -> FakeResponse {
+        FakeResponse {
+            status:  status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body:    body.to_string(),
+        }
+    }
+
+    /// A `200 OK` response with a JSON body.
+    pub fn json(body: &Value) -> FakeResponse {
+        FakeResponse::new("200 OK", "application/json", &body.to_string())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> FakeResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Serves HTTP on a local port until the process exits, answering each
+/// request with the response that `respond` returns for its body.  Returns
+/// the URL of an API endpoint on the server, for `Realm::new` or
+/// `UserApi::new`.
+pub fn serve_http<F>(respond: F) -> Url
+    where F: Fn(&[u8]) -> FakeResponse + Send + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/index.php", listener.local_addr().unwrap()))
+              .unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => BufReader::new(stream),
+                Err(_)     => continue,
+            };
+            let mut length = 0;
+            let mut line = String::new();
+            while stream.read_line(&mut line).map(|n| n > 2).unwrap_or(false) {
+                if let Some(n) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = n.trim().parse().unwrap_or(0);
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            if stream.read_exact(&mut body).is_err() {
+                continue
+            }
+            let resp = respond(&body);
+            let mut head = format!("HTTP/1.1 {}\r\n", resp.status);
+            for &(ref name, ref value) in resp.headers.iter() {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n",
+                                   resp.body.len()));
+            let _ = stream.get_mut().write_all(head.as_bytes())
+                .and_then(|_| stream.get_mut().write_all(resp.body.as_bytes()));
+        }
+    });
+    url
+}
+
+/// Decodes the parameters of a question, as sent to the API in a request
+/// body.  The signature is not checked.
+pub fn question_params(body: &[u8]) -> Option<Map<String, Value>> {
+    serde_json::from_slice::<Question>(body).ok()
+        .and_then(|q| question::unpack(&q.signed_data).ok())
+}
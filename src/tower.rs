@@ -0,0 +1,535 @@
+//! Serves `web::WebHandler`s from servers built on `tower` and `http` 1, such
+//! as hyper 1 and axum.
+//!
+//! `HandlerService` turns a handler into a `tower_service::Service`.  The
+//! request body is read into memory, up to a limit, and the handler is run on
+//! tokio's blocking thread pool: handlers call the Tozny API, and
+//! `status::StatusHub` waits for logins to complete.  Responses are marked
+//! `Cache-Control: no-store`, because they carry sessions, tokens and login
+//! state.
+//!
+//! With hyper 1 and `hyper-util`, for example:
+//!
+//! ```text
+//! let service = HandlerService::new(provider);
+//! http1::Builder::new()
+//!     .serve_connection(TokioIo::new(stream), TowerToHyperService::new(service))
+//! ```
+//!
+//! `LoginGuardLayer` puts a `web::LoginGuard` in front of any `tower`
+//! service instead, for applications whose routes are not `WebHandler`s:
+//!
+//! ```text
+//! let guard = LoginGuard::new(realm, session_key, ());
+//! let app = Router::new().route("/", get(home)).layer(LoginGuardLayer::new(guard));
+//! ```
+//!
+//! The guard answers its own routes and requests without a session, and
+//! passes other requests on unchanged except for the `X-Tozny-User-Id` and
+//! `X-Tozny-Session-Id` headers.
+//!
+//! This module is only available when the `tower` cargo feature is enabled.
+
+use bytes::Bytes;
+use http::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE};
+use http::request::Parts;
+use http::{HeaderMap, HeaderValue, Request, Response};
+use http_body::Body;
+use http_body_util::combinators::Collect;
+use http_body_util::{BodyExt, Either, Full, LengthLimitError, Limited};
+use std::convert::Infallible;
+use std::error::Error;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::task::{self, JoinHandle};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use flow::LoginFlow;
+use protocol::Newtype;
+use session::Session;
+use web::{LoginGuard, Route, WebHandler, WebRequest, WebResponse};
+use web::{SESSION_ID_HEADER, USER_ID_HEADER};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Request bodies larger than this are refused, unless configured.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// A `tower_service::Service` that runs a `WebHandler`.  See the module
+/// documentation.
+pub struct HandlerService<H> {
+    handler:        Arc<H>,
+    max_body_bytes: usize,
+}
+
+impl<H> Clone for HandlerService<H> {
+    fn clone(&self) -> HandlerService<H> {
+        HandlerService {
+            handler:        self.handler.clone(),
+            max_body_bytes: self.max_body_bytes,
+        }
+    }
+}
+
+impl<H: WebHandler + 'static> HandlerService<H> {
+    pub fn new(handler: H) -> HandlerService<H> {
+        HandlerService::from_arc(Arc::new(handler))
+    }
+
+    /// Shares a handler that is also used elsewhere.
+    pub fn from_arc(handler: Arc<H>) -> HandlerService<H> {
+        HandlerService {
+            handler:        handler,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        }
+    }
+
+    /// Requests with larger bodies are refused with status 413.  Defaults to
+    /// `DEFAULT_MAX_BODY_BYTES`.
+    pub fn max_body_bytes(mut self, n: usize) -> HandlerService<H> {
+        self.max_body_bytes = n;
+        self
+    }
+}
+
+impl<H, B> Service<Request<B>> for HandlerService<H>
+    where H:        WebHandler + 'static,
+          B:        Body + Send + 'static,
+          B::Data:  Send,
+          B::Error: Into<BoxError> {
+    type Response = Response<Full<Bytes>>;
+    type Error    = Infallible;
+    type Future   = HandlerFuture<H, B>;
+
+    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> HandlerFuture<H, B> {
+        let (parts, body) = req.into_parts();
+        HandlerFuture {
+            state: State::Reading {
+                handler: self.handler.clone(),
+                parts:   Some(Box::new(parts)),
+                body:    Box::pin(Limited::new(body, self.max_body_bytes).collect()),
+            },
+        }
+    }
+}
+
+/// Response future of `HandlerService`.
+pub struct HandlerFuture<H, B>
+    where B: Body, B::Error: Into<BoxError> {
+    state: State<H, B>,
+}
+
+enum State<H, B>
+    where B: Body, B::Error: Into<BoxError> {
+    Reading {
+        handler: Arc<H>,
+        parts:   Option<Box<Parts>>,
+        body:    Pin<Box<Collect<Limited<B>>>>,
+    },
+    Running(JoinHandle<WebResponse>),
+    Done,
+}
+
+impl<H, B> Future for HandlerFuture<H, B>
+    where H:        WebHandler + 'static,
+          B:        Body,
+          B::Error: Into<BoxError> {
+    type Output = Result<Response<Full<Bytes>>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let next = match this.state {
+                State::Reading { ref handler, ref mut parts, ref mut body } => {
+                    let bytes = match body.as_mut().poll(cx) {
+                        Poll::Pending              => return Poll::Pending,
+                        Poll::Ready(Ok(collected)) => collected.to_bytes(),
+                        Poll::Ready(Err(err))      => {
+                            this.state = State::Done;
+                            return Poll::Ready(Ok(to_http(body_error(err))))
+                        },
+                    };
+                    let req = to_web(parts.take().expect("request parts").as_ref(), bytes);
+                    let handler = handler.clone();
+                    State::Running(task::spawn_blocking(move || handler.handle(req)))
+                },
+                State::Running(ref mut running) => {
+                    let resp = match Pin::new(running).poll(cx) {
+                        Poll::Pending         => return Poll::Pending,
+                        Poll::Ready(Ok(resp)) => resp,
+                        Poll::Ready(Err(_))   => internal_error(),
+                    };
+                    this.state = State::Done;
+                    return Poll::Ready(Ok(to_http(resp)))
+                },
+                State::Done => panic!("HandlerFuture polled after completion"),
+            };
+            this.state = next;
+        }
+    }
+}
+
+/// A `tower_layer::Layer` that requires a Tozny login for the services it
+/// wraps.  See the module documentation.
+pub struct LoginGuardLayer<F> {
+    guard: Arc<LoginGuard<F, ()>>,
+}
+
+impl<F> Clone for LoginGuardLayer<F> {
+    fn clone(&self) -> LoginGuardLayer<F> {
+        LoginGuardLayer { guard: self.guard.clone() }
+    }
+}
+
+impl<F: LoginFlow + Send + Sync + 'static> LoginGuardLayer<F> {
+    /// The guard's own wrapped handler is not used.
+    pub fn new(guard: LoginGuard<F, ()>) -> LoginGuardLayer<F> {
+        LoginGuardLayer::from_arc(Arc::new(guard))
+    }
+
+    pub fn from_arc(guard: Arc<LoginGuard<F, ()>>) -> LoginGuardLayer<F> {
+        LoginGuardLayer { guard: guard }
+    }
+}
+
+impl<F, S> Layer<S> for LoginGuardLayer<F> {
+    type Service = LoginGuardService<F, S>;
+
+    fn layer(&self, inner: S) -> LoginGuardService<F, S> {
+        LoginGuardService { guard: self.guard.clone(), inner: inner }
+    }
+}
+
+/// A service wrapped by `LoginGuardLayer`.
+pub struct LoginGuardService<F, S> {
+    guard: Arc<LoginGuard<F, ()>>,
+    inner: S,
+}
+
+impl<F, S: Clone> Clone for LoginGuardService<F, S> {
+    fn clone(&self) -> LoginGuardService<F, S> {
+        LoginGuardService { guard: self.guard.clone(), inner: self.inner.clone() }
+    }
+}
+
+impl<F, S, B, ResBody> Service<Request<B>> for LoginGuardService<F, S>
+    where F:       LoginFlow + Send + Sync + 'static,
+          S:       Service<Request<B>, Response = Response<ResBody>> + Clone,
+          ResBody: Body<Data = Bytes> {
+    type Response = Response<Either<Full<Bytes>, ResBody>>;
+    type Error    = S::Error;
+    type Future   = GuardFuture<F, S, B>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> GuardFuture<F, S, B> {
+        // The guard only looks at the method, target and cookies; the body is
+        // left for the wrapped service.
+        let (parts, body) = req.into_parts();
+        let web_req = to_web(&parts, Bytes::new());
+        // Call the service that was polled ready, and keep a fresh clone.
+        let clone = self.inner.clone();
+        let inner = mem::replace(&mut self.inner, clone);
+        GuardFuture {
+            state: GuardState::Routing {
+                guard:   self.guard.clone(),
+                web_req: Some(web_req),
+                route:   None,
+                waiting: Some(Box::new((parts, body, inner))),
+            },
+        }
+    }
+}
+
+/// Response future of `LoginGuardService`.
+pub struct GuardFuture<F, S: Service<Request<B>>, B> {
+    state: GuardState<F, S, B>,
+}
+
+enum GuardState<F, S: Service<Request<B>>, B> {
+    /// The guard is started on the first poll, when a runtime is current.
+    Routing {
+        guard:   Arc<LoginGuard<F, ()>>,
+        web_req: Option<WebRequest>,
+        route:   Option<JoinHandle<Route>>,
+        waiting: Option<Box<(Parts, B, S)>>,
+    },
+    Calling(Pin<Box<S::Future>>),
+    Done,
+}
+
+impl<F, S, B, ResBody> Future for GuardFuture<F, S, B>
+    where F:       LoginFlow + Send + Sync + 'static,
+          S:       Service<Request<B>, Response = Response<ResBody>>,
+          ResBody: Body<Data = Bytes> {
+    type Output = Result<Response<Either<Full<Bytes>, ResBody>>, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let next = match this.state {
+                GuardState::Routing { ref guard, ref mut web_req, ref mut route,
+                                      ref mut waiting } => {
+                    let route = route.get_or_insert_with(|| {
+                        let guard = guard.clone();
+                        let web_req = web_req.take().expect("request");
+                        task::spawn_blocking(move || guard.route(&web_req))
+                    });
+                    let session = match Pin::new(route).poll(cx) {
+                        Poll::Pending                          => return Poll::Pending,
+                        Poll::Ready(Ok(Route::Allow(session))) => session,
+                        Poll::Ready(Ok(Route::Respond(resp)))  => {
+                            this.state = GuardState::Done;
+                            return Poll::Ready(Ok(to_http(resp).map(Either::Left)))
+                        },
+                        Poll::Ready(Err(_))                    => {
+                            this.state = GuardState::Done;
+                            return Poll::Ready(Ok(to_http(internal_error()).map(Either::Left)))
+                        },
+                    };
+                    let (mut parts, body, mut inner) = *waiting.take().expect("request");
+                    if !identify(&mut parts.headers, &session) {
+                        this.state = GuardState::Done;
+                        return Poll::Ready(Ok(to_http(internal_error()).map(Either::Left)))
+                    }
+                    GuardState::Calling(Box::pin(inner.call(Request::from_parts(parts, body))))
+                },
+                GuardState::Calling(ref mut calling) => {
+                    let result = match calling.as_mut().poll(cx) {
+                        Poll::Pending     => return Poll::Pending,
+                        Poll::Ready(done) => done,
+                    };
+                    this.state = GuardState::Done;
+                    return Poll::Ready(result.map(|resp| resp.map(Either::Right)))
+                },
+                GuardState::Done => panic!("GuardFuture polled after completion"),
+            };
+            this.state = next;
+        }
+    }
+}
+
+/// Replaces any incoming identity headers with the session's.
+fn identify(headers: &mut HeaderMap, session: &Session) -> bool {
+    headers.remove(USER_ID_HEADER);
+    headers.remove(SESSION_ID_HEADER);
+    match (HeaderValue::from_str(session.user_id.as_slice()),
+           HeaderValue::from_str(session.session_id.as_slice())) {
+        (Ok(user_id), Ok(session_id)) => {
+            headers.insert(USER_ID_HEADER, user_id);
+            headers.insert(SESSION_ID_HEADER, session_id);
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Converts the parts of an `http` request that handlers look at.  Header
+/// values that are not visible ASCII are dropped.
+pub fn to_web(parts: &Parts, body: Bytes) -> WebRequest {
+    let target = parts.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let mut req = WebRequest::new(parts.method.as_str(), target);
+    for (name, value) in parts.headers.iter() {
+        if let Ok(value) = value.to_str() {
+            req.add_header(name.as_str(), value);
+        }
+    }
+    req.body = body.to_vec();
+    req
+}
+
+/// Converts a handler's response.
+pub fn to_http(resp: WebResponse) -> Response<Full<Bytes>> {
+    let mut builder = Response::builder()
+        .status(resp.status)
+        .header(CONTENT_TYPE, resp.content_type)
+        .header(CACHE_CONTROL, "no-store");
+    if let Some(location) = resp.location {
+        builder = builder.header(LOCATION, location);
+    }
+    if let Some(cookie) = resp.set_cookie {
+        builder = builder.header(SET_COOKIE, cookie);
+    }
+    builder.body(Full::new(Bytes::from(resp.body))).unwrap_or_else(|_| {
+        let mut resp = Response::new(Full::new(Bytes::new()));
+        *resp.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+        resp
+    })
+}
+
+fn internal_error() -> WebResponse {
+    WebResponse::json(500, json!({ "error": "Internal error." }))
+}
+
+fn body_error(err: BoxError) -> WebResponse {
+    if err.downcast_ref::<LengthLimitError>().is_some() {
+        WebResponse::json(413, json!({ "error": "Request body is too large." }))
+    }
+    else {
+        WebResponse::json(400, json!({ "error": "Could not read the request body." }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http::{Request, Response};
+    use http_body::Body;
+    use http_body_util::Full;
+    use std::fmt::Debug;
+    use std::future;
+    use std::sync::Arc;
+    use tokio::runtime;
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    use super::*;
+    use flow::LoginFlow;
+    use login::Login;
+    use metrics::PrometheusMetrics;
+    use protocol::{Presence, Secret, SessionId, UserId};
+    use question::{Question, QuestionError};
+    use testing::FakeTozny;
+    use user::LoginChallenge;
+    use web::{LoginGuard, WebHandler, WebRequest, WebResponse, USER_ID_HEADER};
+
+    /// Responds with the request's method, target, body and `Accept` header.
+    struct Echo;
+
+    impl WebHandler for Echo {
+        fn handle(&self, req: WebRequest) -> WebResponse {
+            WebResponse::html(format!("{} {} {} {}", req.method, req.target(),
+                                      String::from_utf8_lossy(&req.body),
+                                      req.header("Accept").unwrap_or("")))
+        }
+    }
+
+    /// A `tower` service that responds with the user id headers it receives.
+    #[derive(Clone)]
+    struct EchoUser;
+
+    impl Service<Request<Full<Bytes>>> for EchoUser {
+        type Response = Response<Full<Bytes>>;
+        type Error    = Infallible;
+        type Future   = future::Ready<Result<Response<Full<Bytes>>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<Full<Bytes>>) -> Self::Future {
+            let users: Vec<&str> = req.headers().get_all(USER_ID_HEADER).iter()
+                .map(|v| v.to_str().unwrap())
+                .collect();
+            future::ready(Ok(Response::new(Full::new(Bytes::from(users.join(","))))))
+        }
+    }
+
+    /// Lets the test confirm logins on the fake that the guard uses.
+    struct Shared(Arc<FakeTozny>);
+
+    impl LoginFlow for Shared {
+        fn login_challenge(&self) -> Result<LoginChallenge, QuestionError> {
+            self.0.login_challenge()
+        }
+
+        fn push(&self, session_id: &SessionId, presence: &Presence) -> Result<(), QuestionError> {
+            self.0.push(session_id, presence)
+        }
+
+        fn check_session_status(&self, session_id: &SessionId
+                                ) -> Result<Option<Question>, QuestionError> {
+            self.0.check_session_status(session_id)
+        }
+
+        fn verify_login(&self, signed_data: &str, signature: &str) -> Result<Login, QuestionError> {
+            self.0.verify_login(signed_data, signature)
+        }
+    }
+
+    fn call<S, R>(service: &mut S, req: Request<Full<Bytes>>) -> Response<R>
+        where S: Service<Request<Full<Bytes>>, Response = Response<R>>, S::Error: Debug {
+        let rt = runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(service.call(req)).unwrap()
+    }
+
+    fn body<R: Body>(resp: Response<R>) -> String where R::Error: Debug {
+        let rt = runtime::Builder::new_current_thread().build().unwrap();
+        let bytes = rt.block_on(resp.into_body().collect()).unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn request(method: &str, uri: &str, body: &str) -> Request<Full<Bytes>> {
+        Request::builder().method(method).uri(uri)
+            .header("Accept", "text/plain").header("accept", "*/*")
+            .body(Full::new(Bytes::from(body.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn it_passes_requests_and_responses_through() {
+        let mut service = HandlerService::new(Echo);
+        let resp = call(&mut service, request("POST", "/form?a=1", "x=y"));
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["cache-control"], "no-store");
+        assert_eq!(body(resp), "POST /form?a=1 x=y text/plain, */*");
+    }
+
+    #[test]
+    fn it_refuses_large_bodies() {
+        let mut service = HandlerService::new(Echo).max_body_bytes(4);
+        let resp = call(&mut service, request("POST", "/", "too long"));
+        assert_eq!(resp.status(), 413);
+    }
+
+    #[test]
+    fn it_serves_the_login_guard_and_metrics() {
+        let guard = LoginGuard::new(FakeTozny::new(), Secret::from_slice("cookie key"), Echo);
+        let resp = call(&mut HandlerService::new(guard), request("GET", "/reports", ""));
+        assert_eq!(resp.status(), 302);
+        assert_eq!(resp.headers()["location"], "/tozny/login?return_to=%2Freports");
+
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let resp = call(&mut HandlerService::from_arc(metrics), request("GET", "/metrics", ""));
+        assert_eq!(resp.headers()["content-type"], "text/plain; version=0.0.4");
+    }
+
+    #[test]
+    fn it_guards_tower_services() {
+        let fake = Arc::new(FakeTozny::new());
+        let guard = LoginGuard::new(Shared(fake.clone()), Secret::from_slice("cookie key"), ());
+        let mut service = LoginGuardLayer::new(guard).layer(EchoUser);
+
+        let mut forged = request("GET", "/reports?year=2015", "");
+        forged.headers_mut().insert(USER_ID_HEADER, "sid_mallory".parse().unwrap());
+        let resp = call(&mut service, forged);
+        assert_eq!(resp.status(), 302);
+        assert_eq!(resp.headers()["location"], "/tozny/login?return_to=%2Freports%3Fyear%3D2015");
+
+        let page = body(call(&mut service, request("GET", "/tozny/login", "")));
+        let start = page.find("data-session=\"").unwrap() + 14;
+        let end = start + page[start..].find('"').unwrap();
+        let sid = page[start..end].to_string();
+        fake.confirm(&SessionId::new(sid.clone()), &UserId::from_slice("sid_alice"), "Alice");
+        let status = format!("/tozny/login/status?session_id={}", sid);
+        let done = call(&mut service, request("GET", &status, ""));
+        let cookie = done.headers()["set-cookie"].to_str().unwrap().split(';').next().unwrap()
+            .to_string();
+
+        let mut forged = request("GET", "/reports", "");
+        forged.headers_mut().insert(USER_ID_HEADER, "sid_mallory".parse().unwrap());
+        forged.headers_mut().insert("cookie", cookie.parse().unwrap());
+        let resp = call(&mut service, forged);
+        assert_eq!(resp.status(), 200);
+        assert_eq!(body(resp), "sid_alice");
+    }
+}
@@ -0,0 +1,272 @@
+//! Instrumentation of Tozny API calls.
+//!
+//! With the `tracing` cargo feature enabled, every call made by
+//! `question::send_request` and `UserApi::raw_call` is recorded as an
+//! `info` level `tracing` span named `tozny_api_call`, under the target
+//! `tozny_auth::api`.  The span has the fields `api`, `method` and
+//! `realm_key_id`, and is given `status`, `latency_ms` and `error` (see
+//! `classify_error`) when the call finishes.  Within the span, a `trace`
+//! event carries the call's parameters, and a closing event is emitted at
+//! `debug` level for successful calls and `warn` level for failures.
+//! Without the feature, spans do nothing.
+//!
+//! Recorded parameters are redacted with `redact`, so signed payloads,
+//! signatures, secrets and presence values never reach a subscriber.
+
+#[cfg(feature = "tracing")]
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+
+use protocol::{KeyId};
+use question::{QuestionError};
+
+/// Parameter names whose values are never logged.  `presence` is included
+/// because it is enough to send push notifications to a user's device.
+pub const REDACTED_FIELDS: &'static [&'static str] = &[
+    "signed_data", "signature", "secret", "realm_secret", "client_secret", "presence",
+];
+
+/// Replacement for redacted values.
+pub const REDACTED: &'static str = "[redacted]";
+
+/// Returns a copy of `params` with the values of `REDACTED_FIELDS` replaced,
+/// in nested objects and arrays as well as at the top level.
+pub fn redact(params: &Map<String, Value>) -> Map<String, Value> {
+    params.iter()
+        .map(|(k, v)| {
+            let v = if is_redacted(k) { Value::from(REDACTED) } else { redact_value(v) };
+            (k.clone(), v)
+        })
+        .collect()
+}
+
+fn redact_value(value: &Value) -> Value {
+    match *value {
+        Value::Object(ref obj)  => Value::Object(redact(obj)),
+        Value::Array(ref items) => Value::Array(items.iter().map(redact_value).collect()),
+        ref v                   => v.clone(),
+    }
+}
+
+/// Like `redact`, for the query parameters of a user-level call.
+pub fn redact_pairs(params: &[(&str, &str)]) -> Map<String, Value> {
+    let mut obj = Map::new();
+    for &(k, v) in params.iter() {
+        obj.insert(k.to_string(), Value::from(v));
+    }
+    redact(&obj)
+}
+
+fn is_redacted(field: &str) -> bool {
+    REDACTED_FIELDS.contains(&field)
+}
+
+/// Sorts an error into a short, stable category for logs and metrics.
+pub fn classify_error(err: &QuestionError) -> &'static str {
+    match *err {
+        QuestionError::HttpError(_)                => "http",
+        QuestionError::IoError(_)                  => "io",
+        QuestionError::ParserError(_)              => "parse",
+        QuestionError::DecoderError(_)             => "decode",
+        QuestionError::EncoderError(_)             => "encode",
+        QuestionError::Base64Error(_)              => "base64",
+        QuestionError::Utf8Error(_)                => "utf8",
+        QuestionError::InvalidSignature            => "invalid_signature",
+        QuestionError::UnsupportedSignatureType(_) => "unsupported_signature_type",
+        QuestionError::BadlyFormedResponse         => "badly_formed_response",
+        QuestionError::ErrorResponse(_)            => "api_error",
+        QuestionError::ServerError(_)              => "server_error",
+        QuestionError::SessionMismatch             => "session_mismatch",
+    }
+}
+
+/// Tracks one API call from start to finish.
+pub struct CallSpan {
+    #[cfg(feature = "tracing")]
+    span:    ::tracing::Span,
+    #[cfg(feature = "tracing")]
+    started: DateTime<Utc>,
+}
+
+impl CallSpan {
+    /// `api` is `"realm"` or `"user"`.  `params` should not be redacted yet.
+    #[cfg(feature = "tracing")]
+    pub fn start(api: &'static str, method: &str, realm_key_id: &KeyId, params: &Map<String, Value>
+                 ) -> CallSpan {
+        CallSpan {
+            span:    open_span(api, method, realm_key_id, params),
+            started: Utc::now(),
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub fn start(_: &'static str, _: &str, _: &KeyId, _: &Map<String, Value>) -> CallSpan {
+        CallSpan {}
+    }
+
+    /// Records the HTTP status of the response.
+    #[cfg(feature = "tracing")]
+    pub fn status(&mut self, status: u16) {
+        self.span.record("status", status);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub fn status(&mut self, _: u16) {}
+
+    /// Records the outcome of the call, and closes the span.
+    #[cfg(feature = "tracing")]
+    pub fn finish<T>(self, result: &Result<T, QuestionError>) {
+        let latency_ms = (Utc::now() - self.started).num_milliseconds();
+        self.span.record("latency_ms", latency_ms);
+        match *result {
+            Ok(_) => {
+                debug!(target: "tozny_auth::api", parent: &self.span, "finished");
+            },
+            Err(ref err) => {
+                let error = classify_error(err);
+                self.span.record("error", error);
+                warn!(target: "tozny_auth::api", parent: &self.span, error = error, "failed");
+            },
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub fn finish<T>(self, _: &Result<T, QuestionError>) {}
+}
+
+#[cfg(feature = "tracing")]
+fn open_span(api: &'static str, method: &str, realm_key_id: &KeyId, params: &Map<String, Value>
+             ) -> ::tracing::Span {
+    use protocol::Newtype;
+    use tracing::field::Empty;
+
+    let span = info_span!(target: "tozny_auth::api", "tozny_api_call",
+                          api = api, method = method, realm_key_id = realm_key_id.as_slice(),
+                          status = Empty, latency_ms = Empty, error = Empty);
+    let params = Value::Object(redact(params));
+    trace!(target: "tozny_auth::api", parent: &span, params = %params, "started");
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Map, Value};
+    #[cfg(feature = "tracing")]
+    use std::fmt;
+    #[cfg(feature = "tracing")]
+    use std::sync::{Arc, Mutex};
+    #[cfg(feature = "tracing")]
+    use tracing::{Event, Metadata, Subscriber, span};
+    #[cfg(feature = "tracing")]
+    use tracing::field::{Field, Visit};
+
+    use super::*;
+    use question::{QuestionError};
+
+    #[test]
+    fn it_redacts_signed_data_signatures_and_secrets() {
+        let mut params = Map::new();
+        params.insert("signed_data".to_string(), Value::from("eyJub25jZSI6"));
+        params.insert("signature".to_string(), Value::from("c2lnbmF0dXJl"));
+        params.insert("realm_secret".to_string(), Value::from("hunter2"));
+        params.insert("session_id".to_string(), Value::from("sess_1"));
+        let redacted = Value::Object(redact(&params)).to_string();
+        assert!(!redacted.contains("eyJub25jZSI6"));
+        assert!(!redacted.contains("c2lnbmF0dXJl"));
+        assert!(!redacted.contains("hunter2"));
+        assert!(redacted.contains("sess_1"));
+    }
+
+    #[test]
+    fn it_redacts_nested_parameters() {
+        let params = json!({
+            "user_id": "sid_1",
+            "meta":    { "client_secret": "hunter2", "devices": [{ "presence": "p_1" }] },
+        });
+        let redacted = redact(params.as_object().unwrap());
+        assert_eq!(Value::Object(redacted), json!({
+            "user_id": "sid_1",
+            "meta":    { "client_secret": REDACTED, "devices": [{ "presence": REDACTED }] },
+        }));
+    }
+
+    #[test]
+    fn it_redacts_user_call_parameters() {
+        let obj = redact_pairs(&[("method", "user.push"), ("presence", "p_secret")]);
+        assert_eq!(obj.get("method"), Some(&Value::from("user.push")));
+        assert_eq!(obj.get("presence"), Some(&Value::from(REDACTED)));
+    }
+
+    /// Collects the fields of every span and event as `name=value` strings.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    #[cfg(feature = "tracing")]
+    impl Visit for Recorder {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.lock().unwrap().push(format!("{}={}", field.name(), value));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.lock().unwrap().push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata) -> bool { true }
+
+        fn new_span(&self, attrs: &span::Attributes) -> span::Id {
+            attrs.record(&mut self.clone());
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, values: &span::Record) {
+            values.record(&mut self.clone());
+        }
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event) {
+            event.record(&mut self.clone());
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn it_records_a_span_for_each_call() {
+        let recorder = Recorder::default();
+        let fields = recorder.0.clone();
+        let mut params = Map::new();
+        params.insert("signature".to_string(), Value::from("c2lnbmF0dXJl"));
+        params.insert("session_id".to_string(), Value::from("sess_1"));
+        ::tracing::subscriber::with_default(recorder, || {
+            let mut span = CallSpan::start("realm", "realm.check_valid_login",
+                                           &KeyId::from_slice("sid_d915e7226947b"), &params);
+            span.status(200);
+            span.finish::<Value>(&Err(QuestionError::InvalidSignature));
+        });
+
+        let fields = fields.lock().unwrap();
+        for expected in ["api=realm", "method=realm.check_valid_login",
+                         "realm_key_id=sid_d915e7226947b", "status=200",
+                         "error=invalid_signature"] {
+            assert!(fields.iter().any(|f| f == expected), "missing {} in {:?}", expected, fields);
+        }
+        assert!(fields.iter().any(|f| f.starts_with("latency_ms=")));
+        let params = fields.iter().find(|f| f.starts_with("params=")).unwrap();
+        assert!(params.contains("sess_1"));
+        assert!(!params.contains("c2lnbmF0dXJl"));
+    }
+
+    #[test]
+    fn it_classifies_errors() {
+        assert_eq!(classify_error(&QuestionError::InvalidSignature), "invalid_signature");
+        assert_eq!(classify_error(&QuestionError::ErrorResponse(Value::Null)), "api_error");
+    }
+}
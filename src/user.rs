@@ -2,53 +2,105 @@
 //!
 //! API calls defined in this module do not require authentication.
 
-use hyper::client::{Client};
-use rustc_serialize::json::{Json};
-use url;
+use chrono::Utc;
+use serde_json;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use ureq;
 use url::Url;
 
+use audit::{AuditEvent, AuditEventKind, AuditOutcome, AuditSink, Auditor};
+use metrics::MetricsRecorder;
 use protocol;
-use protocol::{Challenge, KeyId, Presence, Newtype, SessionId, Timestamp, UserId};
+use protocol::{Challenge, DeviceId, KeyId, Method, Presence, Newtype, SessionId, Timestamp,
+               UserId};
 use question;
 use question::{Question, QuestionError, from_json};
+#[cfg(feature = "qr")]
+use qr::{QrError, QrMatrix};
+use trace;
+use trace::{CallSpan};
 
 /// Information associated with a Tozny user.  This struct should be expanded in
 /// the future.
-#[derive(Debug, RustcDecodable, RustcEncodable)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id:     UserId,
     pub logins: isize,
+    /// Custom fields stored with the user record, if any.
+    pub meta:   Option<Map<String, Value>>,
+}
+
+impl User {
+    /// Returns a string-valued field from the user's `meta` record.
+    pub fn meta_field(&self, field: &str) -> Option<String> {
+        self.meta.as_ref()
+            .and_then(|m| m.get(field))
+            .and_then(|v| match *v {
+                Value::String(ref s) => Some(s.clone()),
+                _                   => None,
+            })
+    }
+}
+
+/// A device, such as a phone running the Tozny app, that a user has
+/// enrolled.  Returned by `Realm::user_devices`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Device {
+    pub id:        DeviceId,
+    pub name:      String,
+    /// Token for sending push notifications to the device.  It stops working
+    /// once the device is revoked.
+    pub presence:  Presence,
+    /// When the device was last used to log in, if it has been.
+    pub last_used: Option<Timestamp>,
 }
 
 /// Result of `login_challenge` call.  Contains a number of values that are
 /// necessary for an authentication flow.  A brief rundown:
 ///
 /// - `qr_url` is the URL of a QR code that can be displayed to the user.  The
-/// user authenticates by scanning the code with the Tozny mobile app.
+///   user authenticates by scanning the code with the Tozny mobile app.
 /// - `mobile_url` is the URL encoded in that QR code.  It uses a custom scheme.
-///  If opened on a device with the Tozny app installed, the app will open
-///  automatically.
+///   If opened on a device with the Tozny app installed, the app will open
+///   automatically.
 /// - `presence` this value may be stored for future logins. If a stored
-/// presence value from a previous login is available, it may be used with the
-/// `push` method to send a push notification to the user's mobile device
-/// instead of (or in addition to) displaying a QR code.
+///   presence value from a previous login is available, it may be used with
+///   the `push` method to send a push notification to the user's mobile device
+///   instead of (or in addition to) displaying a QR code.
 /// - `session_id` is used with `check_session_status` determine whether the
-/// user has completed authentication.
-#[derive(Debug, RustcDecodable, RustcEncodable)]
+///   user has completed authentication.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct LoginChallenge {
     pub challenge:    Challenge,
     pub realm_key_id: KeyId,
     pub session_id:   SessionId,
+    #[serde(with = "protocol::url_string")]
     pub qr_url:       Url,
+    #[serde(with = "protocol::url_string")]
     pub mobile_url:   Url,
     pub created_at:   Timestamp,
     pub presence:     Presence,
 }
 
+#[cfg(feature = "qr")]
+impl LoginChallenge {
+    /// Encodes `mobile_url` as a QR code locally.  This is an alternative to
+    /// displaying the image hosted at `qr_url`, for environments that cannot
+    /// reach Tozny's servers or that cannot display images.
+    ///
+    /// Requires the `qr` cargo feature.
+    pub fn qr_code(&self) -> Result<QrMatrix, QrError> {
+        QrMatrix::from_url(&self.mobile_url)
+    }
+}
+
 /// Interface for sending user-level API calls to Tozny.
 pub struct UserApi {
     key_id:  KeyId,
-    api_url: url::Url,
+    api_url: Url,
+    audit:   Auditor,
+    metrics: MetricsRecorder,
 }
 
 impl UserApi {
@@ -61,52 +113,97 @@ impl UserApi {
         UserApi {
             key_id: key_id,
             api_url: url,
+            audit: Auditor::none(),
+            metrics: MetricsRecorder::none(),
         }
     }
 
+    /// Reports challenges, push notifications and session status results to
+    /// an audit sink.  See the `audit` module.
+    pub fn with_audit<S: AuditSink + 'static>(mut self, sink: Arc<S>) -> UserApi {
+        self.audit = Auditor::new(sink);
+        self
+    }
+
+    /// Reports API calls, challenges and push notifications to a metrics
+    /// recorder.  See the `metrics` module.
+    pub fn with_metrics(mut self, metrics: MetricsRecorder) -> UserApi {
+        self.metrics = metrics;
+        self
+    }
+
     /// Low-level method for sending arbitrary user-level API calls.
-    pub fn raw_call<'a>(&self, params: Vec<(&'a str, &'a str)>) -> Result<Json, QuestionError> {
-        let mut url = question::translate_url(&self.api_url);
-        let mut client = Client::new();
-        url.set_query_from_pairs(params.into_iter());
-        client.get(url).send().map_err(QuestionError::HttpError)
-        .and_then(|mut res| {
-            Json::from_reader(&mut res)
+    pub fn raw_call<'a>(&self, params: Vec<(&'a str, &'a str)>) -> Result<Value, QuestionError> {
+        let method = params.iter().find(|&&(k, _)| k == "method").map(|&(_, v)| v).unwrap_or("");
+        let mut span = CallSpan::start("user", method, &self.key_id, &trace::redact_pairs(&params));
+        let method = Method::from_slice(method);
+        let started = Utc::now();
+        let mut url = self.api_url.clone();
+        url.query_pairs_mut().extend_pairs(params);
+        let result = question::response(ureq::get(url.as_str()).call())
+        .and_then(|res| {
+            span.status(res.status());
+            serde_json::from_reader(res.into_reader())
                 .map_err(QuestionError::ParserError)
         })
-        .and_then(|json| {
+        .and_then(|json: Value| {
             match protocol::error_response(&json.clone()) {
                 Some(errs) => Err(QuestionError::ErrorResponse(errs.clone())),
                 None       => Ok(json),
             }
-        })
+        });
+        span.finish(&result);
+        self.metrics.api_call(&method, started, &result);
+        result
     }
 
     /// Use this method to initiate a login.  See the documentation on
     /// `LoginChallenge` for some information on how to use the response.
     pub fn login_challenge(&self) -> Result<LoginChallenge, QuestionError> {
-        self.raw_call(vec![
+        let result = self.raw_call(vec![
             ("method",       "user.login_challenge"),
             ("realm_key_id", self.key_id.as_slice()),
             ("user_add",     "0"),
             ("format",       "json"),
         ])
         .and_then(|json| {
-            from_json(&json).map_err(QuestionError::DecoderError)
-        })
+            from_json::<LoginChallenge>(&json).map_err(QuestionError::DecoderError)
+        });
+        if let Ok(ref c) = result {
+            self.metrics.challenge_created(&c.session_id);
+        }
+        let kind = AuditEventKind::LoginChallenge;
+        self.audit.record(match result {
+            Ok(ref c)    => AuditEvent::new(kind, AuditOutcome::Success, &self.key_id)
+                                .session_id(&c.session_id),
+            Err(ref err) => AuditEvent::new(kind, AuditOutcome::Failure, &self.key_id)
+                                .reason(trace::classify_error(err)),
+        });
+        result
     }
 
     /// Sends a push notification to a user's mobile device asking the user to
     /// sign in to something.
     pub fn push(&self, session_id: &SessionId, presence: &Presence
                 ) -> Result<(), QuestionError> {
-        self.raw_call(vec![
+        let result = self.raw_call(vec![
             ("method",       "user.push"),
             ("realm_key_id", self.key_id.as_slice()),
             ("session_id",   session_id.as_slice()),
             ("presence",     presence.as_slice()),
         ])
-        .map(|_| ())
+        .map(|_| ());
+        if result.is_ok() {
+            self.metrics.push_sent();
+        }
+        let kind = AuditEventKind::Push;
+        let event = match result {
+            Ok(_)        => AuditEvent::new(kind, AuditOutcome::Success, &self.key_id),
+            Err(ref err) => AuditEvent::new(kind, AuditOutcome::Failure, &self.key_id)
+                                .reason(trace::classify_error(err)),
+        };
+        self.audit.record(event.session_id(session_id));
+        result
     }
 
     /// Returns a signed question that may be checked via the `Realm`
@@ -116,14 +213,14 @@ impl UserApi {
     /// session via the Tozny app.
     pub fn check_session_status(&self, session_id: &SessionId
                                ) -> Result<Option<Question>, QuestionError> {
-        self.raw_call(vec![
+        let result = self.raw_call(vec![
             ("method",       "user.check_session_status"),
             ("session_id",   session_id.as_slice()),
             ("realm_key_id", self.key_id.as_slice()),
             ("format",       "json"),
         ])
         .and_then(|json| {
-            if json.find("signed_data").is_some() && json.find("signature").is_some() {
+            if json.get("signed_data").is_some() && json.get("signature").is_some() {
                 from_json::<Question>(&json)
                     .map_err(QuestionError::DecoderError)
                     .map(Some)
@@ -131,6 +228,15 @@ impl UserApi {
             else {
                 Ok(None)
             }
-        })
+        });
+        let kind = AuditEventKind::SessionStatus;
+        let event = match result {
+            Ok(Some(_))  => AuditEvent::new(kind, AuditOutcome::Success, &self.key_id),
+            Ok(None)     => AuditEvent::new(kind, AuditOutcome::Pending, &self.key_id),
+            Err(ref err) => AuditEvent::new(kind, AuditOutcome::Failure, &self.key_id)
+                                .reason(trace::classify_error(err)),
+        };
+        self.audit.record(event.session_id(session_id));
+        result
     }
 }
@@ -0,0 +1,330 @@
+//! Verifies Tozny logins with public keys only.
+//!
+//! A `LoginVerifier` holds no realm secret, so it can be handed to any
+//! service that needs to check a `Login` without also giving that service the
+//! ability to make realm-level API calls.  It accepts logins signed with
+//! `RSA` or `ED25519`; `HMAC`-signed logins can only be checked with
+//! `Realm::verify_login`.
+//!
+//! Keys are given directly, or as a JWKS document (RFC 7517).  A verifier
+//! built with `from_jwks_url` fetches the document when first needed, caches
+//! it, and fetches it again once the cache is older than `cache_ttl`, or when
+//! a signature does not match any cached key (which happens after Tozny
+//! rotates its keys).
+
+use chrono::{Duration, Utc};
+use serde_json;
+use serde_json::{Value};
+use std::sync::{Mutex};
+use url::{Url};
+
+use login;
+use login::Login;
+use protocol;
+use protocol::{Timestamp};
+use question;
+use question::{PublicKey, QuestionError};
+
+/// A key-set fetch is not repeated because of an unknown signature more often
+/// than this.
+const MIN_REFETCH_SECS: i64 = 60;
+
+struct CachedKeys {
+    keys:       Vec<PublicKey>,
+    fetched_at: Timestamp,
+}
+
+/// Checks signed logins against a set of Tozny public keys.  See the module
+/// documentation.
+pub struct LoginVerifier {
+    fetch:     Option<Box<dyn Fn() -> Result<Value, QuestionError> + Send + Sync>>,
+    cache:     Mutex<Option<CachedKeys>>,
+    cache_ttl: Duration,
+}
+
+impl LoginVerifier {
+    /// Creates a verifier with a fixed set of keys.
+    pub fn new(keys: Vec<PublicKey>) -> LoginVerifier {
+        LoginVerifier {
+            fetch:     None,
+            cache:     Mutex::new(Some(CachedKeys {
+                keys:       keys,
+                fetched_at: Timestamp::new(Utc::now()),
+            })),
+            cache_ttl: Duration::hours(1),
+        }
+    }
+
+    /// Creates a verifier with the keys in a JWKS document.
+    pub fn from_jwks(jwks: &Value) -> Result<LoginVerifier, QuestionError> {
+        parse_jwks(jwks).map(LoginVerifier::new)
+    }
+
+    /// Creates a verifier that fetches its keys as a JWKS document from the
+    /// given URL.
+    pub fn from_jwks_url(url: Url) -> LoginVerifier {
+        LoginVerifier::from_jwks_source(move || {
+            let res = question::response(ureq::get(url.as_str()).call())?;
+            let json: Value = serde_json::from_reader(res.into_reader())
+                .map_err(QuestionError::ParserError)?;
+            match protocol::error_response(&json) {
+                Some(errs) => Err(QuestionError::ErrorResponse(errs.clone())),
+                None       => Ok(json),
+            }
+        })
+    }
+
+    /// Creates a verifier that gets its JWKS document by calling `fetch`.
+    pub fn from_jwks_source<F>(fetch: F) -> LoginVerifier
+        where F: Fn() -> Result<Value, QuestionError> + Send + Sync + 'static {
+        LoginVerifier {
+            fetch:     Some(Box::new(fetch)),
+            cache:     Mutex::new(None),
+            cache_ttl: Duration::hours(1),
+        }
+    }
+
+    /// How long fetched keys are used before they are fetched again.
+    /// Defaults to one hour.  Has no effect on a verifier with fixed keys.
+    pub fn cache_ttl(mut self, ttl: Duration) -> LoginVerifier {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Verifies that a response from `check_session_status` is signed by
+    /// Tozny, and decodes a `Login` value.  As with `Realm::verify_login`, the
+    /// login's `expires_at` is returned for the caller to act on, and is not
+    /// checked here.
+    ///
+    /// Makes a network request only when a verifier built with
+    /// `from_jwks_url` needs to refresh its keys.
+    pub fn verify_login(&self, signed_data: &str, signature: &str
+                        ) -> Result<Login, QuestionError> {
+        let keys = self.keys(false)?;
+        match login::verify(None, &keys, signed_data, signature) {
+            Err(QuestionError::InvalidSignature) if self.may_refetch() => {
+                let keys = self.keys(true)?;
+                login::verify(None, &keys, signed_data, signature)
+            },
+            result => result,
+        }
+    }
+
+    /// Returns the current keys, fetching them if there are none, if they are
+    /// stale, or if `refresh` is set.  The cache is not locked during the
+    /// fetch, so that a slow key server does not hold up logins that the
+    /// cached keys can verify.
+    fn keys(&self, refresh: bool) -> Result<Vec<PublicKey>, QuestionError> {
+        let fetch = match self.fetch {
+            Some(ref fetch) => fetch,
+            None            => return Ok(self.cached_keys()),
+        };
+        {
+            let cache = self.cache.lock().unwrap();
+            match *cache {
+                Some(ref c) if !refresh
+                    && *c.fetched_at.as_slice() + self.cache_ttl > Utc::now() => {
+                    return Ok(c.keys.clone())
+                },
+                _ => (),
+            }
+        }
+        let keys = parse_jwks(&fetch()?)?;
+        *self.cache.lock().unwrap() = Some(CachedKeys {
+            keys:       keys.clone(),
+            fetched_at: Timestamp::new(Utc::now()),
+        });
+        Ok(keys)
+    }
+
+    fn cached_keys(&self) -> Vec<PublicKey> {
+        self.cache.lock().unwrap().as_ref().map(|c| c.keys.clone()).unwrap_or_default()
+    }
+
+    fn may_refetch(&self) -> bool {
+        self.fetch.is_some() && match *self.cache.lock().unwrap() {
+            Some(ref c) => {
+                *c.fetched_at.as_slice() + Duration::seconds(MIN_REFETCH_SECS) <= Utc::now()
+            },
+            None => true,
+        }
+    }
+}
+
+/// Reads the signing keys from a JWKS document.  Keys with `"kty": "RSA"`,
+/// and keys with `"kty": "OKP"` and `"crv": "Ed25519"`, are read; other keys,
+/// and keys whose `use` is not `sig`, are skipped.
+pub fn parse_jwks(jwks: &Value) -> Result<Vec<PublicKey>, QuestionError> {
+    let entries = jwks.get("keys").and_then(|k| k.as_array())
+        .ok_or(QuestionError::BadlyFormedResponse)?;
+    let mut keys = Vec::new();
+    for entry in entries {
+        if entry.get("use").map(|u| u != "sig").unwrap_or(false) {
+            continue
+        }
+        let field = |name: &str| -> Result<Vec<u8>, QuestionError> {
+            let value = entry.get(name).and_then(|v| v.as_str())
+                .ok_or(QuestionError::BadlyFormedResponse)?;
+            question::decode_base64(value).map_err(QuestionError::Base64Error)
+        };
+        match (entry.get("kty").and_then(|v| v.as_str()),
+               entry.get("crv").and_then(|v| v.as_str())) {
+            (Some("RSA"), _) => {
+                keys.push(PublicKey::Rsa { modulus: field("n")?, exponent: field("e")? })
+            },
+            (Some("OKP"), Some("Ed25519")) => keys.push(PublicKey::Ed25519(field("x")?)),
+            _ => (),
+        }
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration};
+    use serde_json;
+    use serde_json::{Value};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time;
+
+    use super::*;
+    use protocol::{UserId};
+    use question::{QuestionError};
+
+    fn vector(contents: &str) -> Value {
+        serde_json::from_str(contents).unwrap()
+    }
+
+    fn rsa() -> Value {
+        vector(include_str!("../testdata/login_rsa.json"))
+    }
+
+    fn ed25519() -> Value {
+        vector(include_str!("../testdata/login_ed25519.json"))
+    }
+
+    fn jwks(vectors: &[Value]) -> Value {
+        let keys: Vec<Value> = vectors.iter().map(|v| v["public_key"].clone()).collect();
+        json!({ "keys": keys })
+    }
+
+    fn verify(verifier: &LoginVerifier, v: &Value) -> Result<Login, QuestionError> {
+        verifier.verify_login(v["question"]["signed_data"].as_str().unwrap(),
+                              v["question"]["signature"].as_str().unwrap())
+    }
+
+    #[test]
+    fn it_verifies_logins_with_keys_from_a_jwks_document() {
+        let verifier = LoginVerifier::from_jwks(&jwks(&[rsa(), ed25519()])).unwrap();
+        for v in [rsa(), ed25519()] {
+            let login = verify(&verifier, &v).unwrap();
+            assert_eq!(login.user_id, UserId::from_slice("sid_52fa6d2d0f9d3"));
+        }
+    }
+
+    #[test]
+    fn it_skips_keys_it_cannot_use() {
+        let doc = json!({ "keys": [
+            { "kty": "EC", "crv": "P-256", "x": "AA", "y": "AA" },
+            { "kty": "RSA", "use": "enc", "n": "AA", "e": "AQAB" },
+            ed25519()["public_key"],
+        ]});
+        assert_eq!(parse_jwks(&doc).unwrap().len(), 1);
+        assert!(parse_jwks(&json!({ "keys": [{ "kty": "RSA", "n": "AA" }] })).is_err());
+        assert!(parse_jwks(&json!({})).is_err());
+    }
+
+    #[test]
+    fn it_rejects_hmac_signed_logins() {
+        let verifier = LoginVerifier::new(Vec::new());
+        let golden = vector(include_str!("../testdata/login.json"));
+        match verify(&verifier, &golden) {
+            Err(QuestionError::InvalidSignature) => (),
+            r => panic!("expected invalid signature, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_caches_fetched_keys() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let verifier = LoginVerifier::from_jwks_source(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(jwks(&[rsa()]))
+        });
+        assert!(verify(&verifier, &rsa()).is_ok());
+        assert!(verify(&verifier, &rsa()).is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // A signature from an unknown key does not trigger another fetch
+        // right away.
+        assert!(verify(&verifier, &ed25519()).is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn it_refetches_stale_keys() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let verifier = LoginVerifier::from_jwks_source(move || {
+            // Simulates a key rotation after the first fetch.
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(jwks(&[rsa()])),
+                _ => Ok(jwks(&[ed25519()])),
+            }
+        }).cache_ttl(Duration::seconds(0));
+        assert!(verify(&verifier, &rsa()).is_ok());
+        assert!(verify(&verifier, &ed25519()).is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn it_verifies_with_cached_keys_during_a_refetch() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (started_tx, release_rx) = (Mutex::new(started_tx), Mutex::new(release_rx));
+        let fetches = AtomicUsize::new(0);
+        let verifier = Arc::new(LoginVerifier::from_jwks_source(move || {
+            match fetches.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(jwks(&[rsa()])),
+                _ => {
+                    started_tx.lock().unwrap().send(()).unwrap();
+                    release_rx.lock().unwrap().recv().unwrap();
+                    Ok(jwks(&[rsa(), ed25519()]))
+                },
+            }
+        }));
+        assert!(verify(&verifier, &rsa()).is_ok());
+        // Old enough that an unknown signature triggers a refetch, but still
+        // within the cache TTL.
+        verifier.cache.lock().unwrap().as_mut().unwrap().fetched_at =
+            Timestamp::new(Utc::now() - Duration::seconds(MIN_REFETCH_SECS * 2));
+
+        let rotated = {
+            let verifier = verifier.clone();
+            thread::spawn(move || verify(&verifier, &ed25519()).is_ok())
+        };
+        started_rx.recv().unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+        {
+            let verifier = verifier.clone();
+            thread::spawn(move || done_tx.send(verify(&verifier, &rsa()).is_ok()).unwrap());
+        }
+        assert_eq!(done_rx.recv_timeout(time::Duration::from_secs(5)), Ok(true));
+
+        release_tx.send(()).unwrap();
+        assert!(rotated.join().unwrap());
+    }
+
+    #[test]
+    fn it_reports_fetch_errors() {
+        let verifier = LoginVerifier::from_jwks_source(|| Err(QuestionError::BadlyFormedResponse));
+        match verify(&verifier, &rsa()) {
+            Err(QuestionError::BadlyFormedResponse) => (),
+            r => panic!("expected fetch error, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,596 @@
+//! Protects routes of a web server with Tozny logins.
+//!
+//! The HTTP endpoints in this crate - `LoginGuard`, `status::StatusHub`,
+//! `oidc::OidcProvider` and `metrics::PrometheusMetrics` - implement
+//! `WebHandler`, a plain synchronous trait over `WebRequest` and
+//! `WebResponse`, so that they do not depend on any particular server.  With
+//! the `tower` cargo feature, the `tower` module mounts them in servers built
+//! on `tower` and `http` 1, such as hyper 1 and axum, and its
+//! `LoginGuardLayer` puts a `LoginGuard` in front of any `tower` service.
+//!
+//! `LoginGuard` wraps another `WebHandler`.  Requests that carry a valid
+//! session cookie are passed through to the wrapped handler, with the headers
+//! `X-Tozny-User-Id` and `X-Tozny-Session-Id` set to identify the user.  Other
+//! requests are redirected to a login page that displays the QR code from
+//! `login_challenge`.  The page polls a status endpoint; once the login is
+//! confirmed the result is checked with `verify_login`, a session token is
+//! minted with a `SessionManager` and set as a cookie, and the browser is sent
+//! back to the page it originally asked for.
+//!
+//! Routes under the guard's prefix (`/tozny` by default):
+//!
+//! - `GET {prefix}/login?return_to=/path`: login page
+//! - `GET {prefix}/login/status?session_id=...`: polled by the login page
+//! - `POST {prefix}/logout`: revokes the session and clears the cookie.  Only
+//!   `POST` is accepted, and the session cookie is `SameSite=Lax`, so other
+//!   sites cannot log users out.
+//!
+//! The routing logic is also available on its own, through
+//! `LoginGuard::route`.
+
+use chrono::{Duration, Utc};
+use std::collections::BTreeMap;
+use serde_json::{Value};
+use std::sync::{Mutex};
+use url::form_urlencoded;
+
+use flow::LoginFlow;
+use protocol::{Newtype, Secret, SessionId, Timestamp};
+use session::{Session, SessionManager};
+
+/// Name of the cookie that holds the session token, unless configured.
+pub const DEFAULT_COOKIE_NAME: &'static str = "tozny_session";
+
+/// Headers set on requests that are passed to the wrapped handler.  Incoming
+/// values for these headers are always removed first.
+pub const USER_ID_HEADER:    &'static str = "X-Tozny-User-Id";
+pub const SESSION_ID_HEADER: &'static str = "X-Tozny-Session-Id";
+
+/// Logins that have not completed within this many seconds are forgotten;
+/// Tozny challenges have expired by then.
+const PENDING_LIFETIME_SECS: i64 = 600;
+
+/// A synchronous HTTP handler.  Handlers may block - for example to call the
+/// Tozny API - so servers should run them on a thread that is allowed to.
+pub trait WebHandler: Send + Sync {
+    fn handle(&self, req: WebRequest) -> WebResponse;
+}
+
+/// An HTTP request, with its body read into memory.
+#[derive(Clone, Debug)]
+pub struct WebRequest {
+    pub method:    String,
+    pub path:      String,
+    pub query:     BTreeMap<String, String>,
+    /// The query string as sent, without the leading `?`, if there was one.
+    pub raw_query: Option<String>,
+    /// Header values, keyed by lower-case name.  Repeated headers are joined
+    /// with `, `, except `Cookie`, which is joined with `; `.
+    pub headers:   BTreeMap<String, String>,
+    pub body:      Vec<u8>,
+}
+
+impl WebRequest {
+    /// Creates a request without headers or body.  `target` is the path and
+    /// query string, as in the request line.
+    pub fn new(method: &str, target: &str) -> WebRequest {
+        let (path, raw_query) = match target.split_once('?') {
+            Some((p, q)) => (p, Some(q.to_string())),
+            None         => (target, None),
+        };
+        WebRequest {
+            method:    method.to_string(),
+            path:      path.to_string(),
+            query:     raw_query.as_ref().map(|q| parse_form(q)).unwrap_or_default(),
+            raw_query: raw_query,
+            headers:   BTreeMap::new(),
+            body:      Vec::new(),
+        }
+    }
+
+    /// Looks up a header by name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(|v| &v[..])
+    }
+
+    /// Adds a header value, joining it to any earlier value of the same
+    /// header.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        let name = name.to_ascii_lowercase();
+        let sep = if name == "cookie" { "; " } else { ", " };
+        let joined = match self.headers.get(&name) {
+            Some(v) => format!("{}{}{}", v, sep, value),
+            None    => value.to_string(),
+        };
+        self.headers.insert(name, joined);
+    }
+
+    /// The path and query string of the request.
+    pub fn target(&self) -> String {
+        match self.raw_query {
+            Some(ref q) => format!("{}?{}", self.path, q),
+            None        => self.path.clone(),
+        }
+    }
+}
+
+/// An HTTP response produced by a `WebHandler`.
+#[derive(Clone, Debug)]
+pub struct WebResponse {
+    pub status:       u16,
+    pub content_type: &'static str,
+    pub location:     Option<String>,
+    pub set_cookie:   Option<String>,
+    pub body:         String,
+}
+
+impl WebResponse {
+    pub fn json(status: u16, body: Value) -> WebResponse {
+        WebResponse {
+            status:       status,
+            content_type: "application/json",
+            location:     None,
+            set_cookie:   None,
+            body:         body.to_string(),
+        }
+    }
+
+    pub fn html(body: String) -> WebResponse {
+        WebResponse {
+            status:       200,
+            content_type: "text/html; charset=utf-8",
+            location:     None,
+            set_cookie:   None,
+            body:         body,
+        }
+    }
+
+    pub fn redirect(location: String) -> WebResponse {
+        WebResponse {
+            status:       302,
+            content_type: "text/plain",
+            location:     Some(location),
+            set_cookie:   None,
+            body:         String::new(),
+        }
+    }
+}
+
+/// What `LoginGuard::route` decided to do with a request.
+#[derive(Debug)]
+pub enum Route {
+    /// The guard answers the request itself.
+    Respond(WebResponse),
+    /// The request has a valid session and may be passed on.
+    Allow(Session),
+}
+
+/// Wraps a `WebHandler`, requiring a Tozny login.  See the module
+/// documentation.
+pub struct LoginGuard<F, H> {
+    flow:        F,
+    inner:       H,
+    sessions:    Mutex<SessionManager>,
+    pending:     Mutex<BTreeMap<String, PendingLogin>>,
+    max_pending: usize,
+    prefix:      String,
+    cookie_name: String,
+    secure:      bool,
+}
+
+/// A login page that has been served, and where to go once it completes.
+struct PendingLogin {
+    return_to:  String,
+    expires_at: Timestamp,
+}
+
+impl<F: LoginFlow, H> LoginGuard<F, H> {
+    /// `session_key` is a server-side secret used to sign session cookies.
+    pub fn new(flow: F, session_key: Secret, inner: H) -> LoginGuard<F, H> {
+        LoginGuard {
+            flow:        flow,
+            inner:       inner,
+            sessions:    Mutex::new(SessionManager::new(session_key)),
+            pending:     Mutex::new(BTreeMap::new()),
+            max_pending: 1000,
+            prefix:      "/tozny".to_string(),
+            cookie_name: DEFAULT_COOKIE_NAME.to_string(),
+            secure:      true,
+        }
+    }
+
+    /// Serves the guard's own routes under `prefix` instead of `/tozny`.
+    pub fn prefix(mut self, prefix: &str) -> LoginGuard<F, H> {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> LoginGuard<F, H> {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Omits the `Secure` attribute from the session cookie.  Only use this
+    /// for local development over plain HTTP.
+    pub fn insecure_cookies(mut self) -> LoginGuard<F, H> {
+        self.secure = false;
+        self
+    }
+
+    /// Maximum number of login pages that may wait for a Tozny login at
+    /// once.  Further requests for the login page are refused with status
+    /// 503 until some complete or expire.  Defaults to 1000.
+    pub fn max_pending(mut self, n: usize) -> LoginGuard<F, H> {
+        self.max_pending = n;
+        self
+    }
+
+    /// Decides whether to answer a request directly or to pass it on.
+    pub fn route(&self, req: &WebRequest) -> Route {
+        let login_path  = format!("{}/login", self.prefix);
+        let status_path = format!("{}/login/status", self.prefix);
+        let logout_path = format!("{}/logout", self.prefix);
+        if req.method == "GET" && req.path == login_path {
+            Route::Respond(self.login(req))
+        }
+        else if req.method == "GET" && req.path == status_path {
+            Route::Respond(self.status(req))
+        }
+        else if req.method == "POST" && req.path == logout_path {
+            Route::Respond(self.logout(req))
+        }
+        else if req.path == logout_path {
+            Route::Respond(error(405, "Use POST to log out."))
+        }
+        else {
+            match self.session(req) {
+                Some(session) => Route::Allow(session),
+                None => Route::Respond(WebResponse::redirect(
+                    format!("{}?return_to={}", login_path, url_encode(&req.target())))),
+            }
+        }
+    }
+
+    fn session(&self, req: &WebRequest) -> Option<Session> {
+        req.header("Cookie")
+            .and_then(|c| find_cookie(c, &self.cookie_name))
+            .and_then(|token| self.sessions.lock().unwrap().validate(&token).ok())
+    }
+
+    fn login(&self, req: &WebRequest) -> WebResponse {
+        let return_to = req.query.get("return_to")
+            .map(|r| &r[..])
+            .and_then(local_path)
+            .unwrap_or("/")
+            .to_string();
+        // Checked before contacting Tozny, so that anonymous requests cannot
+        // fill the table (or use up the realm's API quota) without bound.
+        {
+            let now = Utc::now();
+            let mut pending = self.pending.lock().unwrap();
+            pending.retain(|_, p| *p.expires_at.as_slice() > now);
+            if pending.len() >= self.max_pending {
+                return error(503, "Too many logins are in progress.  Try again later.")
+            }
+        }
+        let challenge = match self.flow.login_challenge() {
+            Ok(c)  => c,
+            Err(_) => return error(502, "Could not reach the Tozny service."),
+        };
+        let expires_at = Utc::now() + Duration::seconds(PENDING_LIFETIME_SECS);
+        self.pending.lock().unwrap().insert(challenge.session_id.as_slice().to_string(),
+                                            PendingLogin {
+                                                return_to:  return_to,
+                                                expires_at: Timestamp::new(expires_at),
+                                            });
+        WebResponse::html(login_page(challenge.session_id.as_slice(),
+                                     challenge.qr_url.as_str(),
+                                     challenge.mobile_url.as_str(),
+                                     &format!("{}/login/status", self.prefix)))
+    }
+
+    fn status(&self, req: &WebRequest) -> WebResponse {
+        let session_id = match req.query.get("session_id") {
+            Some(sid) if self.pending.lock().unwrap().contains_key(sid) => {
+                SessionId::from_slice(sid)
+            },
+            _ => return error(404, "Unknown login session."),
+        };
+        let question = match self.flow.check_session_status(&session_id) {
+            Ok(Some(q)) => q,
+            Ok(None)    => return status_response("pending", None),
+            Err(_)      => return error(502, "Could not reach the Tozny service."),
+        };
+        let return_to = match self.pending.lock().unwrap().remove(session_id.as_slice()) {
+            Some(ref p) if *p.expires_at.as_slice() <= Utc::now() => {
+                return error(404, "Unknown login session.")
+            },
+            Some(p) => p.return_to,
+            None    => return error(404, "Unknown login session."),
+        };
+        let login = match self.flow.verify_login(&question.signed_data, &question.signature) {
+            Ok(login) if login.session_id == session_id => login,
+            _ => return status_response("denied", None),
+        };
+        let token = match self.sessions.lock().unwrap().mint(&login) {
+            Ok(t)  => t,
+            Err(_) => return status_response("denied", None),
+        };
+        let mut resp = status_response("complete", Some(return_to));
+        resp.set_cookie = Some(format!("{}={}; Path=/; HttpOnly; SameSite=Lax{}",
+                                       self.cookie_name, token,
+                                       if self.secure { "; Secure" } else { "" }));
+        resp
+    }
+
+    fn logout(&self, req: &WebRequest) -> WebResponse {
+        if let Some(session) = self.session(req) {
+            self.sessions.lock().unwrap().revoke(&session);
+        }
+        let mut resp = WebResponse::redirect("/".to_string());
+        resp.set_cookie = Some(format!("{}=; Path=/; Max-Age=0", self.cookie_name));
+        resp
+    }
+}
+
+impl<F, H> WebHandler for LoginGuard<F, H>
+    where F: LoginFlow + Send + Sync, H: WebHandler {
+    /// Answers the guard's own routes and requests without a session, and
+    /// passes other requests to the wrapped handler.
+    fn handle(&self, mut req: WebRequest) -> WebResponse {
+        match self.route(&req) {
+            Route::Allow(session) => {
+                req.headers.insert(USER_ID_HEADER.to_ascii_lowercase(),
+                                   session.user_id.as_slice().to_string());
+                req.headers.insert(SESSION_ID_HEADER.to_ascii_lowercase(),
+                                   session.session_id.as_slice().to_string());
+                self.inner.handle(req)
+            },
+            Route::Respond(resp) => resp,
+        }
+    }
+}
+
+/// Only allows redirects to paths on the same site, so that the login page
+/// cannot be used as an open redirector.
+fn local_path(p: &str) -> Option<&str> {
+    if p.starts_with('/') && !p.starts_with("//") && !p.contains('\\') {
+        Some(p)
+    }
+    else {
+        None
+    }
+}
+
+fn find_cookie(header: &str, name: &str) -> Option<String> {
+    header.split(';')
+        .map(|pair| pair.trim())
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if k == name => Some(v.to_string()),
+                _                               => None,
+            }
+        })
+        .next()
+}
+
+fn error(status: u16, msg: &str) -> WebResponse {
+    WebResponse::json(status, json!({ "error": msg }))
+}
+
+fn status_response(status: &str, redirect: Option<String>) -> WebResponse {
+    let mut obj = json!({ "status": status });
+    if let Some(r) = redirect {
+        obj["redirect"] = Value::from(r);
+    }
+    WebResponse::json(200, obj)
+}
+
+/// Decodes an `application/x-www-form-urlencoded` string.
+pub fn parse_form(s: &str) -> BTreeMap<String, String> {
+    form_urlencoded::parse(s.as_bytes()).into_owned().collect()
+}
+
+/// Percent-encodes a string for use in a query parameter.
+pub fn url_encode(s: &str) -> String {
+    form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;")
+     .replace("\"", "&quot;").replace("'", "&#39;")
+}
+
+/// Renders a page that displays a QR code for a login challenge, and polls
+/// `status_url` until the login completes.  The status endpoint should
+/// respond with `{"status": "pending"}` while waiting, and with
+/// `{"status": "complete", "redirect": "..."}` once the login succeeds.
+pub fn login_page(session_id: &str, qr_url: &str, mobile_url: &str, status_url: &str) -> String {
+    format!(r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Log in with Tozny</title></head>
+<body data-session="{session}" data-status="{status}">
+<p>Scan this code with the Tozny app to log in.</p>
+<p><a href="{mobile}"><img src="{qr}" alt="Tozny QR code"></a></p>
+<p id="status"></p>
+<script>
+(function poll() {{
+  var body = document.body;
+  var xhr = new XMLHttpRequest();
+  xhr.open("GET", body.getAttribute("data-status") + "?session_id=" + encodeURIComponent(body.getAttribute("data-session")));
+  xhr.onload = function () {{
+    var resp = JSON.parse(xhr.responseText);
+    if (resp.status === "complete") {{ window.location = resp.redirect; }}
+    else if (resp.status === "pending") {{ setTimeout(poll, 2000); }}
+    else {{ document.getElementById("status").textContent = "Login failed."; }}
+  }};
+  xhr.onerror = function () {{ setTimeout(poll, 5000); }};
+  xhr.send();
+}})();
+</script>
+</body>
+</html>
+"#, session = html_escape(session_id), status = html_escape(status_url),
+        qr = html_escape(qr_url), mobile = html_escape(mobile_url))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use serde_json::{Value};
+
+    use super::*;
+    use protocol::{Secret, SessionId, UserId};
+    use testing::FakeTozny;
+    use url::form_urlencoded;
+
+    fn guard() -> LoginGuard<FakeTozny, ()> {
+        LoginGuard::new(FakeTozny::new(), Secret::from_slice("cookie key"), ())
+    }
+
+    fn get(path: &str, query: Vec<(&str, &str)>, cookie: Option<String>) -> WebRequest {
+        let target = if query.is_empty() {
+            path.to_string()
+        }
+        else {
+            format!("{}?{}", path,
+                    form_urlencoded::Serializer::new(String::new()).extend_pairs(&query).finish())
+        };
+        let mut req = WebRequest::new("GET", &target);
+        if let Some(c) = cookie {
+            req.add_header("Cookie", &c);
+        }
+        req
+    }
+
+    fn post(path: &str, cookie: Option<String>) -> WebRequest {
+        let mut req = get(path, vec![], cookie);
+        req.method = "POST".to_string();
+        req
+    }
+
+    /// Serves the login page, and returns the session id that it polls for.
+    fn login_session<H>(guard: &LoginGuard<FakeTozny, H>, return_to: &str) -> String {
+        let page = respond(guard.route(&get("/tozny/login", vec![("return_to", return_to)], None)));
+        assert_eq!(page.status, 200);
+        let start = page.body.find("data-session=\"").unwrap() + 14;
+        let end = start + page.body[start..].find('"').unwrap();
+        page.body[start..end].to_string()
+    }
+
+    fn respond(route: Route) -> WebResponse {
+        match route {
+            Route::Respond(resp) => resp,
+            Route::Allow(s)      => panic!("expected a response, got session {:?}", s),
+        }
+    }
+
+    #[test]
+    fn it_redirects_anonymous_requests_to_the_login_page() {
+        let resp = respond(guard().route(&get("/reports", vec![], None)));
+        assert_eq!(resp.status, 302);
+        assert_eq!(resp.location, Some("/tozny/login?return_to=%2Freports".to_string()));
+    }
+
+    #[test]
+    fn it_logs_in_and_allows_requests_with_the_session_cookie() {
+        let guard = guard();
+        let sid = login_session(&guard, "/reports");
+
+        let status = get("/tozny/login/status", vec![("session_id", &sid)], None);
+        let pending = respond(guard.route(&status));
+        assert!(pending.set_cookie.is_none());
+
+        guard.flow.confirm(&SessionId::new(sid.clone()), &UserId::from_slice("sid_alice"), "Alice");
+        let done = respond(guard.route(&status));
+        let js: Value = serde_json::from_str(&done.body).unwrap();
+        assert_eq!(js["redirect"].as_str(), Some("/reports"));
+        let cookie = done.set_cookie.unwrap();
+        assert!(cookie.contains("HttpOnly") && cookie.contains("Secure"));
+        let pair = cookie.split(';').next().unwrap().to_string();
+
+        let cookie_header = format!("theme=dark; {}", pair);
+        match guard.route(&get("/reports", vec![], Some(cookie_header.clone()))) {
+            Route::Allow(session) => assert_eq!(session.user_id, UserId::from_slice("sid_alice")),
+            Route::Respond(r)     => panic!("expected access, got {:?}", r),
+        }
+
+        let refused = respond(guard.route(&get("/tozny/logout", vec![], Some(cookie_header.clone()))));
+        assert_eq!(refused.status, 405);
+        match guard.route(&get("/reports", vec![], Some(cookie_header.clone()))) {
+            Route::Allow(_)   => (),
+            Route::Respond(r) => panic!("GET must not log out, got {:?}", r),
+        }
+
+        respond(guard.route(&post("/tozny/logout", Some(cookie_header.clone()))));
+        assert_eq!(respond(guard.route(&get("/reports", vec![], Some(cookie_header)))).status, 302);
+    }
+
+    /// Echoes the user id header that the guard sets.
+    struct Echo;
+
+    impl WebHandler for Echo {
+        fn handle(&self, req: WebRequest) -> WebResponse {
+            WebResponse::html(req.header(USER_ID_HEADER).unwrap_or("none").to_string())
+        }
+    }
+
+    #[test]
+    fn it_passes_logged_in_requests_to_the_wrapped_handler() {
+        let guard = LoginGuard::new(FakeTozny::new(), Secret::from_slice("cookie key"), Echo);
+        let mut forged = get("/reports", vec![], None);
+        forged.add_header(USER_ID_HEADER, "sid_mallory");
+        assert_eq!(guard.handle(forged.clone()).status, 302);
+
+        let sid = login_session(&guard, "/reports");
+        guard.flow.confirm(&SessionId::new(sid.clone()), &UserId::from_slice("sid_alice"), "Alice");
+        let done = guard.handle(get("/tozny/login/status", vec![("session_id", &sid)], None));
+        let cookie = done.set_cookie.unwrap();
+        forged.add_header("Cookie", cookie.split(';').next().unwrap());
+        assert_eq!(guard.handle(forged).body, "sid_alice");
+    }
+
+    #[test]
+    fn it_refuses_to_redirect_off_site() {
+        let guard = guard();
+        let req = WebRequest::new("GET", "/tozny/login?return_to=%2F%2Fevil.example.com%2F");
+        assert_eq!(req.query.get("return_to").map(|r| &r[..]), Some("//evil.example.com/"));
+        let page = respond(guard.route(&req));
+        assert_eq!(page.status, 200);
+        assert_eq!(guard.pending.lock().unwrap().values().next().map(|p| &p.return_to[..]),
+                   Some("/"));
+    }
+
+    #[test]
+    fn it_returns_to_the_query_string_that_was_asked_for() {
+        let resp = respond(guard().route(&get("/reports", vec![("year", "2015"), ("q", "a&b")],
+                                              None)));
+        assert_eq!(resp.location,
+                   Some("/tozny/login?return_to=%2Freports%3Fyear%3D2015%26q%3Da%2526b".to_string()));
+
+        let guard = guard();
+        let sid = login_session(&guard, "/reports?year=2015&q=a%26b");
+        guard.flow.confirm(&SessionId::new(sid.clone()), &UserId::from_slice("sid_alice"), "Alice");
+        let done = respond(guard.route(&get("/tozny/login/status", vec![("session_id", &sid)], None)));
+        let js: Value = serde_json::from_str(&done.body).unwrap();
+        assert_eq!(js["redirect"].as_str(), Some("/reports?year=2015&q=a%26b"));
+    }
+
+    #[test]
+    fn it_forgets_expired_logins_and_limits_pending_ones() {
+        let guard = guard().max_pending(2);
+        let sid = login_session(&guard, "/");
+        login_session(&guard, "/");
+        let refused = respond(guard.route(&get("/tozny/login", vec![], None)));
+        assert_eq!(refused.status, 503);
+
+        for p in guard.pending.lock().unwrap().values_mut() {
+            p.expires_at = Timestamp::new(Utc::now() - Duration::seconds(1));
+        }
+        login_session(&guard, "/");
+        assert_eq!(guard.pending.lock().unwrap().len(), 1);
+        let status = get("/tozny/login/status", vec![("session_id", &sid)], None);
+        assert_eq!(respond(guard.route(&status)).status, 404);
+    }
+}
@@ -0,0 +1,92 @@
+//! Realm configuration for the `tozny` command.
+//!
+//! Realms are read from a JSON file, by default `~/.config/tozny/realms.json`
+//! (override with `--config` or the `TOZNY_CONFIG` environment variable):
+//!
+//! ```json
+//! {
+//!   "default": "production",
+//!   "realms": {
+//!     "production": {
+//!       "realm_key_id": "sid_123456789",
+//!       "realm_secret": "...",
+//!       "api_url":      "https://api.tozny.com/index.php"
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! `realm_secret` may be omitted; commands that make realm-level calls will
+//! then refuse to run.
+
+use serde_json;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use url::Url;
+
+use tozny_auth::protocol::{KeyId, Secret};
+use tozny_auth::{Realm, UserApi};
+
+#[derive(Debug, Deserialize)]
+pub struct RealmConfig {
+    pub realm_key_id: KeyId,
+    pub realm_secret: Option<Secret>,
+    pub api_url:      String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub default: Option<String>,
+    pub realms:  BTreeMap<String, RealmConfig>,
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        match env::var("TOZNY_CONFIG") {
+            Ok(path) => PathBuf::from(path),
+            Err(_)   => {
+                let home = env::home_dir().unwrap_or(PathBuf::from("."));
+                home.join(".config").join("tozny").join("realms.json")
+            },
+        }
+    }
+
+    pub fn load(path: &PathBuf) -> Result<Config, String> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        serde_json::from_str(&contents)
+            .map_err(|err| format!("Invalid configuration in {}: {}", path.display(), err))
+    }
+
+    /// Picks the named realm, or the default realm if no name is given.
+    pub fn realm(&self, name: Option<&str>) -> Result<&RealmConfig, String> {
+        let name = match name.or(self.default.as_ref().map(|d| &d[..])) {
+            Some(n) => n,
+            None if self.realms.len() == 1 => return Ok(self.realms.values().next().unwrap()),
+            None => return Err("No realm selected; use --realm or set a default.".to_string()),
+        };
+        self.realms.get(name).ok_or(format!("No realm named {} in configuration.", name))
+    }
+}
+
+impl RealmConfig {
+    pub fn api_url(&self) -> Result<Url, String> {
+        Url::parse(&self.api_url).map_err(|err| format!("Invalid api_url: {}", err))
+    }
+
+    pub fn user_api(&self) -> Result<UserApi, String> {
+        self.api_url().map(|url| UserApi::new(self.realm_key_id.clone(), url))
+    }
+
+    pub fn realm(&self) -> Result<Realm, String> {
+        let secret = self.realm_secret.clone()
+                     .ok_or("This command requires realm_secret in the configuration."
+                            .to_string())?;
+        self.api_url().map(|url| Realm::new(self.realm_key_id.clone(), secret, url))
+    }
+}
@@ -0,0 +1,227 @@
+//! `tozny`: command-line access to the Tozny API, for realm administration and
+//! for testing logins.
+//!
+//! Run `tozny help` for usage.  See the `config` module for the format of the
+//! realm configuration file.
+
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate tozny_auth;
+extern crate url;
+
+use serde::Serialize;
+use serde_json::{Value};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, io, process, thread};
+
+use tozny_auth::protocol::{Method, Newtype, Presence, SessionId, Timestamp, UserId};
+use tozny_auth::question::{from_json};
+
+use config::{Config, RealmConfig};
+
+mod config;
+
+const USAGE: &'static str = "\
+Usage: tozny [options] <command> [arguments]
+
+Commands:
+    login                              Display a QR code and wait for a login
+    push <session_id> <presence>       Send a push notification for a session
+    user get <user_id>                 Look up a user
+    check-login <user_id> <session_id> <expires_at>
+                                       Ask Tozny whether a login is valid
+    verify <signed_data> <signature>   Verify a signed login locally
+    raw <method> <json>                Make an arbitrary realm-level API call
+
+Options:
+    --config <path>    Realm configuration file
+    --realm <name>     Realm to use, if the configuration lists several
+    --json             Print results as JSON
+    --timeout <secs>   How long `login` waits for confirmation (default 120)
+";
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Format {
+    Human,
+    Json,
+}
+
+struct Options {
+    config:  PathBuf,
+    realm:   Option<String>,
+    format:  Format,
+    timeout: u32,
+    args:    Vec<String>,
+}
+
+fn parse_options(mut argv: Vec<String>) -> Result<Options, String> {
+    let mut opts = Options {
+        config:  Config::default_path(),
+        realm:   None,
+        format:  Format::Human,
+        timeout: 120,
+        args:    Vec::new(),
+    };
+    argv.reverse();
+    while let Some(arg) = argv.pop() {
+        match &arg[..] {
+            "--json"    => opts.format = Format::Json,
+            "--config"  => opts.config = PathBuf::from(option_value(&mut argv, &arg)?),
+            "--realm"   => opts.realm = Some(option_value(&mut argv, &arg)?),
+            "--timeout" => {
+                let v = option_value(&mut argv, &arg)?;
+                opts.timeout = v.parse().map_err(|_| format!("Invalid timeout: {}", v))?;
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => opts.args.push(arg),
+        }
+    }
+    Ok(opts)
+}
+
+fn option_value(argv: &mut Vec<String>, name: &str) -> Result<String, String> {
+    argv.pop().ok_or(format!("{} requires a value", name))
+}
+
+/// Prints a result.  In human-readable format, the top-level fields of an
+/// object are printed one per line.
+fn print<T: Serialize>(value: &T, format: Format) -> Result<(), String> {
+    let js = serde_json::to_value(value).map_err(|e| e.to_string())?;
+    match (format, js) {
+        (Format::Json, js) => println!("{}", js),
+        (Format::Human, Value::Object(obj)) => {
+            for (k, v) in obj.iter() {
+                match *v {
+                    Value::String(ref s) => println!("{}: {}", k, s),
+                    ref other            => println!("{}: {}", k, other),
+                }
+            }
+        },
+        (Format::Human, Value::String(s)) => println!("{}", s),
+        (Format::Human, js) => {
+            let pretty = serde_json::to_string_pretty(&js).map_err(|e| e.to_string())?;
+            println!("{}", pretty)
+        },
+    }
+    Ok(())
+}
+
+fn login(realm: &RealmConfig, opts: &Options) -> Result<(), String> {
+    let api = realm.user_api()?;
+    let challenge = api.login_challenge().map_err(|e| e.to_string())?;
+    let qr = challenge.qr_code().map_err(|e| e.to_string())?;
+    if opts.format == Format::Human {
+        println!("{}", qr.to_unicode_inverted());
+        println!("Scan the code above with the Tozny app.");
+        println!("session_id: {}", challenge.session_id.as_slice());
+        println!("presence: {}", challenge.presence.as_slice());
+    }
+    for attempt in 0..opts.timeout {
+        if attempt > 0 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        let status = api.check_session_status(&challenge.session_id)
+                     .map_err(|e| e.to_string())?;
+        if let Some(question) = status {
+            return match realm.realm() {
+                Ok(r) => {
+                    let login = r.verify_login(&question.signed_data, &question.signature)
+                                .map_err(|e| e.to_string())?;
+                    print(&login, opts.format)
+                },
+                // Without a realm secret the result cannot be verified, so
+                // print the signed result as-is.
+                Err(_) => print(&question, opts.format),
+            }
+        }
+    }
+    Err("Timed out waiting for the login to be confirmed.".to_string())
+}
+
+fn run(opts: &Options) -> Result<(), String> {
+    let args: Vec<&str> = opts.args.iter().map(|a| &a[..]).collect();
+    if args.is_empty() || args[0] == "help" {
+        print!("{}", USAGE);
+        return Ok(())
+    }
+    let config = Config::load(&opts.config)?;
+    let realm = config.realm(opts.realm.as_ref().map(|r| &r[..]))?;
+    match &args[..] {
+        ["login"] => login(realm, opts),
+        ["push", sid, presence] => {
+            let api = realm.user_api()?;
+            api.push(&SessionId::from_slice(sid), &Presence::from_slice(presence))
+                .map_err(|e| e.to_string())?;
+            print(&"Push notification sent.", opts.format)
+        },
+        ["user", "get", uid] => {
+            let r = realm.realm()?;
+            let user = r.user_get(&UserId::from_slice(uid)).map_err(|e| e.to_string())?;
+            print(&user, opts.format)
+        },
+        ["check-login", uid, sid, expires_at] => {
+            let r = realm.realm()?;
+            let seconds: i64 = expires_at.parse()
+                               .map_err(|_| format!("Invalid expires_at: {}", expires_at))?;
+            let expires_at: Timestamp = from_json(&Value::from(seconds))
+                                        .map_err(|e| e.to_string())?;
+            let valid = r.check_valid_login(&UserId::from_slice(uid),
+                                            &SessionId::from_slice(sid),
+                                            &expires_at)
+                        .map_err(|e| e.to_string())?;
+            print(&valid, opts.format)
+        },
+        ["verify", signed_data, signature] => {
+            let r = realm.realm()?;
+            let login = r.verify_login(signed_data, signature).map_err(|e| e.to_string())?;
+            print(&login, opts.format)
+        },
+        ["raw", method, params] => {
+            let r = realm.realm()?;
+            let params = match serde_json::from_str(params).map_err(|e| e.to_string())? {
+                Value::Object(obj) => obj,
+                _ => return Err("Parameters must be a JSON object.".to_string()),
+            };
+            let resp = r.raw_call(&Method::from_slice(method), &params)
+                       .map_err(|e| e.to_string())?;
+            print(&resp, opts.format)
+        },
+        _ => Err(format!("Unrecognized command.\n\n{}", USAGE)),
+    }
+}
+
+fn main() {
+    let result = parse_options(env::args().skip(1).collect()).and_then(|opts| run(&opts));
+    if let Err(msg) = result {
+        let _ = writeln!(io::stderr(), "tozny: {}", msg);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_options, Format};
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn it_separates_options_from_arguments() {
+        let opts = parse_options(argv(&["--realm", "dev", "user", "get", "--json", "sid_1"]))
+            .ok().unwrap();
+        assert_eq!(opts.realm, Some("dev".to_string()));
+        assert!(opts.format == Format::Json);
+        assert_eq!(opts.args, argv(&["user", "get", "sid_1"]));
+    }
+
+    #[test]
+    fn it_rejects_unknown_options_and_missing_values() {
+        assert!(parse_options(argv(&["--verbose"])).is_err());
+        assert!(parse_options(argv(&["login", "--timeout"])).is_err());
+        assert!(parse_options(argv(&["login", "--timeout", "soon"])).is_err());
+    }
+}
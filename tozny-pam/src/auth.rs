@@ -0,0 +1,361 @@
+//! The authentication procedure, independent of the PAM calling convention.
+
+use std::fmt;
+use std::sync::Arc;
+
+use tozny_auth::audit::JsonLinesSink;
+use tozny_auth::flow;
+use tozny_auth::flow::{LoginFlow, ToznyLoginFlow, VerifierLoginFlow};
+use tozny_auth::login::Login;
+use tozny_auth::mapping;
+use tozny_auth::mapping::{MappingError, MetaFieldMapping, StaticMapping, UserMapping};
+use tozny_auth::presence::{FilePresenceStore, MemoryPresenceStore, PresenceError, PresenceStore};
+use tozny_auth::question::QuestionError;
+use tozny_auth::{LoginVerifier, Realm, UserApi};
+
+use config::{Config, ConfigError};
+use conv::Conversation;
+
+/// Logs in the local user `username` against the Tozny realm described by
+/// `config`, using the configured user mapping, remembering devices in the
+/// configured presence store, and recording events in the configured audit
+/// log.
+///
+/// Logins are verified with the realm secret if the configuration has one,
+/// and otherwise with the public keys published at `jwks_url`.
+pub fn authenticate_with_config<C: Conversation>(config:   &Config,
+                                                 conv:     &mut C,
+                                                 username: &str) -> Result<Login, AuthError> {
+    let api_url = config.api_url().map_err(AuthError::ConfigError)?;
+    let mut user_api = UserApi::new(config.realm_key_id.clone(), api_url.clone());
+    let sink = match config.audit_log {
+        Some(ref path) => Some(Arc::new(JsonLinesSink::open(path).map_err(|e| {
+            AuthError::ConfigError(ConfigError::AuditLogError(e))
+        })?)),
+        None => None,
+    };
+    if let Some(ref sink) = sink {
+        user_api = user_api.with_audit(sink.clone());
+    }
+    match config.realm_secret {
+        Some(ref secret) => {
+            let realm = || Realm::new(config.realm_key_id.clone(), secret.clone(),
+                                      api_url.clone());
+            let mut flow_realm = realm();
+            if let Some(sink) = sink {
+                flow_realm = flow_realm.with_audit(sink);
+            }
+            let flow = ToznyLoginFlow::new(user_api, flow_realm);
+            match config.username_field {
+                Some(ref field) => {
+                    let mapping = MetaFieldMapping::new(realm(), field);
+                    authenticate_with_store(config, &flow, conv, &mapping, username)
+                },
+                None => authenticate_with_users(config, &flow, conv, username),
+            }
+        },
+        None => {
+            let jwks_url = config.jwks_url().map_err(AuthError::ConfigError)?;
+            let flow = VerifierLoginFlow::new(user_api, LoginVerifier::from_jwks_url(jwks_url));
+            match config.username_field {
+                Some(_) => Err(AuthError::ConfigError(ConfigError::SecretRequired)),
+                None    => authenticate_with_users(config, &flow, conv, username),
+            }
+        },
+    }
+}
+
+fn authenticate_with_users<F, C>(config:   &Config,
+                                 flow:     &F,
+                                 conv:     &mut C,
+                                 username: &str) -> Result<Login, AuthError>
+    where F: LoginFlow, C: Conversation {
+    match config.users {
+        Some(ref users) => {
+            let mapping = StaticMapping::new(users.clone());
+            authenticate_with_store(config, flow, conv, &mapping, username)
+        },
+        None => Err(AuthError::ConfigError(ConfigError::NoUserMapping)),
+    }
+}
+
+fn authenticate_with_store<F, C, M>(config:   &Config,
+                                    flow:     &F,
+                                    conv:     &mut C,
+                                    mapping:  &M,
+                                    username: &str) -> Result<Login, AuthError>
+    where F: LoginFlow, C: Conversation, M: UserMapping {
+    let timeout = config.timeout_secs();
+    match config.presence_store {
+        Some(ref path) => {
+            let mut store = FilePresenceStore::new(path);
+            authenticate(flow, conv, &mut store, mapping, username, timeout)
+        },
+        None => {
+            let mut store = MemoryPresenceStore::new();
+            authenticate(flow, conv, &mut store, mapping, username, timeout)
+        },
+    }
+}
+
+/// Logs in the local user `username`.
+///
+/// If a presence value is stored for the user, a push notification is sent to
+/// their device; a QR code is displayed either way.  The module then waits for
+/// the user to press Enter, and polls for up to `timeout_secs` seconds for the
+/// login to be confirmed.  The signed result must verify, and must belong to
+/// a Tozny user that `mapping` maps to `username` and to no other account.
+pub fn authenticate<F, C, S, M>(flow:         &F,
+                                conv:         &mut C,
+                                store:        &mut S,
+                                mapping:      &M,
+                                username:     &str,
+                                timeout_secs: u32) -> Result<Login, AuthError>
+    where F: LoginFlow, C: Conversation, S: PresenceStore, M: UserMapping {
+    if !mapping::is_valid_account_name(username) {
+        return Err(AuthError::MappingError(MappingError::InvalidName(username.to_string())))
+    }
+    let challenge = flow.login_challenge().map_err(AuthError::QuestionError)?;
+
+    // Devices are remembered under the local account name.  Fall back to the
+    // Tozny user id, in case the store is shared with another integration.
+    let stored = match mapping::find_presence(mapping, store, username) {
+        Ok((_, presence)) => presence,
+        Err(_)            => store.get(username).map_err(AuthError::PresenceError)?,
+    };
+    let pushed = match stored {
+        Some(presence) => flow.push(&challenge.session_id, &presence).is_ok(),
+        None           => false,
+    };
+    if pushed {
+        conv.info("A login request has been sent to your phone.")
+            .map_err(|_| AuthError::ConversationError)?;
+    }
+    let qr = challenge.qr_code().map_err(|_| AuthError::QrError)?;
+    conv.info(&format!("Scan this code with the Tozny app:\n{}", qr.to_unicode_inverted()))
+        .map_err(|_| AuthError::ConversationError)?;
+    conv.prompt("Press Enter after approving the login in the Tozny app.")
+        .map_err(|_| AuthError::ConversationError)?;
+
+    // The presence value is only remembered once the login is known to
+    // belong to this account, so `flow::login_with_push` cannot be used.
+    let login = match flow::wait_for_login(flow, &challenge, timeout_secs)
+                      .map_err(AuthError::QuestionError)? {
+        Some(login) => login,
+        None        => return Err(AuthError::Timeout),
+    };
+    match mapping::map_to_local(mapping, &login.user_id) {
+        Ok(ref name) if name == username    => (),
+        Ok(_)                               => return Err(AuthError::WrongUser),
+        Err(MappingError::NotFound)         => return Err(AuthError::UnknownUser),
+        Err(err)                            => return Err(AuthError::MappingError(err)),
+    }
+    // The account must not be claimed by any other Tozny user either.
+    // Directories that cannot search are trusted on the forward lookup alone.
+    match mapping::map_to_user_id(mapping, username) {
+        Ok(ref user_id) if *user_id == login.user_id => (),
+        Ok(_)                                        => return Err(AuthError::WrongUser),
+        Err(MappingError::Unsupported)               => (),
+        Err(err)                                     => return Err(AuthError::MappingError(err)),
+    }
+    store.put(username, challenge.presence).map_err(AuthError::PresenceError)?;
+    Ok(login)
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    ConfigError(ConfigError),
+    QuestionError(QuestionError),
+    PresenceError(PresenceError),
+    MappingError(MappingError),
+    ConversationError,
+    QrError,
+    Timeout,
+    UnknownUser,
+    WrongUser,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &AuthError::ConfigError(ref err) => fmt::Display::fmt(err, f),
+            &AuthError::QuestionError(ref err) => fmt::Display::fmt(err, f),
+            &AuthError::PresenceError(ref err) => fmt::Display::fmt(err, f),
+            &AuthError::MappingError(ref err) => fmt::Display::fmt(err, f),
+            &AuthError::ConversationError => f.write_str("Conversation with the user failed."),
+            &AuthError::QrError => f.write_str("Could not render QR code."),
+            &AuthError::Timeout => f.write_str("Login was not approved in time."),
+            &AuthError::UnknownUser => f.write_str("Tozny user is not mapped to a local account."),
+            &AuthError::WrongUser => f.write_str("Tozny user is mapped to a different account."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tozny_auth::mapping::{MappingError, StaticMapping};
+    use tozny_auth::presence::{MemoryPresenceStore, PresenceStore};
+    use tozny_auth::protocol::{KeyId, Presence, UserId};
+    use tozny_auth::testing::FakeTozny;
+
+    use super::*;
+    use conv::Conversation;
+
+    /// Conversation stub that approves every pending login as the given Tozny
+    /// user when it is prompted.
+    struct StubConversation<'a> {
+        tozny:    &'a FakeTozny,
+        approver: Option<&'static str>,
+        messages: Vec<String>,
+    }
+
+    impl<'a> Conversation for StubConversation<'a> {
+        fn info(&mut self, msg: &str) -> Result<(), ()> {
+            self.messages.push(msg.to_string());
+            Ok(())
+        }
+
+        fn error(&mut self, msg: &str) -> Result<(), ()> {
+            self.messages.push(msg.to_string());
+            Ok(())
+        }
+
+        fn prompt(&mut self, msg: &str) -> Result<String, ()> {
+            self.messages.push(msg.to_string());
+            if let Some(uid) = self.approver {
+                for sid in self.tozny.pending_sessions() {
+                    self.tozny.confirm(&sid, &UserId::from_slice(uid), "Alice");
+                }
+            }
+            Ok(String::new())
+        }
+    }
+
+    fn users() -> StaticMapping {
+        let mut users = BTreeMap::new();
+        users.insert("sid_alice".to_string(), "alice".to_string());
+        users.insert("sid_bob".to_string(), "bob".to_string());
+        users.insert("sid_carol".to_string(), "carol".to_string());
+        users.insert("sid_carol2".to_string(), "carol".to_string());
+        StaticMapping::new(users)
+    }
+
+    fn conversation<'a>(tozny: &'a FakeTozny, approver: Option<&'static str>
+                        ) -> StubConversation<'a> {
+        StubConversation { tozny: tozny, approver: approver, messages: Vec::new() }
+    }
+
+    #[test]
+    fn it_authenticates_a_mapped_user_with_a_qr_code() {
+        let tozny = FakeTozny::new();
+        let mut conv = conversation(&tozny, Some("sid_alice"));
+        let mut store = MemoryPresenceStore::new();
+        let login = authenticate(&tozny, &mut conv, &mut store, &users(), "alice", 1).unwrap();
+        assert_eq!(login.user_id, UserId::from_slice("sid_alice"));
+        assert!(conv.messages[0].contains('\u{2588}'));
+        assert!(tozny.pushes().is_empty());
+        let presence = tozny.presence_for(&login.session_id);
+        assert_eq!(store.get("alice").unwrap(), presence);
+    }
+
+    #[test]
+    fn it_pushes_to_a_stored_presence() {
+        let tozny = FakeTozny::new();
+        let mut conv = conversation(&tozny, Some("sid_alice"));
+        let mut store = MemoryPresenceStore::new();
+        store.put("alice", Presence::from_slice("old_presence")).unwrap();
+        authenticate(&tozny, &mut conv, &mut store, &users(), "alice", 1).unwrap();
+        let pushes = tozny.pushes();
+        assert_eq!(pushes.len(), 1);
+        assert_eq!(pushes[0].1, Presence::from_slice("old_presence"));
+    }
+
+    #[test]
+    fn it_rejects_a_tozny_user_mapped_to_another_account() {
+        let tozny = FakeTozny::new();
+        let mut conv = conversation(&tozny, Some("sid_bob"));
+        let mut store = MemoryPresenceStore::new();
+        match authenticate(&tozny, &mut conv, &mut store, &users(), "alice", 1) {
+            Err(AuthError::WrongUser) => (),
+            r => panic!("expected wrong user, got {:?}", r),
+        }
+        assert!(store.get("alice").unwrap().is_none());
+    }
+
+    #[test]
+    fn it_rejects_unmapped_tozny_users() {
+        let tozny = FakeTozny::new();
+        let mut conv = conversation(&tozny, Some("sid_mallory"));
+        let mut store = MemoryPresenceStore::new();
+        match authenticate(&tozny, &mut conv, &mut store, &users(), "alice", 1) {
+            Err(AuthError::UnknownUser) => (),
+            r => panic!("expected unknown user, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_times_out_when_the_login_is_not_approved() {
+        let tozny = FakeTozny::new();
+        let mut conv = conversation(&tozny, None);
+        let mut store = MemoryPresenceStore::new();
+        match authenticate(&tozny, &mut conv, &mut store, &users(), "alice", 1) {
+            Err(AuthError::Timeout) => (),
+            r => panic!("expected timeout, got {:?}", r),
+        }
+        assert_eq!(tozny.pending_sessions().len(), 1);
+    }
+
+    #[test]
+    fn it_rejects_ambiguous_mappings() {
+        let tozny = FakeTozny::new();
+        let mut conv = conversation(&tozny, Some("sid_carol"));
+        let mut store = MemoryPresenceStore::new();
+        match authenticate(&tozny, &mut conv, &mut store, &users(), "carol", 1) {
+            Err(AuthError::MappingError(MappingError::Ambiguous(_))) => (),
+            r => panic!("expected ambiguous mapping, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_pushes_to_a_presence_stored_under_the_tozny_user_id() {
+        let tozny = FakeTozny::new();
+        let mut conv = conversation(&tozny, Some("sid_alice"));
+        let mut store = MemoryPresenceStore::new();
+        store.put("sid_alice", Presence::from_slice("shared_presence")).unwrap();
+        authenticate(&tozny, &mut conv, &mut store, &users(), "alice", 1).unwrap();
+        assert_eq!(tozny.pushes()[0].1, Presence::from_slice("shared_presence"));
+    }
+
+    fn config_without_secret() -> Config {
+        Config {
+            realm_key_id:   KeyId::from_slice("sid_123"),
+            realm_secret:   None,
+            api_url:        "http://127.0.0.1:1/".to_string(),
+            jwks_url:       None,
+            presence_store: None,
+            audit_log:      None,
+            timeout_secs:   Some(1),
+            users:          None,
+            username_field: None,
+        }
+    }
+
+    #[test]
+    fn it_requires_a_jwks_url_or_secret_and_a_static_mapping_without_a_secret() {
+        let tozny = FakeTozny::new();
+        let mut conv = conversation(&tozny, None);
+        let mut config = config_without_secret();
+        match authenticate_with_config(&config, &mut conv, "alice") {
+            Err(AuthError::ConfigError(ConfigError::NoVerifier)) => (),
+            r => panic!("expected missing verifier, got {:?}", r),
+        }
+        config.jwks_url = Some("http://127.0.0.1:1/jwks".to_string());
+        config.username_field = Some("username".to_string());
+        match authenticate_with_config(&config, &mut conv, "alice") {
+            Err(AuthError::ConfigError(ConfigError::SecretRequired)) => (),
+            r => panic!("expected secret required, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Configuration file for the PAM module.
+//!
+//! The file is JSON, for example:
+//!
+//! ```json
+//! {
+//!   "realm_key_id":   "sid_123456789",
+//!   "realm_secret":   "...",
+//!   "api_url":        "https://api.tozny.com/index.php",
+//!   "presence_store": "/var/lib/tozny/presence.json",
+//!   "audit_log":      "/var/log/tozny/audit.jsonl",
+//!   "timeout_secs":   60,
+//!   "users":          { "sid_abcdef": "alice" }
+//! }
+//! ```
+//!
+//! `users` maps Tozny user ids to local account names.  Alternatively,
+//! `"username_field": "username"` reads the account name from that field of
+//! the Tozny user's `meta` record; see `tozny_auth::mapping`.  The Tozny API
+//! cannot search by that field, so with `username_field` push notifications
+//! only go to devices remembered under the local account name.
+//!
+//! Logins are verified with `realm_secret`.  A configuration that is read by
+//! an unprivileged process, such as `tozny-ssh-auth`, must leave the secret
+//! out and set `jwks_url` instead, so that logins are verified with Tozny's
+//! public keys (see `tozny_auth::verifier`).  `username_field` needs the
+//! secret, so such a configuration must use `users`.
+
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::{fmt, io};
+use url::{Url, ParseError};
+
+use tozny_auth::protocol::{KeyId, Secret};
+
+/// Path used when the module is not given a `config=` argument.
+pub const DEFAULT_PATH: &'static str = "/etc/tozny/pam.json";
+
+/// How long to wait for the user to confirm a login, unless configured.
+pub const DEFAULT_TIMEOUT_SECS: u32 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub realm_key_id:   KeyId,
+    pub realm_secret:   Option<Secret>,
+    pub api_url:        String,
+    pub jwks_url:       Option<String>,
+    pub presence_store: Option<String>,
+    pub audit_log:      Option<String>,
+    pub timeout_secs:   Option<u32>,
+    pub users:          Option<BTreeMap<String, String>>,
+    pub username_field: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(ConfigError::IoError)?;
+        serde_json::from_str(&contents).map_err(ConfigError::DecoderError)
+    }
+
+    pub fn api_url(&self) -> Result<Url, ConfigError> {
+        Url::parse(&self.api_url).map_err(ConfigError::UrlError)
+    }
+
+    pub fn jwks_url(&self) -> Result<Url, ConfigError> {
+        match self.jwks_url {
+            Some(ref url) => Url::parse(url).map_err(ConfigError::JwksUrlError),
+            None          => Err(ConfigError::NoVerifier),
+        }
+    }
+
+    pub fn timeout_secs(&self) -> u32 {
+        self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(io::Error),
+    DecoderError(serde_json::Error),
+    UrlError(ParseError),
+    JwksUrlError(ParseError),
+    NoUserMapping,
+    NoVerifier,
+    SecretRequired,
+    AuditLogError(io::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &ConfigError::IoError(ref err) => {
+                f.write_fmt(format_args!("Error reading configuration: {}", err))
+            },
+            &ConfigError::DecoderError(ref err) => {
+                f.write_fmt(format_args!("Error in configuration: {}", err))
+            },
+            &ConfigError::UrlError(ref err) => {
+                f.write_fmt(format_args!("Invalid api_url in configuration: {}", err))
+            },
+            &ConfigError::JwksUrlError(ref err) => {
+                f.write_fmt(format_args!("Invalid jwks_url in configuration: {}", err))
+            },
+            &ConfigError::AuditLogError(ref err) => {
+                f.write_fmt(format_args!("Could not open audit_log: {}", err))
+            },
+            &ConfigError::NoUserMapping => {
+                f.write_str("Configuration must set either users or username_field.")
+            },
+            &ConfigError::NoVerifier => {
+                f.write_str("Configuration must set either realm_secret or jwks_url.")
+            },
+            &ConfigError::SecretRequired => {
+                f.write_str("username_field requires realm_secret; use users instead.")
+            },
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Abstraction over the PAM conversation function, so that the
+//! authentication logic can be driven by a stub in tests.
+
+use libc::{c_char, c_int, c_void, free};
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+pub const PAM_PROMPT_ECHO_OFF: c_int = 1;
+pub const PAM_PROMPT_ECHO_ON:  c_int = 2;
+pub const PAM_ERROR_MSG:       c_int = 3;
+pub const PAM_TEXT_INFO:       c_int = 4;
+
+pub const PAM_SUCCESS: c_int = 0;
+
+/// Ways in which the module talks to the user.  Errors carry no detail: PAM
+/// reports a failed conversation as `PAM_CONV_ERR` either way.
+#[allow(clippy::result_unit_err)]
+pub trait Conversation {
+    /// Displays a message.
+    fn info(&mut self, msg: &str) -> Result<(), ()>;
+
+    /// Displays an error message.
+    fn error(&mut self, msg: &str) -> Result<(), ()>;
+
+    /// Asks the user for input, which is echoed back.
+    fn prompt(&mut self, msg: &str) -> Result<String, ()>;
+}
+
+#[repr(C)]
+pub struct PamMessage {
+    pub msg_style: c_int,
+    pub msg:       *const c_char,
+}
+
+#[repr(C)]
+pub struct PamResponse {
+    pub resp:         *mut c_char,
+    pub resp_retcode: c_int,
+}
+
+#[repr(C)]
+pub struct PamConv {
+    pub conv: extern "C" fn(num_msg:     c_int,
+                            msg:         *mut *const PamMessage,
+                            resp:        *mut *mut PamResponse,
+                            appdata_ptr: *mut c_void) -> c_int,
+    pub appdata_ptr: *mut c_void,
+}
+
+/// `Conversation` that calls the conversation function supplied by the PAM
+/// application.
+pub struct PamConversation<'a> {
+    conv: &'a PamConv,
+}
+
+impl<'a> PamConversation<'a> {
+    pub fn new(conv: &'a PamConv) -> PamConversation<'a> {
+        PamConversation { conv: conv }
+    }
+
+    fn send(&mut self, style: c_int, msg: &str) -> Result<Option<String>, ()> {
+        let text = CString::new(msg).map_err(|_| ())?;
+        let message = PamMessage { msg_style: style, msg: text.as_ptr() };
+        let mut message_ptr = &message as *const PamMessage;
+        let mut resp: *mut PamResponse = ptr::null_mut();
+        let ret = (self.conv.conv)(1, &mut message_ptr, &mut resp, self.conv.appdata_ptr);
+        if ret != PAM_SUCCESS {
+            return Err(())
+        }
+        if resp.is_null() {
+            return Ok(None)
+        }
+        unsafe {
+            let answer = if (*resp).resp.is_null() {
+                None
+            }
+            else {
+                let s = String::from_utf8_lossy(CStr::from_ptr((*resp).resp).to_bytes())
+                    .into_owned();
+                free((*resp).resp as *mut c_void);
+                Some(s)
+            };
+            free(resp as *mut c_void);
+            Ok(answer)
+        }
+    }
+}
+
+impl<'a> Conversation for PamConversation<'a> {
+    fn info(&mut self, msg: &str) -> Result<(), ()> {
+        self.send(PAM_TEXT_INFO, msg).map(|_| ())
+    }
+
+    fn error(&mut self, msg: &str) -> Result<(), ()> {
+        self.send(PAM_ERROR_MSG, msg).map(|_| ())
+    }
+
+    fn prompt(&mut self, msg: &str) -> Result<String, ()> {
+        self.send(PAM_PROMPT_ECHO_ON, msg).map(|r| r.unwrap_or(String::new()))
+    }
+}
@@ -0,0 +1,133 @@
+//! PAM module that authenticates users with Tozny.
+//!
+//! Build this crate to produce `pam_tozny.so`, and reference it from a PAM
+//! service configuration:
+//!
+//! ```text
+//! auth required pam_tozny.so config=/etc/tozny/pam.json
+//! ```
+//!
+//! See the `config` module for the format of the configuration file.  The
+//! file holds the realm secret, so it should be readable only by root.
+//!
+//! # SSH
+//!
+//! To require a Tozny login for SSH, add the line above to
+//! `/etc/pam.d/sshd` and let `sshd` use PAM for keyboard-interactive
+//! authentication:
+//!
+//! ```text
+//! UsePAM yes
+//! KbdInteractiveAuthentication yes
+//! AuthenticationMethods publickey,keyboard-interactive
+//! ```
+//!
+//! With `AuthenticationMethods` as shown, users need both their SSH key and a
+//! Tozny login.  The module runs inside `sshd` before a session is opened, so
+//! every channel - including port forwarding with `ssh -N` - is covered.
+
+extern crate libc;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate tozny_auth;
+extern crate url;
+
+use libc::{c_char, c_int, c_void};
+use std::ffi::{CStr};
+use std::ptr;
+
+use config::Config;
+use conv::{Conversation, PamConv, PamConversation};
+
+pub mod auth;
+pub mod config;
+pub mod conv;
+
+pub const PAM_SUCCESS:          c_int = 0;
+pub const PAM_SERVICE_ERR:      c_int = 3;
+pub const PAM_AUTH_ERR:         c_int = 7;
+pub const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+pub const PAM_USER_UNKNOWN:     c_int = 10;
+pub const PAM_CONV_ERR:         c_int = 19;
+
+const PAM_CONV: c_int = 5;
+
+/// Opaque handle passed in by libpam.
+#[allow(missing_copy_implementations)]
+pub enum PamHandle {}
+
+// Resolved against the libpam that loads the module, so the module is not
+// linked with `-lpam`.
+extern "C" {
+    fn pam_get_item(pamh: *const PamHandle, item_type: c_int,
+                    item: *mut *const c_void) -> c_int;
+    fn pam_get_user(pamh: *const PamHandle, user: *mut *const c_char,
+                    prompt: *const c_char) -> c_int;
+}
+
+/// Finds the `config=` option among the module arguments.
+fn config_path(argc: c_int, argv: *const *const c_char) -> String {
+    for i in 0..argc as isize {
+        let arg = unsafe { CStr::from_ptr(*argv.offset(i)) };
+        let arg = String::from_utf8_lossy(arg.to_bytes());
+        if let Some(path) = arg.strip_prefix("config=") {
+            return path.to_string()
+        }
+    }
+    config::DEFAULT_PATH.to_string()
+}
+
+fn run<C: Conversation>(conv: &mut C, config: &Config, username: &str) -> c_int {
+    match auth::authenticate_with_config(config, conv, username) {
+        Ok(_) => PAM_SUCCESS,
+        Err(auth::AuthError::ConfigError(_)) => PAM_SERVICE_ERR,
+        Err(auth::AuthError::ConversationError) => PAM_CONV_ERR,
+        Err(auth::AuthError::QuestionError(_))  => {
+            let _ = conv.error("Could not reach the Tozny service.");
+            PAM_AUTHINFO_UNAVAIL
+        },
+        Err(auth::AuthError::UnknownUser) => PAM_USER_UNKNOWN,
+        Err(err) => {
+            let _ = conv.error(&format!("{}", err));
+            PAM_AUTH_ERR
+        },
+    }
+}
+
+/// Entry point called by libpam.
+///
+/// # Safety
+///
+/// `pamh` must be the handle that libpam passes to the module, and `argv`
+/// must point to `argc` NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pam_sm_authenticate(pamh:   *const PamHandle,
+                                             _flags: c_int,
+                                             argc:   c_int,
+                                             argv:   *const *const c_char) -> c_int {
+    let config = match Config::load(&config_path(argc, argv)) {
+        Ok(c)  => c,
+        Err(_) => return PAM_SERVICE_ERR,
+    };
+
+    let mut user: *const c_char = ptr::null();
+    if unsafe { pam_get_user(pamh, &mut user, ptr::null()) } != PAM_SUCCESS || user.is_null() {
+        return PAM_USER_UNKNOWN
+    }
+    let username = unsafe { String::from_utf8_lossy(CStr::from_ptr(user).to_bytes()).into_owned() };
+
+    let mut item: *const c_void = ptr::null();
+    if unsafe { pam_get_item(pamh, PAM_CONV, &mut item) } != PAM_SUCCESS || item.is_null() {
+        return PAM_CONV_ERR
+    }
+    let pam_conv = unsafe { &*(item as *const PamConv) };
+    run(&mut PamConversation::new(pam_conv), &config, &username)
+}
+
+#[no_mangle]
+pub extern "C" fn pam_sm_setcred(_pamh:  *const PamHandle,
+                                 _flags: c_int,
+                                 _argc:  c_int,
+                                 _argv:  *const *const c_char) -> c_int {
+    PAM_SUCCESS
+}
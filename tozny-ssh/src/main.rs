@@ -0,0 +1,127 @@
+//! `tozny-ssh-auth`: requires a Tozny login before an SSH session proceeds.
+//!
+//! The recommended way to require Tozny logins for SSH is the PAM module,
+//! through keyboard-interactive authentication; see the `pam_tozny` crate
+//! documentation.  That runs inside `sshd` before any session is opened, so
+//! it covers port forwarding as well as shells and commands.  Use this
+//! program only where the PAM configuration cannot be changed.
+//!
+//! The program talks to the user on the terminal: it sends a push
+//! notification to the device remembered for the account (if any), renders
+//! the `mobile_url` QR code as text, and waits for the login to be approved.
+//! It exits with status 0 only if the signed login verifies and belongs to
+//! a Tozny user that is mapped to the target account.
+//!
+//! It is run through `ForceCommand`, as the user who is logging in:
+//!
+//! ```text
+//! ForceCommand /usr/bin/tozny-ssh-auth && exec ${SSH_ORIGINAL_COMMAND:-$SHELL -l}
+//! DisableForwarding yes
+//! ```
+//!
+//! Because the user can read anything the program reads, its configuration
+//! file (see `pam_tozny::config`) must not contain `realm_secret`; the program
+//! refuses to run if it does.  Logins are verified with the public keys at
+//! `jwks_url` instead, and accounts are mapped with `users`.  Leave
+//! `presence_store` and `audit_log` unset, or point them at files that only
+//! the user's own logins are written to: the user can change both.
+//!
+//! `ForceCommand` only applies to commands.  A client that asks for no
+//! command (`ssh -N`) still gets any port forwarding it requested with `-L`,
+//! `-R` or `-D` without logging in with Tozny, which is why the example turns
+//! forwarding off.
+//!
+//! Usage: `tozny-ssh-auth [--config <path>] [--user <name>]`.  The target
+//! account defaults to `$USER`.
+
+extern crate pam_tozny;
+
+use std::io::{BufRead, Write};
+use std::{env, io, process};
+
+use pam_tozny::auth;
+use pam_tozny::config::{self, Config};
+use pam_tozny::conv::Conversation;
+
+const EXIT_DENIED: i32 = 1;
+const EXIT_USAGE:  i32 = 2;
+
+/// `Conversation` over the controlling terminal.  Messages go to stderr so
+/// that they are not mixed into the output of a forced command.
+struct TerminalConversation;
+
+impl Conversation for TerminalConversation {
+    fn info(&mut self, msg: &str) -> Result<(), ()> {
+        writeln!(io::stderr(), "{}", msg).map_err(|_| ())
+    }
+
+    fn error(&mut self, msg: &str) -> Result<(), ()> {
+        writeln!(io::stderr(), "tozny-ssh-auth: {}", msg).map_err(|_| ())
+    }
+
+    fn prompt(&mut self, msg: &str) -> Result<String, ()> {
+        write!(io::stderr(), "{} ", msg).map_err(|_| ())?;
+        let mut line = String::new();
+        let stdin = io::stdin();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => Err(()),
+            Ok(_)          => Ok(line.trim_end().to_string()),
+        }
+    }
+}
+
+struct Options {
+    config: String,
+    user:   Option<String>,
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut opts = Options { config: config::DEFAULT_PATH.to_string(), user: None };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--config" => opts.config = args.next().ok_or("--config requires a value")?,
+            "--user"   => opts.user = Some(args.next().ok_or("--user requires a value")?),
+            _          => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+    Ok(opts)
+}
+
+fn run() -> Result<(), i32> {
+    let mut conv = TerminalConversation;
+    let opts = parse_options(env::args().skip(1).collect()).map_err(|msg| {
+        let _ = conv.error(&msg);
+        EXIT_USAGE
+    })?;
+    let username = match opts.user.or(env::var("USER").ok()) {
+        Some(u) => u,
+        None    => {
+            let _ = conv.error("Could not determine the target account; use --user.");
+            return Err(EXIT_USAGE)
+        },
+    };
+    let config = Config::load(&opts.config).map_err(|err| {
+        let _ = conv.error(&format!("{}", err));
+        EXIT_USAGE
+    })?;
+    if config.realm_secret.is_some() {
+        let _ = conv.error("The configuration must not contain realm_secret; set jwks_url.");
+        return Err(EXIT_USAGE)
+    }
+    auth::authenticate_with_config(&config, &mut conv, &username)
+        .map(|_| ())
+        .map_err(|err| {
+            let _ = conv.error(&format!("{}", err));
+            match err {
+                auth::AuthError::ConfigError(_) => EXIT_USAGE,
+                _                               => EXIT_DENIED,
+            }
+        })
+}
+
+fn main() {
+    if let Err(code) = run() {
+        process::exit(code);
+    }
+}